@@ -1,123 +1,131 @@
 // 声明这个模块需要使用外部 crates
-use futures::try_join;
-use serde::{Deserialize, Serialize};
+use crate::config::GameTarget;
+use crate::session::TunnelSession;
 use std::error::Error;
-use std::net::SocketAddr;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_kcp::{KcpConfig, KcpStream}; // 移除 UdpSocket，tokio-kcp 会处理
+use tunnel::minecraft::{self, Handshake, LEGACY_PING, MAX_HANDSHAKE_PACKET_LEN, NEXT_STATE_LOGIN};
+use tunnel::transport;
 
-// JWT声明结构体
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String, // 主题 (通常是用户ID)
-    exp: usize,  // 过期时间 (Unix时间戳)
-                 // 可以根据需要添加更多字段
-}
+// 等待游戏发送握手包的最长时间
+const GAME_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
 // 异步函数：处理单个本地连接和代理服务器连接之间的数据转发
-async fn handle_client_connection(mut local_stream: TcpStream, server_addr: SocketAddr) {
-    // 使用 tokio-kcp 连接到反向代理服务器
-    // tokio-kcp 的 connect 会自动处理 UDP socket
-    let mut server_stream = match KcpStream::connect(&KcpConfig::default(), server_addr).await {
-        Ok(stream) => stream,
+async fn handle_client_connection(
+    mut local_stream: TcpStream,
+    session: Arc<TunnelSession>,
+    game_target: Option<Arc<GameTarget>>,
+) {
+    // 先读取游戏的握手包: 需要改写其中的目标地址, 出错时也要靠它判断能否向玩家显示原因
+    let mut handshake = match read_game_handshake(&mut local_stream).await {
+        Ok(handshake) => handshake,
         Err(e) => {
-            eprintln!(
-                "Failed to connect to proxy server {} via KCP: {}",
-                server_addr, e
-            );
-            // 关闭本地连接
+            eprintln!("Client: Could not read the game handshake: {}", e);
             let _ = local_stream.shutdown().await;
             return;
         }
     };
-    println!("Client: Connected to proxy server {} via KCP", server_addr);
-
-    // 生成JWT令牌 (在实际应用中，这个用户ID应该从配置或认证系统获取)
-    let token = "your_jwt_token_here";
 
-    // 发送JWT令牌
-    if let Err(e) = send_auth_token(&mut server_stream, &token).await {
-        eprintln!("Failed to send authentication token: {}", e);
-        let _ = local_stream.shutdown().await;
-        return;
-    }
-
-    // 等待认证响应
-    let mut auth_response = [0u8; 1];
-    if let Err(e) = server_stream.read_exact(&mut auth_response).await {
-        eprintln!("Failed to receive authentication response: {}", e);
-        let _ = local_stream.shutdown().await;
-        return;
+    // 在会话上打开一个流 (会话不可用时先建立隧道并认证)
+    let mut server_stream = match session.open_stream().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Client: Cannot open tunnel stream: {}", e);
+            // 告诉玩家原因并关闭本地连接
+            disconnect_game(&mut local_stream, handshake.as_ref(), &e.player_reason()).await;
+            return;
+        }
+    };
+    if let Some(rtt) = session.rtt() {
+        println!("Client: Tunnel round-trip time {} ms", rtt.as_millis());
     }
 
-    // 检查认证是否成功
-    if auth_response[0] != 1 {
-        eprintln!("Authentication failed");
-        let _ = local_stream.shutdown().await;
-        return;
+    // 把握手包中的地址改写为真实目标后转发, 后续数据原样转发
+    if let Some(handshake) = handshake.as_mut() {
+        if let Some(target) = &game_target {
+            handshake.rewrite_target(&target.host, target.port);
+        }
+        if let Err(e) = server_stream.write_all(&handshake.encode()).await {
+            eprintln!("Client: Failed to forward the game handshake: {}", e);
+            let _ = local_stream.shutdown().await;
+            return;
+        }
     }
 
-    println!("Client: Authentication successful");
-
-    // 分割本地 TCP 连接和 KCP 服务器连接流为读写半部分
-    // KcpStream 可能不直接支持 split()，需要确认 kcp crate 的 API
-    // 假设 KcpStream 实现了 AsyncRead 和 AsyncWrite
-    let (mut local_read, mut local_write) = local_stream.split();
-    let (mut server_read, mut server_write) = io::split(server_stream); // 使用 tokio::io::split 来分割 KcpStream
-
-    // 创建两个 futures：一个从本地连接读并写到服务器，另一个从服务器读并写到本地连接
-    let local_to_server = io::copy(&mut local_read, &mut server_write);
-    let server_to_local = io::copy(&mut server_read, &mut local_write);
-
-    // 并发地运行这两个 futures
-    match try_join!(local_to_server, server_to_local) {
-        Ok((local_bytes, server_bytes)) => {
+    // 双向转发, 直到两个方向都结束
+    match transport::relay(&mut server_stream, &mut local_stream).await {
+        Ok((server_bytes, local_bytes)) => {
             println!(
-                "Client: KCP Connection closed. {} bytes local->server, {} bytes server->local",
+                "Client: Tunnel connection closed. {} bytes local->server, {} bytes server->local",
                 local_bytes, server_bytes
             );
         }
-        Err(e) => {
-            eprintln!("Client: KCP Connection closed with error: {}", e);
+        Err(reason) => {
+            eprintln!("Client: Tunnel connection closed with error: {}", reason);
         }
     }
 }
 
-// 发送认证令牌
-async fn send_auth_token(stream: &mut KcpStream, token: &str) -> io::Result<()> {
-    // 发送令牌长度 (4字节)
-    let token_len = token.len() as u32;
-    stream.write_all(&token_len.to_be_bytes()).await?;
-
-    // 发送令牌内容
-    stream.write_all(token.as_bytes()).await?;
+// 读取游戏连接的第一个数据包 (握手包)
+// 旧版本的服务器列表查询不是普通的数据包, 返回 None 并原样转发
+async fn read_game_handshake(local_stream: &mut TcpStream) -> io::Result<Option<Handshake>> {
+    tokio::time::timeout(GAME_HANDSHAKE_TIMEOUT, async {
+        let mut first = [0u8; 1];
+        if local_stream.peek(&mut first).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the handshake",
+            ));
+        }
+        if first[0] == LEGACY_PING {
+            return Ok(None);
+        }
+        let packet = minecraft::read_packet(local_stream, MAX_HANDSHAKE_PACKET_LEN).await?;
+        Handshake::parse(&packet).map(Some)
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "game did not send a handshake in time"))?
+}
 
-    Ok(())
+// 向游戏显示断开原因: 如果玩家正在登录就回复断开数据包, 然后关闭本地连接
+// 服务器列表的状态查询 (next state = 1) 没有可以显示原因的位置, 直接关闭
+async fn disconnect_game(local_stream: &mut TcpStream, handshake: Option<&Handshake>, reason: &str) {
+    if handshake.is_some_and(|handshake| handshake.next_state == NEXT_STATE_LOGIN)
+        && let Err(e) = local_stream
+            .write_all(&minecraft::login_disconnect_packet(reason))
+            .await
+    {
+        eprintln!("Client: Could not deliver disconnect reason to game: {}", e);
+    }
+    let _ = local_stream.shutdown().await;
 }
 
 // 公共异步函数：运行客户端的主要监听循环 (监听本地 TCP)
 // 这个函数将在 client.rs 中由运行时调用
 pub async fn run_client(
-    listener: &TcpListener,  // 仍然监听本地 TCP
-    server_addr: SocketAddr, // 代理服务器地址 (用于 KCP 连接)
+    listener: &TcpListener,              // 仍然监听本地 TCP
+    session: Arc<TunnelSession>,         // 到代理服务器的会话
+    game_target: Option<Arc<GameTarget>>, // 改写握手包使用的目标
 ) -> Result<(), Box<dyn Error>> {
     println!(
         "Client listening on {}:{} (TCP)",
         listener.local_addr()?.ip(),
         listener.local_addr()?.port()
     );
-    println!(
-        "Forwarding connections to proxy server {} via KCP",
-        server_addr
-    );
+    println!("Forwarding connections to relay servers {}", session.relays());
 
     // 循环接受新的本地 TCP 连接
     loop {
         let (local_stream, local_addr) = listener.accept().await?;
         println!("Client: Accepted local TCP connection from {}", local_addr);
 
-        // 为每个新的本地连接 spawn 一个异步任务，使用 KCP 连接到服务器
-        tokio::spawn(handle_client_connection(local_stream, server_addr));
+        // 为每个新的本地连接 spawn 一个异步任务，通过隧道连接到服务器
+        tokio::spawn(handle_client_connection(
+            local_stream,
+            session.clone(),
+            game_target.clone(),
+        ));
     }
 }
@@ -0,0 +1,246 @@
+// 客户端配置: 目前全部从环境变量读取, 由启动 agent.exe 的一方注入
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_kcp::{KcpConfig, KcpNoDelayConfig};
+use tunnel::transport::quic::QuicOptions;
+
+const DEFAULT_TICKET_PATH: &str = "auth/ticket";
+const DEFAULT_GAME_PORT: u16 = 25565;
+const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 5_000;
+const DEFAULT_HEARTBEAT_TIMEOUT_MS: u64 = 20_000;
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub api_base: String,        // 后端 API 根地址, 例如 https://api.example.com/
+    pub ticket_path: String,     // 签发 JWT 的接口路径 (相对 api_base)
+    pub relays: Vec<RelayEndpoint>,  // 中继服务器列表, 延迟相同时按这里的顺序选择
+    pub relays_path: Option<String>, // 从后端 API 获取中继列表的路径, 获取成功时替换 relays
+    pub transport: TransportConfig,
+    pub player_name: String,             // 当前玩家名, 用于申请令牌
+    pub game_target: Option<GameTarget>, // 改写握手包使用的目标, 为空时原样转发
+    pub heartbeat: HeartbeatConfig,
+}
+
+// 隧道心跳
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration, // 发送 PING 的间隔
+    pub timeout: Duration,  // 超过这个时间没有收到服务器的数据时认为隧道已经断开
+}
+
+// 一个中继服务器
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RelayEndpoint {
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub server_name: Option<String>, // 校验证书使用的域名, 覆盖 AGENT_TLS_SERVER_NAME
+}
+
+// 游戏本来要连接的服务器地址: 游戏实际连接的是 127.0.0.1:<随机端口>,
+// agent 把握手包中的地址改写成这里的值, 后端看到的就是玩家预期的主机名
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameTarget {
+    pub host: String,
+    pub port: u16,
+}
+
+impl GameTarget {
+    // 支持 host、host:port 和 [ipv6]:port, 未指定端口时使用 25565
+    fn parse(value: &str) -> Result<Self> {
+        let (host, port) = match value.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') || host.starts_with('[') => {
+                let port = port
+                    .parse()
+                    .with_context(|| format!("'{}' is not a valid port", port))?;
+                (host, port)
+            }
+            _ => (value, DEFAULT_GAME_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.len() > 255 || host.contains('\0') {
+            return Err(anyhow!("'{}' is not a valid host name", host));
+        }
+        Ok(GameTarget {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+// 隧道传输协议, 需要与中继服务器一致
+#[derive(Debug, Clone)]
+pub enum TransportConfig {
+    Kcp(KcpConfig),
+    Quic(TlsSettings, QuicOptions),
+    Tcp,
+    Tls(TlsSettings),
+}
+
+// QUIC 和 TLS 共用的证书校验设置
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub server_name: Option<String>, // 校验服务器证书使用的域名, 不设置时使用中继的 IP
+    pub ca_file: Option<PathBuf>, // 自签证书的 CA, 不设置时使用内置根证书
+}
+
+impl ClientConfig {
+    pub fn from_env() -> Result<Self> {
+        // AGENT_SERVER_ADDR 可以是逗号分隔的多个 ip:port; 配置了 AGENT_RELAYS_PATH 时可以省略
+        let relays_path = env::var("AGENT_RELAYS_PATH").ok();
+        let relays = match env::var("AGENT_SERVER_ADDR") {
+            Ok(value) => parse_relays(&value)?,
+            Err(_) if relays_path.is_some() => Vec::new(),
+            Err(_) => return Err(anyhow!("environment variable AGENT_SERVER_ADDR is not set")),
+        };
+        let tls = || TlsSettings {
+            server_name: env::var("AGENT_TLS_SERVER_NAME").ok(),
+            ca_file: env::var_os("AGENT_TLS_CA_FILE").map(PathBuf::from),
+        };
+        let transport = match env::var("AGENT_TRANSPORT").as_deref() {
+            Err(_) | Ok("kcp") => TransportConfig::Kcp(kcp_config()?),
+            Ok("quic") => TransportConfig::Quic(tls(), quic_options()?),
+            Ok("tcp") => TransportConfig::Tcp,
+            Ok("tls") => TransportConfig::Tls(tls()),
+            Ok(other) => {
+                return Err(anyhow!(
+                    "AGENT_TRANSPORT '{}' is not one of kcp, quic, tcp, tls",
+                    other
+                ));
+            }
+        };
+        let heartbeat = HeartbeatConfig {
+            interval: millis("AGENT_HEARTBEAT_INTERVAL_MS", DEFAULT_HEARTBEAT_INTERVAL_MS)?,
+            timeout: millis("AGENT_HEARTBEAT_TIMEOUT_MS", DEFAULT_HEARTBEAT_TIMEOUT_MS)?,
+        };
+        if heartbeat.interval.is_zero() || heartbeat.timeout <= heartbeat.interval {
+            return Err(anyhow!(
+                "AGENT_HEARTBEAT_TIMEOUT_MS must be greater than AGENT_HEARTBEAT_INTERVAL_MS, which must be greater than 0"
+            ));
+        }
+        Ok(ClientConfig {
+            api_base: required("AGENT_API_BASE")?,
+            ticket_path: env::var("AGENT_TICKET_PATH")
+                .unwrap_or_else(|_| DEFAULT_TICKET_PATH.to_string()),
+            relays,
+            relays_path,
+            transport,
+            player_name: required("AGENT_PLAYER_NAME")?,
+            game_target: env::var("AGENT_GAME_TARGET")
+                .ok()
+                .map(|value| {
+                    GameTarget::parse(&value)
+                        .with_context(|| format!("AGENT_GAME_TARGET '{}' is invalid", value))
+                })
+                .transpose()?,
+            heartbeat,
+        })
+    }
+}
+
+fn parse_relays(value: &str) -> Result<Vec<RelayEndpoint>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| {
+            Ok(RelayEndpoint {
+                addr: addr
+                    .parse()
+                    .with_context(|| format!("AGENT_SERVER_ADDR '{}' is not ip:port", addr))?,
+                server_name: None,
+            })
+        })
+        .collect()
+}
+
+// KCP 参数, 未设置的沿用 tokio_kcp 的默认值; 需要与服务器配置的 [kcp] 一致,
+// 尤其是 AGENT_KCP_STREAM 和 AGENT_KCP_MTU, 否则双方无法解析对方的数据
+fn kcp_config() -> Result<KcpConfig> {
+    let mut config = KcpConfig::default();
+
+    if let Some(mtu) = parsed::<usize>("AGENT_KCP_MTU")? {
+        if !(64..=1500).contains(&mtu) {
+            return Err(anyhow!("AGENT_KCP_MTU {} is out of range 64..=1500", mtu));
+        }
+        config.mtu = mtu;
+    }
+
+    let interval = parsed("AGENT_KCP_INTERVAL")?.unwrap_or(config.nodelay.interval);
+    if !(10..=5000).contains(&interval) {
+        return Err(anyhow!("AGENT_KCP_INTERVAL {} ms is out of range 10..=5000", interval));
+    }
+    let resend = parsed("AGENT_KCP_RESEND")?.unwrap_or(config.nodelay.resend);
+    if resend < 0 {
+        return Err(anyhow!("AGENT_KCP_RESEND must not be negative"));
+    }
+    config.nodelay = KcpNoDelayConfig {
+        nodelay: parsed("AGENT_KCP_NODELAY")?.unwrap_or(config.nodelay.nodelay),
+        interval,
+        resend,
+        nc: parsed("AGENT_KCP_NO_CONGESTION_CONTROL")?.unwrap_or(config.nodelay.nc),
+    };
+
+    let send_window = parsed("AGENT_KCP_SEND_WINDOW")?.unwrap_or(config.wnd_size.0);
+    let recv_window = parsed("AGENT_KCP_RECV_WINDOW")?.unwrap_or(config.wnd_size.1);
+    if send_window == 0 || recv_window == 0 {
+        return Err(anyhow!("AGENT_KCP_SEND_WINDOW and AGENT_KCP_RECV_WINDOW must be greater than 0"));
+    }
+    config.wnd_size = (send_window, recv_window);
+
+    if let Some(stream) = parsed("AGENT_KCP_STREAM")? {
+        config.stream = stream;
+    }
+    Ok(config)
+}
+
+// QUIC 连接参数, 未设置的使用与服务器 [quic] 相同的默认值
+fn quic_options() -> Result<QuicOptions> {
+    let defaults = QuicOptions::default();
+    let options = QuicOptions {
+        idle_timeout: parsed("AGENT_QUIC_IDLE_TIMEOUT_SECS")?
+            .map(Duration::from_secs)
+            .unwrap_or(defaults.idle_timeout),
+        keep_alive: parsed("AGENT_QUIC_KEEP_ALIVE_SECS")?
+            .map(Duration::from_secs)
+            .unwrap_or(defaults.keep_alive),
+    };
+    // 保活间隔必须小于空闲超时, 否则空闲连接会在保活包发出前被关闭
+    if options.keep_alive.is_zero() || options.keep_alive >= options.idle_timeout {
+        return Err(anyhow!(
+            "AGENT_QUIC_KEEP_ALIVE_SECS must be greater than 0 and less than AGENT_QUIC_IDLE_TIMEOUT_SECS ({})",
+            options.idle_timeout.as_secs()
+        ));
+    }
+    Ok(options)
+}
+
+// 未设置时为空, 设置了但无法解析时报错
+fn parsed<T: FromStr>(name: &str) -> Result<Option<T>> {
+    match env::var(name) {
+        Ok(value) => match value.trim().parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(anyhow!("{} '{}' is invalid", name, value)),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+// 以毫秒为单位的时长, 未设置时使用默认值
+fn millis(name: &str, default: u64) -> Result<Duration> {
+    let value = match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("{} '{}' is not a number of milliseconds", name, value))?,
+        Err(_) => default,
+    };
+    Ok(Duration::from_millis(value))
+}
+
+fn required(name: &str) -> Result<String> {
+    env::var(name).map_err(|_| anyhow!("environment variable {} is not set", name))
+}
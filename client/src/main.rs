@@ -1,87 +1,151 @@
 mod client_core;
 mod api;
+mod config;
+mod relay;
+mod session;
+mod token;
+mod transport;
 
 use std::error::Error;
-use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
-use tokio::net::windows::named_pipe::{ServerOptions};
+use std::sync::Arc;
+use api::api::Api;
+use config::ClientConfig;
+use ipc::endpoint::{self, Credentials};
+use ipc::{Message, PortAssignment};
+use relay::RelaySet;
+use session::TunnelSession;
+use token::{TicketRequest, TokenProvider};
 use tokio::{net::TcpListener, runtime::Runtime};
 
-use windows::{Win32::Foundation::*, Win32::Storage::FileSystem::*, Win32::System::Pipes::*};
-
-#[derive(Serialize, Deserialize, Debug)]
-struct NetworkInfo {
-    continent: String,
-    country: String,
-    owner: String,
-    isp: String,
-    prov: String,
-    city: String,
-    district: String,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct Point {
-    code: String,
-    data: NetworkInfo,
-}
-
 fn main() -> Result<(), Box<dyn Error>> {
+    let config = ClientConfig::from_env()?;
     let rt = Runtime::new()?;
     let listener2 = rt.block_on(async { TcpListener::bind("127.0.0.1:0").await })?;
     let listener = rt.block_on(async { TcpListener::bind("127.0.0.1:0").await })?;
-    rt.block_on(async {
+
+    // 由游戏启动时使用游戏传入的端点, 单独运行时每次启动生成新的端点, 需要把它们传给游戏
+    let credentials = match Credentials::from_env() {
+        Some(credentials) => credentials,
+        None => {
+            let credentials = Credentials::generate()?;
+            println!(
+                "Client: Start the game with {}={} {}={}",
+                endpoint::ENDPOINT_ENV,
+                credentials.name,
+                endpoint::NONCE_ENV,
+                credentials.nonce
+            );
+            credentials
+        }
+    };
+    let ipc = rt.block_on(async {
         new_pipes_server(
+            &credentials,
             listener2.local_addr().unwrap().port(),
             listener.local_addr().unwrap().port(),
         )
         .await
     });
+    // 没有管道就无法告诉游戏端口, 也无法通知游戏退出原因
+    let ipc = match ipc {
+        Ok(ipc) => ipc,
+        Err(e) => {
+            eprintln!("Client: Failed to set up the pipe to the game: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // 握手完成后开始转发游戏连接, 退出前告诉游戏原因, 由游戏提示玩家
+    let result = run_agent(&rt, &config, &listener, &ipc);
+    let farewell = match &result {
+        Ok(()) => Message::Shutdown {
+            reason: "agent exited".to_string(),
+        },
+        Err(e) => Message::Error {
+            message: e.to_string(),
+        },
+    };
+    if let Err(e) = rt.block_on(ipc.send(&farewell)) {
+        eprintln!("Client: Failed to notify the game over the pipe: {}", e);
+    }
+    result?;
     println!("Client: Exiting synchronous main function.");
 
     Ok(())
 }
 
-const PIPE_NAME: &str = r"\\.\pipe\novoline893";
+fn run_agent(
+    rt: &Runtime,
+    config: &ClientConfig,
+    listener: &TcpListener,
+    game: &ipc::Sender,
+) -> Result<(), Box<dyn Error>> {
+    // 令牌在后台保持刷新
+    let api = Api::new(&config.api_base)?;
 
-async fn new_pipes_server(network_port: u16, forward_port: u16) {
-    let server = ServerOptions::new()
-        .first_pipe_instance(true)
-        .create(PIPE_NAME)
-        .unwrap();
-    
-    let server_task = tokio::spawn(async move {
-        server.connect().await.unwrap();
-        println!("Pipe client connected.");
-        let mut client = server;
-        let message = format!("{},{}", network_port, forward_port);
-        client.write_all(message.as_bytes()).await.unwrap();
-        println!("Sent ports to pipe client: {}", message);
-        
-        let mut buffer = [0u8; 512];
-        loop {
-            match client.try_read(&mut buffer) {
-                Ok(0) => {
-                    println!("Pipe client disconnected.");
-                    break;
-                }
-                Ok(n) => {
-                    let received_message = String::from_utf8_lossy(&buffer[..n]);
-                    println!("Received from pipe client: {}", received_message);
-                    if received_message == "goodjobguy" {
-                        println!("magic received. Closing pipe connection.");
-                        client.flush().await.unwrap();
-                        client.shutdown().await.unwrap();
-                        break;
-                    }
-                }
-                Err(_) => {}
+    // 后端下发的中继列表优先, 获取失败时使用配置中的列表
+    let mut relays = config.relays.clone();
+    if let Some(path) = &config.relays_path {
+        match rt.block_on(relay::fetch(&api, path)) {
+            Ok(fetched) => relays = fetched,
+            Err(e) if !relays.is_empty() => {
+                eprintln!("Client: Failed to fetch relay list, using configured relays: {}", e)
             }
+            Err(e) => return Err(e.context("cannot fetch relay list").into()),
         }
-    });
-    if let Err(e) = server_task.await {
-        eprintln!("Pipe server task panicked or was cancelled: {}", e);
-    } else {
-        println!("Pipe server task completed successfully.");
     }
+
+    let tokens = Arc::new(TokenProvider::new(
+        api,
+        &config.ticket_path,
+        TicketRequest {
+            player_name: config.player_name.clone(),
+        },
+    ));
+    rt.block_on(async {
+        let transports = relays
+            .iter()
+            .map(|relay| transport::build(relay, &config.transport))
+            .collect::<Result<Vec<_>, _>>()?;
+        tokens.clone().spawn_refresh_task();
+        let relays = Arc::new(RelaySet::new(transports));
+        relays.probe_all().await;
+        relays.clone().spawn_probe_task();
+        let session = Arc::new(TunnelSession::new(relays, tokens, config.heartbeat, game.clone()));
+        client_core::run_client(listener, session, config.game_target.clone().map(Arc::new)).await
+    })
+}
+
+// 等待游戏连接管道并发送端口; 之后管道保持打开并双向心跳, 游戏退出或失去响应时 agent 随之退出
+async fn new_pipes_server(
+    credentials: &Credentials,
+    network_port: u16,
+    forward_port: u16,
+) -> std::io::Result<ipc::Sender> {
+    let listener = endpoint::Listener::bind(credentials)?;
+    println!("Waiting for the game on {}", listener.address());
+    let (mut receiver, sender) = listener.accept().await?;
+    println!("Pipe client connected.");
+
+    let ports = PortAssignment {
+        network_port,
+        forward_port,
+    };
+    if let Err(e) = ipc::send_ports(&mut receiver, &sender, ports).await {
+        eprintln!("Client: Game did not acknowledge the ports ({}), exiting", e);
+        std::process::exit(1);
+    }
+    println!("Sent ports to pipe client: {:?}", ports);
+
+    // 游戏不会主动发送其他消息
+    let heartbeat = sender.clone();
+    tokio::spawn(async move {
+        let closed = ipc::heartbeat(receiver, heartbeat, |message| {
+            eprintln!("Client: Ignoring unexpected message from the game: {:?}", message)
+        })
+        .await;
+        eprintln!("Client: Game stopped responding over the pipe ({}), exiting", closed);
+        std::process::exit(0);
+    });
+    Ok(sender)
 }
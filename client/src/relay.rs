@@ -0,0 +1,195 @@
+// 中继服务器列表: 启动时和之后定期测量到每个中继的往返时间,
+// 建立会话时按延迟从低到高依次尝试
+use crate::api::api::Api;
+use crate::config::RelayEndpoint;
+use anyhow::{Result, anyhow};
+use futures::future::join_all;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tunnel::handshake;
+use tunnel::transport::Transport;
+
+// 单次探测 (建立隧道 + 一次往返) 的最长时间
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+// 重新测量的间隔
+const PROBE_INTERVAL: Duration = Duration::from_secs(300);
+
+pub struct Relay {
+    transport: Arc<dyn Transport>,
+    rtt: Mutex<Option<Duration>>, // 最近一次探测或心跳测得的往返时间, 探测失败时为空
+}
+
+impl Relay {
+    pub fn transport(&self) -> &Arc<dyn Transport> {
+        &self.transport
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.rtt.lock().unwrap()
+    }
+
+    // 记录会话心跳测得的往返时间
+    pub fn record_rtt(&self, rtt: Duration) {
+        *self.rtt.lock().unwrap() = Some(rtt);
+    }
+}
+
+pub struct RelaySet {
+    relays: Vec<Arc<Relay>>,
+}
+
+impl RelaySet {
+    pub fn new(transports: Vec<Arc<dyn Transport>>) -> Self {
+        RelaySet {
+            relays: transports
+                .into_iter()
+                .map(|transport| {
+                    Arc::new(Relay {
+                        transport,
+                        rtt: Mutex::new(None),
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    // 按延迟从低到高排列, 没有测量结果的中继排在最后, 延迟相同时保持配置顺序
+    pub fn ranked(&self) -> Vec<Arc<Relay>> {
+        let mut relays = self.relays.clone();
+        relays.sort_by_key(|relay| relay.rtt().unwrap_or(Duration::MAX));
+        relays
+    }
+
+    // 同时探测所有中继
+    pub async fn probe_all(&self) {
+        join_all(self.relays.iter().map(|relay| async move {
+            let result = tokio::time::timeout(PROBE_TIMEOUT, probe(relay.transport().as_ref()))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "probe timed out"))
+                });
+            let rtt = match result {
+                Ok(rtt) => Some(rtt),
+                Err(e) => {
+                    eprintln!(
+                        "Client: Relay {} did not answer the probe: {}",
+                        relay.transport().server_addr(),
+                        e
+                    );
+                    None
+                }
+            };
+            *relay.rtt.lock().unwrap() = rtt;
+        }))
+        .await;
+        println!("Client: Relay latency: {}", self);
+    }
+
+    // 后台任务: 定期重新测量, 新建立的会话会使用当时延迟最低的中继
+    pub fn spawn_probe_task(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(PROBE_INTERVAL).await;
+                self.probe_all().await;
+            }
+        })
+    }
+}
+
+impl fmt::Display for RelaySet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, relay) in self.ranked().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            let transport = relay.transport();
+            match relay.rtt() {
+                Some(rtt) => write!(
+                    f,
+                    "{} via {} ({} ms)",
+                    transport.server_addr(),
+                    transport.protocol(),
+                    rtt.as_millis()
+                )?,
+                None => write!(
+                    f,
+                    "{} via {} (no answer)",
+                    transport.server_addr(),
+                    transport.protocol()
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// 建立一条隧道并测量一次探测帧的往返时间 (不包含建立隧道本身的时间)
+async fn probe(transport: &dyn Transport) -> io::Result<Duration> {
+    let mut tunnel = transport.connect().await?;
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    let started = Instant::now();
+    handshake::probe(&mut tunnel.stream, nonce).await?;
+    Ok(started.elapsed())
+}
+
+// 从后端 API 获取中继列表
+pub async fn fetch(api: &Api, path: &str) -> Result<Vec<RelayEndpoint>> {
+    let response = api.get::<Vec<RelayEndpoint>>(path).await?;
+    if response.code != 0 {
+        return Err(anyhow!(
+            "relay list request rejected (code {}): {}",
+            response.code,
+            response.message.unwrap_or_default()
+        ));
+    }
+    let relays = response.data.unwrap_or_default();
+    if relays.is_empty() {
+        return Err(anyhow!("relay list is empty"));
+    }
+    Ok(relays)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tunnel::handshake::ClientFrame;
+    use tunnel::transport::TunnelListener;
+    use tunnel::transport::memory;
+
+    // 回复探测帧的中继
+    fn answering_relay(port: u16) -> Arc<dyn Transport> {
+        let (transport, mut listener) = memory::pair(SocketAddr::from(([127, 0, 0, 1], port)));
+        tokio::spawn(async move {
+            while let Ok(mut tunnel) = listener.accept().await {
+                match ClientFrame::read_from(&mut tunnel.stream, 1024).await.unwrap() {
+                    ClientFrame::Probe(nonce) => {
+                        handshake::write_probe_reply(&mut tunnel.stream, nonce).await.unwrap()
+                    }
+                    other => panic!("expected a probe, got {:?}", other),
+                }
+            }
+        });
+        Arc::new(transport)
+    }
+
+    #[tokio::test]
+    async fn probes_rank_answering_relays_first() {
+        // 监听器已经关闭, 连接会被拒绝
+        let (closed, _) = memory::pair(SocketAddr::from(([127, 0, 0, 1], 19132)));
+        let relays = RelaySet::new(vec![Arc::new(closed), answering_relay(19133)]);
+        tokio::time::timeout(PROBE_TIMEOUT * 2, relays.probe_all())
+            .await
+            .unwrap();
+
+        let ranked = relays.ranked();
+        assert_eq!(ranked[0].transport().server_addr().port(), 19133);
+        assert!(ranked[0].rtt().is_some());
+        assert_eq!(ranked[1].transport().server_addr().port(), 19132);
+        assert_eq!(ranked[1].rtt(), None);
+    }
+}
@@ -0,0 +1,478 @@
+// 到中继服务器的持久会话: 认证只做一次, 之后每个游戏连接在同一条隧道上打开一个流
+// 服务器支持恢复时, 短暂的网络中断由 resume 模块在新连接上恢复, 游戏连接不受影响;
+// 会话无法恢复时, 下一个游戏连接会重新建立隧道并认证 (优先选择延迟最低的中继)
+// 往返时间、认证失败和服务器推送的消息通过管道转发给游戏, 由 mod 显示
+use crate::config::HeartbeatConfig;
+use crate::relay::{Relay, RelaySet};
+use crate::token::TokenProvider;
+use ipc::Message;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tunnel::auth::AuthResponse;
+use tunnel::handshake::{self, CAP_MUX, CAP_RESUME, Hello};
+use tunnel::mux::{Heartbeat, MuxSession, Role};
+use tunnel::resume::{Connector, ResumableStream};
+use tunnel::transport::{BoxedStream, PeerInfo};
+
+// 在 hello 帧中上报的构建ID, 便于服务器端排查版本问题
+const BUILD_ID: &str = concat!("agent/", env!("CARGO_PKG_VERSION"));
+
+// 获取令牌的最长时间, 令牌在尝试中继之前获取, 所有中继共用
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 连接一个中继并完成认证的最长时间, 超时后尝试下一个中继;
+// KCP 连接到不可达的地址时不会报错, 只能靠超时发现
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 打开流失败的原因, 每一种都对应一条展示给玩家的提示
+#[derive(Debug)]
+pub enum SessionError {
+    Unreachable(io::Error), // 无法连接到中继服务器
+    Token(anyhow::Error),   // 无法获取令牌
+    Handshake(io::Error),   // 握手过程中连接出错
+    Rejected(AuthResponse), // 服务器拒绝了认证
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Unreachable(e) => write!(f, "cannot reach the relay server: {}", e),
+            SessionError::Token(e) => write!(f, "cannot obtain an authentication token: {}", e),
+            SessionError::Handshake(e) => write!(f, "authentication handshake failed: {}", e),
+            SessionError::Rejected(response) => write!(
+                f,
+                "authentication rejected: {} ({})",
+                response.status, response.reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl SessionError {
+    // 显示在游戏断开界面上的原因
+    pub fn player_reason(&self) -> String {
+        match self {
+            SessionError::Unreachable(_) => "Unable to reach the relay server".to_string(),
+            SessionError::Token(_) => "Unable to log in, please restart the game".to_string(),
+            SessionError::Handshake(_) => "Authentication failed, please try again".to_string(),
+            SessionError::Rejected(response) if response.reason.is_empty() => {
+                format!("Authentication failed: {}", response.status)
+            }
+            SessionError::Rejected(response) => response.reason.clone(),
+        }
+    }
+}
+
+pub struct TunnelSession {
+    relays: Arc<RelaySet>,
+    tokens: Arc<TokenProvider>,
+    heartbeat: HeartbeatConfig,
+    game: ipc::Sender, // 到游戏的管道
+    current: Mutex<Option<Arc<MuxSession>>>,
+    dialing: Mutex<()>, // 建立新会话期间持有, 同时到来的游戏连接只会触发一次认证
+}
+
+// 一条完成认证的隧道
+struct Connected {
+    stream: BoxedStream,
+    capabilities: u32, // 协商出的能力
+    peer: PeerInfo,
+    relay: Arc<Relay>,
+}
+
+impl TunnelSession {
+    pub fn new(
+        relays: Arc<RelaySet>,
+        tokens: Arc<TokenProvider>,
+        heartbeat: HeartbeatConfig,
+        game: ipc::Sender,
+    ) -> Self {
+        TunnelSession {
+            relays,
+            tokens,
+            heartbeat,
+            game,
+            current: Mutex::new(None),
+            dialing: Mutex::new(()),
+        }
+    }
+
+    pub fn relays(&self) -> &RelaySet {
+        &self.relays
+    }
+
+    // 当前会话的心跳往返时间; 没有会话或正在建立会话时为空
+    pub fn rtt(&self) -> Option<Duration> {
+        self.current.try_lock().ok()?.as_ref()?.rtt()
+    }
+
+    // 为一个游戏连接打开一个流, 必要时先建立会话
+    pub async fn open_stream(&self) -> Result<BoxedStream, SessionError> {
+        if let Some(stream) = self.open_on_current().await {
+            return Ok(stream);
+        }
+        // 建立会话期间不持有 current, 不影响 rtt() 等对当前会话的访问;
+        // 等待 dialing 的连接在会话建立后直接使用新会话
+        let _dialing = self.dialing.lock().await;
+        if let Some(stream) = self.open_on_current().await {
+            return Ok(stream);
+        }
+
+        let connected = self.connect().await?;
+        // 旧版本服务器不支持复用, 这条隧道只能用于当前连接
+        if connected.capabilities & CAP_MUX == 0 {
+            return Ok(connected.stream);
+        }
+
+        // 可恢复的会话由 resume 模块检测断开, 心跳只用于测量往返时间
+        let heartbeat = Heartbeat {
+            interval: self.heartbeat.interval,
+            timeout: (connected.capabilities & CAP_RESUME == 0).then_some(self.heartbeat.timeout),
+        };
+        let mux = Arc::new(MuxSession::new(connected.stream, Role::Client, heartbeat));
+        let stream = mux.open().await.map_err(SessionError::Handshake)?;
+        println!("Client: Tunnel session established with {}", connected.peer);
+
+        // 心跳测得的往返时间同样用于下次选择中继
+        let mut rtt_updates = mux.rtt_updates();
+        let relay = connected.relay;
+        let game = self.game.clone();
+        tokio::spawn(async move {
+            while rtt_updates.changed().await.is_ok() {
+                if let Some(rtt) = *rtt_updates.borrow_and_update() {
+                    relay.record_rtt(rtt);
+                    notify_game(
+                        &game,
+                        Message::TunnelRtt {
+                            rtt_ms: rtt.as_millis() as u64,
+                        },
+                    );
+                }
+            }
+        });
+        // 会话结束后 next_notice 返回 None, 任务随之结束
+        let notices = mux.clone();
+        let game = self.game.clone();
+        tokio::spawn(async move {
+            while let Some(message) = notices.next_notice().await {
+                println!("Client: Server notice: {}", message);
+                notify_game(&game, Message::ServerNotice { message });
+            }
+        });
+        *self.current.lock().await = Some(mux);
+        Ok(Box::new(stream))
+    }
+
+    // 在当前会话上打开一个流; 没有会话或会话已经结束时为空
+    async fn open_on_current(&self) -> Option<BoxedStream> {
+        let mux = self.current.lock().await.clone()?;
+        match mux.open().await {
+            Ok(stream) => Some(Box::new(stream)),
+            Err(_) => None,
+        }
+    }
+
+    // 按延迟从低到高尝试各个中继, 直到一个中继完成认证; 全部失败时返回最后一个错误
+    async fn connect(&self) -> Result<Connected, SessionError> {
+        // 获取JWT令牌 (缓存中的令牌快过期时会先向后端刷新); 令牌与中继无关, 失败时不再尝试中继
+        let token = tokio::time::timeout(TOKEN_TIMEOUT, self.tokens.token())
+            .await
+            .unwrap_or_else(|_| {
+                Err(anyhow::anyhow!("no response within {} s", TOKEN_TIMEOUT.as_secs()))
+            })
+            .map_err(SessionError::Token)?;
+
+        let mut last_error = None;
+        for relay in self.relays.ranked() {
+            let attempt = tokio::time::timeout(RELAY_TIMEOUT, self.connect_to(&relay, &token))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(SessionError::Unreachable(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no response within {} s", RELAY_TIMEOUT.as_secs()),
+                    )))
+                });
+            match attempt {
+                Ok(connected) => return Ok(connected),
+                Err(e) => {
+                    eprintln!(
+                        "Client: Relay {} failed, trying the next one: {}",
+                        relay.transport().server_addr(),
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            SessionError::Unreachable(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no relay server configured",
+            ))
+        }))
+    }
+
+    // 建立到一个中继的隧道并完成认证
+    async fn connect_to(&self, relay: &Arc<Relay>, token: &str) -> Result<Connected, SessionError> {
+        let transport = relay.transport();
+        let tunnel = transport
+            .connect()
+            .await
+            .map_err(SessionError::Unreachable)?;
+        println!("Client: Connected to proxy server {}", tunnel.peer);
+        let mut stream = tunnel.stream;
+
+        // 发送 hello 帧 (包含JWT令牌) 并等待认证响应
+        let response = handshake::client_handshake(&mut stream, &Hello::new(BUILD_ID, token))
+            .await
+            .map_err(SessionError::Handshake)?;
+        if !response.is_ok() {
+            notify_game(
+                &self.game,
+                Message::AuthRejected {
+                    status: response.status.to_string(),
+                    reason: response.reason.clone(),
+                },
+            );
+            return Err(SessionError::Rejected(response));
+        }
+        println!(
+            "Client: Authentication successful (protocol v{}, capabilities {:#x})",
+            response.protocol_version, response.capabilities
+        );
+
+        // 可恢复的会话: 断开后在同一个中继上建立新连接 (新的本地端口) 并恢复
+        if response.capabilities & CAP_RESUME != 0 {
+            let id = handshake::read_session_id(&mut stream)
+                .await
+                .map_err(SessionError::Handshake)?;
+            let transport = transport.clone();
+            let connector: Connector = Arc::new(move || {
+                let transport = transport.clone();
+                Box::pin(async move { transport.connect().await.map(|tunnel| tunnel.stream) })
+            });
+            // 复用会话的心跳保证连接上一直有数据, 这时才能按超时判断连接是否断开
+            let link_timeout =
+                (response.capabilities & CAP_MUX != 0).then_some(self.heartbeat.timeout);
+            stream = Box::new(ResumableStream::client(stream, id, connector, link_timeout));
+        }
+        Ok(Connected {
+            stream,
+            capabilities: response.capabilities,
+            peer: tunnel.peer,
+            relay: relay.clone(),
+        })
+    }
+}
+
+// 在后台发送, 游戏读得慢时不阻塞隧道; 管道断开时心跳会让 agent 退出, 这里不用处理发送失败
+fn notify_game(game: &ipc::Sender, message: Message) {
+    let game = game.clone();
+    tokio::spawn(async move {
+        let _ = game.send(&message).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::api::Api;
+    use crate::token::TicketRequest;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tunnel::auth::AuthStatus;
+    use tunnel::transport::memory::{self, MemoryTransport};
+    use tunnel::transport::{ChannelListener, Transport, TunnelListener};
+
+    const TOKEN: &str = "valid-token";
+    const MAX_TOKEN_LEN: usize = 1024;
+
+    // 测试不应该等待这么久, 超时说明卡住了
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    const HEARTBEAT: HeartbeatConfig = HeartbeatConfig {
+        interval: Duration::from_secs(1),
+        timeout: Duration::from_secs(5),
+    };
+
+    fn memory_pair(port: u16) -> (MemoryTransport, ChannelListener) {
+        memory::pair(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    // 中继的替身: 令牌等于 TOKEN 时启用复用, 每个流原样发回收到的数据; 返回收到的 hello 数
+    fn serve(mut listener: ChannelListener) -> Arc<AtomicUsize> {
+        let hellos = Arc::new(AtomicUsize::new(0));
+        let counter = hellos.clone();
+        tokio::spawn(async move {
+            while let Ok(mut tunnel) = listener.accept().await {
+                let hello = Hello::read_from(&mut tunnel.stream, MAX_TOKEN_LEN).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                if hello.token != TOKEN {
+                    AuthResponse::reject(AuthStatus::Banned, "You are banned from this server")
+                        .write_to(&mut tunnel.stream)
+                        .await
+                        .unwrap();
+                    continue;
+                }
+                AuthResponse::ok(hello.max_version, CAP_MUX)
+                    .write_to(&mut tunnel.stream)
+                    .await
+                    .unwrap();
+                let heartbeat = Heartbeat {
+                    interval: HEARTBEAT.interval,
+                    timeout: None,
+                };
+                let mux = MuxSession::new(tunnel.stream, Role::Server, heartbeat);
+                tokio::spawn(async move {
+                    while let Some(stream) = mux.accept().await {
+                        tokio::spawn(async move {
+                            let (mut reader, mut writer) = tokio::io::split(stream);
+                            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                            writer.shutdown().await.unwrap();
+                        });
+                    }
+                });
+            }
+        });
+        hellos
+    }
+
+    // 返回会话和游戏一端的管道
+    fn session(transports: Vec<Arc<dyn Transport>>, token: &str) -> (TunnelSession, ipc::Receiver) {
+        let (agent, game) = tokio::io::duplex(64 * 1024);
+        let (_, sender) = ipc::split(agent);
+        let (receiver, _) = ipc::split(game);
+        let session = TunnelSession::new(
+            Arc::new(RelaySet::new(transports)),
+            Arc::new(TokenProvider::fixed(token)),
+            HEARTBEAT,
+            sender,
+        );
+        (session, receiver)
+    }
+
+    async fn echo(stream: &mut BoxedStream, data: &[u8]) {
+        stream.write_all(data).await.unwrap();
+        let mut echoed = vec![0u8; data.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, data);
+    }
+
+    #[tokio::test]
+    async fn streams_share_one_authenticated_tunnel() {
+        let (transport, listener) = memory_pair(19132);
+        let hellos = serve(listener);
+        let (session, _game) = session(vec![Arc::new(transport)], TOKEN);
+
+        tokio::time::timeout(TEST_TIMEOUT, async {
+            let mut first = session.open_stream().await.unwrap();
+            let mut second = session.open_stream().await.unwrap();
+            echo(&mut first, b"first").await;
+            echo(&mut second, b"second").await;
+        })
+        .await
+        .unwrap();
+        assert_eq!(hellos.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rejection_is_reported_to_the_game() {
+        let (transport, listener) = memory_pair(19132);
+        serve(listener);
+        let (session, mut game) = session(vec![Arc::new(transport)], "forged-token");
+
+        let error = tokio::time::timeout(TEST_TIMEOUT, session.open_stream())
+            .await
+            .unwrap()
+            .err()
+            .unwrap();
+        match &error {
+            SessionError::Rejected(response) => assert_eq!(response.status, AuthStatus::Banned),
+            other => panic!("expected a rejection, got {}", other),
+        }
+        assert_eq!(error.player_reason(), "You are banned from this server");
+        let message = tokio::time::timeout(TEST_TIMEOUT, game.recv()).await.unwrap().unwrap();
+        assert_eq!(
+            message,
+            Message::AuthRejected {
+                status: "banned".to_string(),
+                reason: "You are banned from this server".to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn token_failure_does_not_try_any_relay() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let api = Api::new(&format!("http://{}/", closed.local_addr().unwrap())).unwrap();
+        drop(closed);
+        let tokens = TokenProvider::new(
+            api,
+            "ticket",
+            TicketRequest {
+                player_name: "test".to_string(),
+            },
+        );
+        let (transport, listener) = memory_pair(19132);
+        let hellos = serve(listener);
+        let (mut session, _game) = session(vec![Arc::new(transport)], TOKEN);
+        session.tokens = Arc::new(tokens);
+
+        let error = tokio::time::timeout(TEST_TIMEOUT, session.open_stream())
+            .await
+            .unwrap()
+            .err()
+            .unwrap();
+        assert!(matches!(error, SessionError::Token(_)), "{}", error);
+        assert_eq!(error.player_reason(), "Unable to log in, please restart the game");
+        assert_eq!(hellos.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_relay_times_out_and_the_next_one_is_used() {
+        // 接受隧道但从不回复认证
+        let (silent, mut silent_listener) = memory_pair(19132);
+        tokio::spawn(async move {
+            let mut tunnels = Vec::new();
+            while let Ok(tunnel) = silent_listener.accept().await {
+                tunnels.push(tunnel);
+            }
+        });
+        let (transport, listener) = memory_pair(19133);
+        let hellos = serve(listener);
+        let (session, _game) = session(vec![Arc::new(silent), Arc::new(transport)], TOKEN);
+
+        let started = tokio::time::Instant::now();
+        let mut stream = tokio::time::timeout(RELAY_TIMEOUT + TEST_TIMEOUT, session.open_stream())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= RELAY_TIMEOUT);
+        echo(&mut stream, b"hello").await;
+        assert_eq!(hellos.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn unreachable_relay_fails_over_to_the_next_one() {
+        // 监听器已经关闭, 连接会被拒绝
+        let (closed, _) = memory_pair(19132);
+        let (transport, listener) = memory_pair(19133);
+        let hellos = serve(listener);
+        let (session, _game) = session(vec![Arc::new(closed), Arc::new(transport)], TOKEN);
+
+        let mut stream = tokio::time::timeout(TEST_TIMEOUT, session.open_stream())
+            .await
+            .unwrap()
+            .unwrap();
+        tokio::time::timeout(TEST_TIMEOUT, echo(&mut stream, b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(hellos.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,147 @@
+// 通过后端 API 获取 JWT, 缓存并在过期前刷新
+use crate::api::api::Api;
+use anyhow::{Result, anyhow};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+// 距离过期不足这个时间的令牌视为需要刷新
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+// 刷新失败后的重试间隔
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// JWT声明结构体, 客户端只关心过期时间
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    exp: u64, // 过期时间 (Unix时间戳)
+}
+
+// 申请令牌的请求体
+#[derive(Debug, Clone, Serialize)]
+pub struct TicketRequest {
+    pub player_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TicketResponse {
+    token: String,
+}
+
+struct CachedToken {
+    token: String,
+    exp: u64,
+}
+
+pub struct TokenProvider {
+    api: Api,
+    ticket_path: String,
+    request: TicketRequest,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+impl TokenProvider {
+    pub fn new(api: Api, ticket_path: &str, request: TicketRequest) -> Self {
+        TokenProvider {
+            api,
+            ticket_path: ticket_path.to_string(),
+            request,
+            cached: Mutex::new(None),
+        }
+    }
+
+    // 测试用: 缓存一个不会过期的令牌, 不访问后端
+    #[cfg(test)]
+    pub fn fixed(token: &str) -> Self {
+        TokenProvider {
+            api: Api::new("http://127.0.0.1:9/").unwrap(),
+            ticket_path: String::new(),
+            request: TicketRequest {
+                player_name: "test".to_string(),
+            },
+            cached: Mutex::new(Some(CachedToken {
+                token: token.to_string(),
+                exp: u64::MAX,
+            })),
+        }
+    }
+
+    // 返回一个有效的令牌, 缓存的令牌快过期时先向后端重新申请
+    pub async fn token(&self) -> Result<String> {
+        let mut cached = self.cached.lock().await;
+        if let Some(current) = cached.as_ref()
+            && !needs_refresh(current.exp)
+        {
+            return Ok(current.token.clone());
+        }
+
+        let fresh = self.fetch().await?;
+        let token = fresh.token.clone();
+        *cached = Some(fresh);
+        Ok(token)
+    }
+
+    // 后台任务: 在令牌过期前主动刷新, 避免新连接在握手时等待后端
+    pub fn spawn_refresh_task(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let delay = match self.token().await {
+                    Ok(_) => {
+                        let exp = self.cached.lock().await.as_ref().map_or(0, |c| c.exp);
+                        Duration::from_secs(exp.saturating_sub(now()))
+                            .saturating_sub(REFRESH_MARGIN)
+                    }
+                    Err(e) => {
+                        eprintln!("Client: Failed to refresh token: {}", e);
+                        RETRY_DELAY
+                    }
+                };
+                // 至少等待一秒, 防止后端签发的令牌有效期过短时空转
+                tokio::time::sleep(delay.max(Duration::from_secs(1))).await;
+            }
+        })
+    }
+
+    async fn fetch(&self) -> Result<CachedToken> {
+        let response = self
+            .api
+            .post::<TicketResponse, _>(&self.ticket_path, &self.request)
+            .await?;
+        if response.code != 0 {
+            return Err(anyhow!(
+                "ticket request rejected (code {}): {}",
+                response.code,
+                response.message.unwrap_or_default()
+            ));
+        }
+        let token = response
+            .data
+            .ok_or_else(|| anyhow!("ticket response has no data"))?
+            .token;
+        let exp = read_expiry(&token)?;
+        println!("Client: Obtained token, expires at {}", exp);
+        Ok(CachedToken { token, exp })
+    }
+}
+
+// 读取令牌中的 exp; 签名由服务器校验, 客户端没有也不需要密钥
+fn read_expiry(token: &str) -> Result<u64> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    let data = decode::<Claims>(token, &DecodingKey::from_secret(&[]), &validation)
+        .map_err(|e| anyhow!("backend returned a malformed token: {}", e))?;
+    Ok(data.claims.exp)
+}
+
+fn needs_refresh(exp: u64) -> bool {
+    now() + REFRESH_MARGIN.as_secs() >= exp
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
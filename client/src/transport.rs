@@ -0,0 +1,37 @@
+// 按配置创建到中继服务器的隧道传输
+use crate::config::{RelayEndpoint, TlsSettings, TransportConfig};
+use std::io;
+use std::sync::Arc;
+use tunnel::transport::Transport;
+use tunnel::transport::kcp::KcpTransport;
+use tunnel::transport::quic::QuicTransport;
+use tunnel::transport::tcp::TcpTransport;
+use tunnel::transport::tls::TlsTransport;
+
+// QUIC 需要在 tokio 运行时内创建
+pub fn build(relay: &RelayEndpoint, config: &TransportConfig) -> io::Result<Arc<dyn Transport>> {
+    Ok(match config {
+        TransportConfig::Kcp(kcp) => Arc::new(KcpTransport::new(relay.addr, *kcp)),
+        TransportConfig::Quic(tls, options) => Arc::new(QuicTransport::new(
+            relay.addr,
+            &server_name(relay, tls),
+            tls.ca_file.as_deref(),
+            options,
+        )?),
+        TransportConfig::Tcp => Arc::new(TcpTransport::new(relay.addr)),
+        TransportConfig::Tls(tls) => Arc::new(TlsTransport::new(
+            relay.addr,
+            &server_name(relay, tls),
+            tls.ca_file.as_deref(),
+        )?),
+    })
+}
+
+// 校验证书使用的名称: 中继自己的设置 > AGENT_TLS_SERVER_NAME > 中继的 IP
+fn server_name(relay: &RelayEndpoint, tls: &TlsSettings) -> String {
+    relay
+        .server_name
+        .clone()
+        .or_else(|| tls.server_name.clone())
+        .unwrap_or_else(|| relay.addr.ip().to_string())
+}
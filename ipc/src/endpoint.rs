@@ -0,0 +1,392 @@
+// agent 与游戏之间的本地连接: Windows 上使用命名管道, Linux/macOS 上使用 Unix 域套接字
+//
+// 两端使用相同的端点名称, 由各平台的实现转换成管道名或套接字路径;
+// 建立连接后都通过 split 得到消息的收发端, 上层协议与平台无关
+//
+// 端点名称和一次性随机数每次启动随机生成, 通过环境变量交给对端, 其他本地程序无法预先占用或冒充;
+// 游戏连接后先发送 Hello 带上随机数, agent 还会检查对端的进程ID (Unix 上还检查用户)
+use crate::{Message, Receiver, Sender, split, unexpected};
+use std::env;
+use std::process::Command;
+use std::time::Duration;
+use tokio::io;
+
+// 启动 agent 时传递端点的环境变量
+pub const ENDPOINT_ENV: &str = "AGENT_IPC_ENDPOINT";
+pub const NONCE_ENV: &str = "AGENT_IPC_NONCE";
+pub const PEER_PID_ENV: &str = "AGENT_IPC_PEER_PID"; // 允许连接的进程, 即启动 agent 的游戏进程
+
+// 游戏连接后立即发送 Hello; 校验期间不接受其他连接, 不发送的连接不能长时间挡住游戏
+const HELLO_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub name: String,
+    pub nonce: String,
+    pub peer_pid: Option<u32>, // 为空时不检查对端的进程ID
+}
+
+impl Credentials {
+    pub fn generate() -> io::Result<Self> {
+        Ok(Credentials {
+            name: format!("agent-{}", random_hex(8)?),
+            nonce: random_hex(32)?,
+            peer_pid: None,
+        })
+    }
+
+    // 名称和随机数都存在时才返回
+    pub fn from_env() -> Option<Self> {
+        Some(Credentials {
+            name: env::var(ENDPOINT_ENV).ok()?,
+            nonce: env::var(NONCE_ENV).ok()?,
+            peer_pid: env::var(PEER_PID_ENV).ok().and_then(|pid| pid.parse().ok()),
+        })
+    }
+
+    // 启动 agent 前设置环境变量, 只允许当前进程连接
+    pub fn apply(&self, command: &mut Command) {
+        command
+            .env(ENDPOINT_ENV, &self.name)
+            .env(NONCE_ENV, &self.nonce)
+            .env(PEER_PID_ENV, std::process::id().to_string());
+    }
+}
+
+fn random_hex(len: usize) -> io::Result<String> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes).map_err(|e| io::Error::other(format!("cannot generate random bytes: {}", e)))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// 比较时间与内容无关, 不泄露随机数的前缀
+fn nonce_matches(received: &str, expected: &str) -> bool {
+    received.len() == expected.len()
+        && received
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn denied(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, message)
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::os::windows::io::AsRawHandle;
+    use tokio::io;
+    use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions};
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::System::Pipes::GetNamedPipeClientProcessId;
+
+    pub type Server = NamedPipeServer;
+    pub type Stream = NamedPipeServer;
+    pub type Client = NamedPipeClient;
+
+    pub fn address(name: &str) -> String {
+        format!(r"\\.\pipe\{}", name)
+    }
+
+    // 只允许创建第一个实例, 名称已经被占用时失败
+    pub fn bind(address: &str) -> io::Result<NamedPipeServer> {
+        ServerOptions::new().first_pipe_instance(true).create(address)
+    }
+
+    // 连接上的实例交给调用方, 同时创建下一个实例继续等待
+    pub async fn accept(server: &mut NamedPipeServer, address: &str) -> io::Result<NamedPipeServer> {
+        server.connect().await?;
+        let next = ServerOptions::new().create(address)?;
+        Ok(std::mem::replace(server, next))
+    }
+
+    pub fn peer_pid(stream: &NamedPipeServer) -> io::Result<Option<u32>> {
+        let mut pid = 0u32;
+        unsafe { GetNamedPipeClientProcessId(HANDLE(stream.as_raw_handle()), &mut pid) }
+            .map_err(io::Error::other)?;
+        Ok(Some(pid))
+    }
+
+    pub async fn connect(address: &str) -> io::Result<NamedPipeClient> {
+        ClientOptions::new().open(address)
+    }
+
+    pub fn cleanup(_address: &str) {}
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io;
+    use tokio::net::{UnixListener, UnixStream};
+
+    pub type Server = UnixListener;
+    pub type Stream = UnixStream;
+    pub type Client = UnixStream;
+
+    pub fn address(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("{}.sock", name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // 上一个 agent 异常退出时会留下套接字文件, 绑定前先删除; 绑定后只允许当前用户连接
+    pub fn bind(address: &str) -> io::Result<UnixListener> {
+        cleanup(address);
+        let listener = UnixListener::bind(address)?;
+        fs::set_permissions(address, Permissions::from_mode(0o600))?;
+        Ok(listener)
+    }
+
+    pub async fn accept(listener: &mut UnixListener, _address: &str) -> io::Result<UnixStream> {
+        let (stream, _) = listener.accept().await?;
+        Ok(stream)
+    }
+
+    pub fn peer_pid(stream: &UnixStream) -> io::Result<Option<u32>> {
+        let credentials = stream.peer_cred()?;
+        if credentials.uid() != unsafe { libc::getuid() } {
+            return Err(super::denied(format!(
+                "IPC peer belongs to user {}",
+                credentials.uid()
+            )));
+        }
+        Ok(credentials.pid().map(|pid| pid as u32))
+    }
+
+    pub async fn connect(address: &str) -> io::Result<UnixStream> {
+        UnixStream::connect(address).await
+    }
+
+    pub fn cleanup(address: &str) {
+        let _ = fs::remove_file(address);
+    }
+}
+
+// agent 端: 在端点上等待游戏连接, 校验通过的第一个连接被接受后不再接受其他连接
+pub struct Listener {
+    server: platform::Server,
+    address: String,
+    credentials: Credentials,
+}
+
+impl Listener {
+    pub fn bind(credentials: &Credentials) -> io::Result<Self> {
+        let address = platform::address(&credentials.name);
+        Ok(Listener {
+            server: platform::bind(&address)?,
+            address,
+            credentials: credentials.clone(),
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    // 进程ID或随机数不符、或没有及时发送 Hello 的连接会被断开, 继续等待下一个连接
+    pub async fn accept(mut self) -> io::Result<(Receiver, Sender)> {
+        loop {
+            let stream = platform::accept(&mut self.server, &self.address).await?;
+            match self.verify(stream).await {
+                Ok(connection) => return Ok(connection),
+                Err(e) => eprintln!("IPC: Rejected a connection on {}: {}", self.address, e),
+            }
+        }
+    }
+
+    async fn verify(&self, stream: platform::Stream) -> io::Result<(Receiver, Sender)> {
+        let pid = platform::peer_pid(&stream)?;
+        if let Some(expected) = self.credentials.peer_pid
+            && pid != Some(expected)
+        {
+            return Err(denied(format!(
+                "IPC peer process {:?} is not the game process {}",
+                pid, expected
+            )));
+        }
+
+        let (mut receiver, sender) = split(stream);
+        let hello = tokio::time::timeout(HELLO_TIMEOUT, receiver.recv())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "IPC peer did not send hello"))??;
+        match hello {
+            Message::Hello { nonce } if nonce_matches(&nonce, &self.credentials.nonce) => {
+                Ok((receiver, sender))
+            }
+            Message::Hello { .. } => Err(denied("IPC peer sent a wrong nonce".to_string())),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+impl Drop for Listener {
+    // 连接建立后其他进程不应该再能连接这个端点
+    fn drop(&mut self) {
+        platform::cleanup(&self.address);
+    }
+}
+
+// 游戏端: 连接 agent 创建的端点并发送随机数; agent 还没有创建端点时返回错误, 由调用方重试
+pub async fn connect(credentials: &Credentials) -> io::Result<(Receiver, Sender)> {
+    let client: platform::Client = platform::connect(&platform::address(&credentials.name)).await?;
+    let (receiver, sender) = split(client);
+    sender
+        .send(&Message::Hello {
+            nonce: credentials.nonce.clone(),
+        })
+        .await?;
+    Ok((receiver, sender))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    // 测试不应该等待这么久, 超时说明卡住了
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // 只允许当前进程连接的凭据, 与 apply 交给游戏的一致
+    fn credentials() -> Credentials {
+        Credentials {
+            peer_pid: Some(std::process::id()),
+            ..Credentials::generate().unwrap()
+        }
+    }
+
+    fn with_nonce(credentials: &Credentials, nonce: &str) -> Credentials {
+        Credentials {
+            nonce: nonce.to_string(),
+            ..credentials.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn game_with_the_nonce_is_accepted() {
+        let credentials = credentials();
+        let listener = Listener::bind(&credentials).unwrap();
+        let accept = tokio::spawn(listener.accept());
+
+        let (mut game_receiver, game_sender) = connect(&credentials).await.unwrap();
+        let (mut agent_receiver, agent_sender) = tokio::time::timeout(TEST_TIMEOUT, accept)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        agent_sender.send(&Message::Heartbeat).await.unwrap();
+        assert_eq!(game_receiver.recv().await.unwrap(), Message::Heartbeat);
+        game_sender.send(&Message::Ack).await.unwrap();
+        assert_eq!(agent_receiver.recv().await.unwrap(), Message::Ack);
+    }
+
+    #[tokio::test]
+    async fn wrong_nonce_is_rejected_and_the_next_game_is_accepted() {
+        let credentials = credentials();
+        let listener = Listener::bind(&credentials).unwrap();
+        let accept = tokio::spawn(listener.accept());
+
+        let wrong = with_nonce(&credentials, &"0".repeat(credentials.nonce.len()));
+        let (mut squatter, _squatter_sender) = connect(&wrong).await.unwrap();
+        let e = tokio::time::timeout(TEST_TIMEOUT, squatter.recv()).await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let (mut game_receiver, _game_sender) = connect(&credentials).await.unwrap();
+        let (_agent_receiver, agent_sender) = tokio::time::timeout(TEST_TIMEOUT, accept)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        agent_sender.send(&Message::Heartbeat).await.unwrap();
+        assert_eq!(game_receiver.recv().await.unwrap(), Message::Heartbeat);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peer_is_dropped_after_the_hello_timeout() {
+        let credentials = credentials();
+        let listener = Listener::bind(&credentials).unwrap();
+        let accept = tokio::spawn(listener.accept());
+
+        // 只连接不发送 Hello
+        let client = platform::connect(&platform::address(&credentials.name)).await.unwrap();
+        let (mut silent, _silent_sender) = split(client);
+        let start = tokio::time::Instant::now();
+        let e = tokio::time::timeout(TEST_TIMEOUT, silent.recv()).await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(start.elapsed() >= HELLO_TIMEOUT);
+
+        // 之后游戏仍然可以连接
+        let _game = connect(&credentials).await.unwrap();
+        tokio::time::timeout(TEST_TIMEOUT, accept).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_processes_are_rejected() {
+        let credentials = Credentials {
+            peer_pid: Some(std::process::id() + 1),
+            ..credentials()
+        };
+        let listener = Listener::bind(&credentials).unwrap();
+        let accept = tokio::spawn(listener.accept());
+
+        // 随机数正确也不能连接
+        let (mut game_receiver, _game_sender) = connect(&credentials).await.unwrap();
+        let e = tokio::time::timeout(TEST_TIMEOUT, game_receiver.recv()).await.unwrap();
+        // agent 没有读取 Hello 就断开, 对端可能看到连接被重置
+        let kind = e.unwrap_err().kind();
+        assert!(
+            matches!(kind, io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset),
+            "{:?}",
+            kind
+        );
+        accept.abort();
+    }
+
+    #[test]
+    fn nonces_must_match_exactly() {
+        let nonce = "0123456789abcdef";
+        let cases = [
+            ("0123456789abcdef", true),
+            ("0123456789abcdee", false), // 只有最后一个字节不同
+            ("1123456789abcdef", false), // 只有第一个字节不同
+            ("0123456789abcde", false),  // 前缀
+            ("0123456789abcdef0", false),
+            ("0123456789ABCDEF", false),
+            ("", false),
+        ];
+        for (received, expected) in cases {
+            assert_eq!(nonce_matches(received, nonce), expected, "{:?}", received);
+        }
+    }
+
+    #[tokio::test]
+    async fn socket_is_private_and_removed_with_the_listener() {
+        let credentials = credentials();
+        let listener = Listener::bind(&credentials).unwrap();
+        let address = listener.address().to_string();
+        let mode = std::fs::metadata(&address).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        drop(listener);
+        assert!(!std::path::Path::new(&address).exists());
+        assert!(connect(&credentials).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stale_socket_file_is_replaced() {
+        let credentials = credentials();
+        let address = platform::address(&credentials.name);
+        // 上一个 agent 异常退出时留下的套接字文件
+        drop(std::os::unix::net::UnixListener::bind(&address).unwrap());
+        assert!(std::path::Path::new(&address).exists());
+
+        let listener = Listener::bind(&credentials).unwrap();
+        let accept = tokio::spawn(listener.accept());
+        let _game = connect(&credentials).await.unwrap();
+        tokio::time::timeout(TEST_TIMEOUT, accept).await.unwrap().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,339 @@
+// agent 与游戏 (java_native / testexe) 之间的本地通信协议, 连接方式见 endpoint 模块
+//
+// 每条消息是一个帧 (大端序):
+//   u32 长度, 之后是 JSON 编码的 {"version": 协议版本, "message": 消息}
+// 版本不一致的消息按格式错误处理
+//
+// 游戏连接管道后先发送带有随机数的 Hello (见 endpoint 模块), agent 校验后发送 PortAssignment, 游戏回复 Ack;
+// 之后管道保持打开, 双方定期发送 Heartbeat, 一方退出前发送 Shutdown 或 Error 说明原因;
+// agent 还会把隧道的状态 (TunnelRtt, AuthRejected, ServerNotice) 转发给游戏
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+pub mod endpoint;
+
+pub const PROTOCOL_VERSION: u16 = 3;
+
+// 单条消息的最大长度, 防止对端用一个很大的长度让我们分配内存
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+// 心跳的发送间隔, 以及多久没有收到对端的消息时认为对端已经退出
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+pub const TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortAssignment {
+    pub network_port: u16,
+    pub forward_port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Hello { nonce: String },        // 游戏 -> agent: 连接后的第一条消息, 证明自己是启动 agent 的一方
+    PortAssignment(PortAssignment), // agent -> 游戏: 游戏应该连接的本地端口
+    Ack,                            // 游戏 -> agent: 已经收到端口
+    Heartbeat,
+    Error { message: String },      // 发送方因为错误即将退出
+    Shutdown { reason: String },    // 发送方正常退出
+    TunnelRtt { rtt_ms: u64 },      // agent -> 游戏: 隧道最近一次测得的往返时间
+    AuthRejected { status: String, reason: String }, // agent -> 游戏: 服务器拒绝了认证
+    ServerNotice { message: String }, // agent -> 游戏: 服务器推送的消息
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<M> {
+    version: u16,
+    message: M,
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> io::Result<()> {
+    let body = serde_json::to_vec(&Envelope {
+        version: PROTOCOL_VERSION,
+        message,
+    })?;
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Message> {
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "IPC message length {} exceeds the limit of {} bytes",
+                len, MAX_MESSAGE_LEN
+            ),
+        ));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+
+    // 先检查版本再解析消息, 新版本的消息可能无法按本版本解析
+    let envelope: Envelope<serde_json::Value> = serde_json::from_slice(&body)?;
+    if envelope.version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported IPC protocol version {}", envelope.version),
+        ));
+    }
+    Ok(serde_json::from_value(envelope.message)?)
+}
+
+// 管道的发送端, 可以在多个任务之间共享, 消息之间不会交错
+#[derive(Clone)]
+pub struct Sender {
+    writer: Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>,
+}
+
+impl Sender {
+    // 对端不再读取时写入会阻塞, 按超时处理; 超时后管道上可能留有半条消息, 不应再继续使用
+    pub async fn send(&self, message: &Message) -> io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        match tokio::time::timeout(TIMEOUT, write_message(&mut *writer, message)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "IPC write timed out")),
+        }
+    }
+}
+
+pub struct Receiver {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+}
+
+impl Receiver {
+    pub async fn recv(&mut self) -> io::Result<Message> {
+        read_message(&mut self.reader).await
+    }
+
+    // 握手阶段对端应该立即回复, 超时按错误处理
+    async fn recv_timeout(&mut self) -> io::Result<Message> {
+        match tokio::time::timeout(TIMEOUT, self.recv()).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "IPC peer did not reply")),
+        }
+    }
+}
+
+pub fn split<S: AsyncRead + AsyncWrite + Send + 'static>(pipe: S) -> (Receiver, Sender) {
+    let (reader, writer) = io::split(pipe);
+    (
+        Receiver {
+            reader: Box::new(reader),
+        },
+        Sender {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+        },
+    )
+}
+
+// agent 端的握手: 发送分配的端口并等待游戏确认
+pub async fn send_ports(
+    receiver: &mut Receiver,
+    sender: &Sender,
+    ports: PortAssignment,
+) -> io::Result<()> {
+    sender.send(&Message::PortAssignment(ports)).await?;
+    match receiver.recv_timeout().await? {
+        Message::Ack => Ok(()),
+        other => Err(unexpected(other)),
+    }
+}
+
+// 游戏端的握手: 等待 agent 分配端口并确认
+pub async fn receive_ports(receiver: &mut Receiver, sender: &Sender) -> io::Result<PortAssignment> {
+    match receiver.recv_timeout().await? {
+        Message::PortAssignment(ports) => {
+            sender.send(&Message::Ack).await?;
+            Ok(ports)
+        }
+        other => Err(unexpected(other)),
+    }
+}
+
+fn unexpected(message: Message) -> io::Error {
+    match message {
+        Message::Error { message } => io::Error::other(format!("IPC peer reported an error: {}", message)),
+        Message::Shutdown { reason } => io::Error::other(format!("IPC peer shut down: {}", reason)),
+        other => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected IPC message: {:?}", other),
+        ),
+    }
+}
+
+// 心跳结束的原因
+#[derive(Debug)]
+pub enum Closed {
+    Shutdown(String), // 对端正常退出
+    Error(String),    // 对端因为错误退出
+    Io(io::Error),    // 管道关闭、读写超时或收到了无法解析的消息
+}
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Closed::Shutdown(reason) => write!(f, "peer shut down: {}", reason),
+            Closed::Error(message) => write!(f, "peer reported an error: {}", message),
+            Closed::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// 在管道上双向心跳: 定期发送 Heartbeat, 对端退出或超时没有发送任何消息时返回原因;
+// 心跳以外的消息交给 on_message 处理
+pub async fn heartbeat(mut receiver: Receiver, sender: Sender, mut on_message: impl FnMut(Message)) -> Closed {
+    let send_loop = async {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sender.send(&Message::Heartbeat).await {
+                return Closed::Io(e);
+            }
+        }
+    };
+    let receive_loop = async {
+        loop {
+            match tokio::time::timeout(TIMEOUT, receiver.recv()).await {
+                Err(_) => {
+                    return Closed::Io(io::Error::new(io::ErrorKind::TimedOut, "no heartbeat received"));
+                }
+                Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Closed::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "pipe closed"));
+                }
+                Ok(Err(e)) => return Closed::Io(e),
+                Ok(Ok(Message::Shutdown { reason })) => return Closed::Shutdown(reason),
+                Ok(Ok(Message::Error { message })) => return Closed::Error(message),
+                // 心跳和其他消息都说明对端仍在运行
+                Ok(Ok(Message::Heartbeat)) => {}
+                Ok(Ok(message)) => on_message(message),
+            }
+        }
+    };
+    tokio::select! {
+        closed = send_loop => closed,
+        closed = receive_loop => closed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 按协议格式写入一个帧, 版本和内容由测试决定
+    async fn write_raw<W: AsyncWrite + Unpin>(writer: &mut W, body: &[u8]) {
+        writer.write_u32(body.len() as u32).await.unwrap();
+        writer.write_all(body).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip() {
+        let (mut a, mut b) = io::duplex(MAX_MESSAGE_LEN * 2);
+        let messages = [
+            Message::Hello { nonce: "00ff".to_string() },
+            Message::PortAssignment(PortAssignment { network_port: 25565, forward_port: 25566 }),
+            Message::Ack,
+            Message::Heartbeat,
+            Message::TunnelRtt { rtt_ms: 42 },
+            Message::AuthRejected { status: "banned".to_string(), reason: "cheating".to_string() },
+            Message::ServerNotice { message: "restart in 5 minutes".to_string() },
+            Message::Error { message: "relay unreachable".to_string() },
+            Message::Shutdown { reason: "game exited".to_string() },
+        ];
+        for message in &messages {
+            write_message(&mut a, message).await.unwrap();
+        }
+        for message in &messages {
+            assert_eq!(&read_message(&mut b).await.unwrap(), message);
+        }
+    }
+
+    #[tokio::test]
+    async fn other_protocol_versions_are_rejected() {
+        for version in [PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let (mut a, mut b) = io::duplex(1024);
+            let body = format!(r#"{{"version":{},"message":{{"type":"ack"}}}}"#, version);
+            write_raw(&mut a, body.as_bytes()).await;
+            let e = read_message(&mut b).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(e.to_string(), format!("unsupported IPC protocol version {}", version));
+        }
+
+        // 版本正确时同样的帧可以解析
+        let (mut a, mut b) = io::duplex(1024);
+        let body = format!(r#"{{"version":{},"message":{{"type":"ack"}}}}"#, PROTOCOL_VERSION);
+        write_raw(&mut a, body.as_bytes()).await;
+        assert_eq!(read_message(&mut b).await.unwrap(), Message::Ack);
+    }
+
+    #[tokio::test]
+    async fn messages_above_the_limit_are_rejected() {
+        // 正好达到上限的消息可以读取
+        let empty = Message::ServerNotice { message: String::new() };
+        let overhead = serde_json::to_vec(&Envelope { version: PROTOCOL_VERSION, message: &empty })
+            .unwrap()
+            .len();
+        let largest = Message::ServerNotice { message: "a".repeat(MAX_MESSAGE_LEN - overhead) };
+        let (mut a, mut b) = io::duplex(MAX_MESSAGE_LEN * 2);
+        write_message(&mut a, &largest).await.unwrap();
+        assert_eq!(read_message(&mut b).await.unwrap(), largest);
+
+        // 超过上限时只看长度就拒绝, 不读取也不分配消息体
+        let (mut a, mut b) = io::duplex(1024);
+        a.write_u32(MAX_MESSAGE_LEN as u32 + 1).await.unwrap();
+        let e = read_message(&mut b).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "IPC message length {} exceeds the limit of {} bytes",
+                MAX_MESSAGE_LEN + 1,
+                MAX_MESSAGE_LEN
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn ports_are_assigned_and_acknowledged() {
+        let (agent, game) = io::duplex(1024);
+        let (mut agent_receiver, agent_sender) = split(agent);
+        let (mut game_receiver, game_sender) = split(game);
+        let ports = PortAssignment { network_port: 40001, forward_port: 40002 };
+
+        let (sent, received) = tokio::join!(
+            send_ports(&mut agent_receiver, &agent_sender, ports),
+            receive_ports(&mut game_receiver, &game_sender),
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), ports);
+    }
+
+    #[tokio::test]
+    async fn port_assignment_fails_on_an_unexpected_reply() {
+        let (agent, game) = io::duplex(1024);
+        let (mut agent_receiver, agent_sender) = split(agent);
+        let (mut game_receiver, game_sender) = split(game);
+        let ports = PortAssignment { network_port: 40001, forward_port: 40002 };
+
+        let game = async {
+            assert_eq!(game_receiver.recv().await.unwrap(), Message::PortAssignment(ports));
+            let reason = "game exited".to_string();
+            game_sender.send(&Message::Shutdown { reason }).await.unwrap();
+        };
+        let (sent, ()) = tokio::join!(send_ports(&mut agent_receiver, &agent_sender, ports), game);
+        assert_eq!(sent.unwrap_err().to_string(), "IPC peer shut down: game exited");
+
+        // 游戏端等待端口时收到其他消息同样失败
+        agent_sender.send(&Message::Heartbeat).await.unwrap();
+        let e = receive_ports(&mut game_receiver, &game_sender).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
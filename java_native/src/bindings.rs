@@ -0,0 +1,207 @@
+// mod 中的 Java 类通过 System.loadLibrary 加载本库后调用这里导出的 native 方法:
+//
+//   package org.clientsideagent.bootstrap;
+//
+//   public final class BootstrapNative {
+//       // 启动 agent 并等待握手完成; 之后的调用返回当前运行的 agent 的端口, agent 不再重启后再次调用会重新启动
+//       // agentPath 为 null 时依次使用 AGENT_PATH 环境变量和游戏目录下的 agent.exe
+//       // 等待时间和重启次数由环境变量调整 (见 supervisor 模块的 Policy), 失败时抛出:
+//       //   FileNotFoundException    找不到 agent
+//       //   InterruptedIOException   agent 没有在限定时间内开始运行
+//       //   IOException              agent 已经退出或连续崩溃后不再重启
+//       //   IllegalArgumentException 环境变量的值无效
+//       public static native AgentPorts start(String agentPath) throws IOException;
+//       public static native boolean isAgentAlive();
+//       // agent 没有在运行的原因 (启动中、重启中或已经退出), agent 正常运行时为 null
+//       public static native String agentError();
+//       // 注册接收 agent 事件的监听器 (见 callbacks 模块), 传入 null 时取消注册
+//       public static native void setListener(AgentListener listener);
+//   }
+//
+// 库被卸载时 (JNI_OnUnload) 取消监听器并结束 agent
+// 本库中的 panic 不会传到 JVM, 而是抛出 IllegalStateException
+//
+//   public record AgentPorts(int networkPort, int forwardPort) {}
+use crate::error::StartError;
+use crate::{agent_error, callbacks, pipe, shutdown};
+use ipc::PortAssignment;
+use jni::JNIEnv;
+use jni::JavaVM;
+use jni::objects::{GlobalRef, JClass, JObject, JString, JValue};
+use jni::sys::{JNI_ERR, JNI_FALSE, JNI_VERSION_1_8, jboolean, jint, jobject, jstring};
+use once_cell::sync::OnceCell;
+use std::any::Any;
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+const PORTS_CLASS: &str = "org/clientsideagent/bootstrap/AgentPorts";
+
+// 加载本库时缓存的 AgentPorts 类; 只有加载 mod 的类加载器能找到这个类
+static PORTS_CLASS_REF: OnceCell<GlobalRef> = OnceCell::new();
+
+#[unsafe(no_mangle)]
+pub extern "system" fn JNI_OnLoad(vm: JavaVM, _reserved: *mut c_void) -> jint {
+    let loaded = panic::catch_unwind(AssertUnwindSafe(|| {
+        let class = vm.get_env().and_then(|mut env| {
+            let class = env.find_class(PORTS_CLASS)?;
+            env.new_global_ref(class)
+        })?;
+        let _ = PORTS_CLASS_REF.set(class);
+        callbacks::init(vm);
+        jni::errors::Result::Ok(())
+    }));
+    match loaded {
+        Ok(Ok(())) => JNI_VERSION_1_8,
+        Ok(Err(e)) => {
+            eprintln!("Cannot load {}: {}", PORTS_CLASS, e);
+            JNI_ERR
+        }
+        Err(panic) => {
+            eprintln!("bootstrap-native panicked while loading: {}", panic_message(&*panic));
+            JNI_ERR
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn JNI_OnUnload(_vm: JavaVM, _reserved: *mut c_void) {
+    let unloaded = panic::catch_unwind(|| {
+        callbacks::set_listener(None);
+        shutdown();
+    });
+    if let Err(panic) = unloaded {
+        eprintln!("bootstrap-native panicked while unloading: {}", panic_message(&*panic));
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_clientsideagent_bootstrap_BootstrapNative_start<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    agent_path: JString<'local>,
+) -> jobject {
+    guard(&mut env, JObject::null().into_raw(), |env| start(env, agent_path))
+}
+
+fn start(env: &mut JNIEnv, agent_path: JString) -> jobject {
+    let agent_path = if agent_path.is_null() {
+        None
+    } else {
+        match env.get_string(&agent_path) {
+            Ok(path) => Some(PathBuf::from(String::from(path))),
+            Err(e) => {
+                throw(env, "java/lang/IllegalArgumentException", &e.to_string());
+                return JObject::null().into_raw();
+            }
+        }
+    };
+    let ports = match pipe(agent_path) {
+        Ok(ports) => ports,
+        Err(e) => {
+            throw(env, exception_class(&e), &e.to_string());
+            return JObject::null().into_raw();
+        }
+    };
+    match new_ports(env, ports) {
+        Ok(object) => object.into_raw(),
+        Err(e) => {
+            throw(env, "java/lang/IllegalStateException", &e.to_string());
+            JObject::null().into_raw()
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_clientsideagent_bootstrap_BootstrapNative_isAgentAlive<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jboolean {
+    guard(&mut env, JNI_FALSE, |_| agent_error().is_none() as jboolean)
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_clientsideagent_bootstrap_BootstrapNative_agentError<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jstring {
+    guard(&mut env, JObject::null().into_raw(), |env| {
+        let Some(error) = agent_error() else {
+            return JObject::null().into_raw();
+        };
+        match env.new_string(error) {
+            Ok(error) => error.into_raw(),
+            // new_string 失败时已经有挂起的 OutOfMemoryError
+            Err(_) => JObject::null().into_raw(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_clientsideagent_bootstrap_BootstrapNative_setListener<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    listener: JObject<'local>,
+) {
+    guard(&mut env, (), |env| {
+        if listener.is_null() {
+            callbacks::set_listener(None);
+            return;
+        }
+        match env.new_global_ref(listener) {
+            Ok(listener) => callbacks::set_listener(Some(listener)),
+            Err(e) => throw(env, "java/lang/IllegalStateException", &e.to_string()),
+        }
+    })
+}
+
+fn new_ports<'local>(env: &mut JNIEnv<'local>, ports: PortAssignment) -> jni::errors::Result<JObject<'local>> {
+    let args = [
+        JValue::Int(ports.network_port as jint),
+        JValue::Int(ports.forward_port as jint),
+    ];
+    match PORTS_CLASS_REF.get() {
+        Some(class) => env.new_object(<&JClass>::from(class.as_obj()), "(II)V", &args),
+        // 在 Java 线程上调用时也可以直接查找
+        None => env.new_object(PORTS_CLASS, "(II)V", &args),
+    }
+}
+
+fn exception_class(error: &StartError) -> &'static str {
+    match error {
+        StartError::InvalidConfig(..) => "java/lang/IllegalArgumentException",
+        StartError::AgentNotFound(_) => "java/io/FileNotFoundException",
+        StartError::Timeout(_) => "java/io/InterruptedIOException",
+        StartError::Runtime(_) | StartError::Stopped(_) => "java/io/IOException",
+    }
+}
+
+// panic 不能穿过 FFI 边界展开, 转换成 Java 异常后返回 fallback
+fn guard<'local, T>(env: &mut JNIEnv<'local>, fallback: T, f: impl FnOnce(&mut JNIEnv<'local>) -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(env))) {
+        Ok(value) => value,
+        Err(panic) => {
+            let message = format!("bootstrap-native panicked: {}", panic_message(&*panic));
+            throw(env, "java/lang/IllegalStateException", &message);
+            fallback
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+// 已经有挂起的异常时保留原来的异常
+fn throw(env: &mut JNIEnv, class: &str, message: &str) {
+    if env.exception_check().unwrap_or(false) {
+        return;
+    }
+    let _ = env.throw_new(class, message);
+}
@@ -0,0 +1,120 @@
+// 把 supervisor 发出的事件回调给 mod 注册的监听器, mod 不需要轮询 agent 的状态:
+//
+//   package org.clientsideagent.bootstrap;
+//
+//   public interface AgentListener {
+//       void onAgentConnected(int networkPort, int forwardPort); // agent 重启后会再次调用, 端口可能变化
+//       void onAgentDisconnected(String reason);
+//       void onTunnelRtt(long rttMillis);
+//       void onAuthRejected(String status, String reason);
+//       void onServerMessage(String message);
+//   }
+//
+// 回调在名为 agent-events 的线程上依次调用, 需要更新界面时由 mod 切换到游戏线程;
+// 没有注册监听器时事件被丢弃, 所以应该在 BootstrapNative.start 之前注册
+use crate::supervisor::Event;
+use jni::objects::{GlobalRef, JObject, JValue};
+use jni::{JNIEnv, JavaVM};
+use once_cell::sync::OnceCell;
+use std::io;
+use std::sync::{Mutex, PoisonError, mpsc};
+use std::thread;
+
+// 加载本库时保存, 事件线程通过它附加到 JVM
+static JVM: OnceCell<JavaVM> = OnceCell::new();
+
+static LISTENER: Mutex<Option<GlobalRef>> = Mutex::new(None);
+
+pub fn init(vm: JavaVM) {
+    let _ = JVM.set(vm);
+}
+
+// 传入 null 时取消注册
+pub fn set_listener(listener: Option<GlobalRef>) {
+    *LISTENER.lock().unwrap_or_else(PoisonError::into_inner) = listener;
+}
+
+// 启动事件线程, 返回的发送端交给 supervisor; 发送端全部被丢弃后线程退出
+pub fn spawn_dispatcher() -> io::Result<mpsc::Sender<Event>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("agent-events".to_string())
+        .spawn(move || dispatch(receiver))?;
+    Ok(sender)
+}
+
+fn dispatch(receiver: mpsc::Receiver<Event>) {
+    let Some(vm) = JVM.get() else {
+        eprintln!("Cannot deliver agent events: the library was not loaded by a JVM");
+        return;
+    };
+    // 以守护线程附加, 不会阻止 JVM 退出
+    let mut env = match vm.attach_current_thread_as_daemon() {
+        Ok(env) => env,
+        Err(e) => {
+            eprintln!("Cannot attach the agent event thread to the JVM: {}", e);
+            return;
+        }
+    };
+    for event in receiver {
+        // 回调期间可能被取消注册, 持有一份引用
+        let Some(listener) = LISTENER.lock().unwrap_or_else(PoisonError::into_inner).clone() else {
+            continue;
+        };
+        // 每个事件在单独的局部帧中处理, 创建的局部引用随帧释放
+        let result = env.with_local_frame(8, |env| deliver(env, listener.as_obj(), &event));
+        if let Err(e) = result {
+            eprintln!("Agent listener failed to handle {:?}: {}", event, e);
+        }
+        // 监听器抛出的异常打印后清除, 不影响之后的事件
+        if env.exception_check().unwrap_or(false) {
+            let _ = env.exception_describe();
+            let _ = env.exception_clear();
+        }
+    }
+}
+
+fn deliver(env: &mut JNIEnv, listener: &JObject, event: &Event) -> jni::errors::Result<()> {
+    match event {
+        Event::Connected(ports) => {
+            let args = [
+                JValue::Int(ports.network_port as i32),
+                JValue::Int(ports.forward_port as i32),
+            ];
+            env.call_method(listener, "onAgentConnected", "(II)V", &args)?;
+        }
+        Event::Disconnected(reason) => {
+            let reason = env.new_string(reason)?;
+            env.call_method(
+                listener,
+                "onAgentDisconnected",
+                "(Ljava/lang/String;)V",
+                &[JValue::Object(&reason)],
+            )?;
+        }
+        Event::TunnelRtt(rtt) => {
+            let rtt = JValue::Long(rtt.as_millis() as i64);
+            env.call_method(listener, "onTunnelRtt", "(J)V", &[rtt])?;
+        }
+        Event::AuthRejected { status, reason } => {
+            let status = env.new_string(status)?;
+            let reason = env.new_string(reason)?;
+            env.call_method(
+                listener,
+                "onAuthRejected",
+                "(Ljava/lang/String;Ljava/lang/String;)V",
+                &[JValue::Object(&status), JValue::Object(&reason)],
+            )?;
+        }
+        Event::ServerMessage(message) => {
+            let message = env.new_string(message)?;
+            env.call_method(
+                listener,
+                "onServerMessage",
+                "(Ljava/lang/String;)V",
+                &[JValue::Object(&message)],
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,36 @@
+// 启动 agent 失败的原因, bindings 模块按种类抛出不同的 Java 异常
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum StartError {
+    InvalidConfig(&'static str, String), // 环境变量名和无效的值
+    AgentNotFound(PathBuf),
+    Runtime(io::Error),  // 无法创建运行时或事件线程
+    Timeout(Duration),   // agent 没有在限定时间内开始运行
+    Stopped(String),     // agent 已经退出, 或连续崩溃次数过多不再重启
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartError::InvalidConfig(name, value) => write!(f, "invalid value '{}' for {}", value, name),
+            StartError::AgentNotFound(path) => write!(f, "agent binary not found at {}", path.display()),
+            StartError::Runtime(e) => write!(f, "cannot start the agent runtime: {}", e),
+            StartError::Timeout(timeout) => {
+                write!(f, "agent did not start within {} ms", timeout.as_millis())
+            }
+            StartError::Stopped(reason) => write!(f, "agent is not running: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StartError {}
+
+impl From<io::Error> for StartError {
+    fn from(e: io::Error) -> Self {
+        StartError::Runtime(e)
+    }
+}
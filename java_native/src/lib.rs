@@ -1,117 +1,75 @@
-use once_cell::sync::OnceCell;
-use windows::{
-    Win32::Foundation::*, Win32::Storage::FileSystem::*, Win32::System::Pipes::*,
-};
-use anyhow::{Result, Error};
-use tokio::net::windows::named_pipe::ClientOptions;
-use windows::core::w;
+// 游戏中的 bootstrap-native: 启动 agent 并保持心跳 (见 supervisor 模块), 通过 JNI 提供给 mod (见 bindings 模块),
+// agent 的状态变化回调给 mod 注册的监听器 (见 callbacks 模块)
+mod bindings;
+mod callbacks;
+mod error;
+mod supervisor;
 
-static NETWORK_PORT: OnceCell<u16> = OnceCell::new();
-static FORWARD_PORT: OnceCell<u16> = OnceCell::new();
-const PIPE_NAME: &str = r"\\.\pipe\novoline893";
-fn aaaaaa() {
-    match ClientOptions::new().open(PIPE_NAME) {
-        Ok(_) => {}
-        Err(_) => {}
-    };
-}
-fn pipe() -> Result<Vec<u16>> {
-    let pipe_name = w!("\\\\.\\pipe\\novoline893");
-    unsafe {
-        println!(
-            "Connecting named pipe: {}",
-            pipe_name.to_string().unwrap_or_default()
-        );
-        loop {
-            // 尝试连接到命名管道
-            // 注意：这里使用 CreateFileW 而不是 CreateNamedPipeW
-            let pipe_handle = CreateFileW(
-                pipe_name,                          // 管道名称
-                GENERIC_READ.0 | GENERIC_WRITE.0,   // 请求读写访问权限
-                FILE_SHARE_MODE(0),                 // 不共享
-                None,                               // 默认安全属性
-                OPEN_EXISTING,                      // 打开已存在的管道
-                FILE_FLAGS_AND_ATTRIBUTES(0),       // 默认文件属性
-                Some(HANDLE(std::ptr::null_mut())), // 无模板文件
-            );
+use once_cell::sync::OnceCell;
+use error::StartError;
+use ipc::PortAssignment;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use supervisor::{Policy, Status, Supervisor};
+use tokio::io;
+use tokio::runtime::Runtime;
 
-            if pipe_handle == Ok(INVALID_HANDLE_VALUE) {
-                eprintln!(
-                    "Failed to connect to named pipe. Error: {:?}",
-                    GetLastError()
-                );
-                // 如果服务器尚未启动，客户端可能会收到 ERROR_PIPE_BUSY
-                // 可以添加重试逻辑
-                return Err(Error::from_win32());
-            }
-            if pipe_handle.is_err() {
-                continue;
-            }
-            println!("Connected to pipe.");
+// 管道读写、心跳和 agent 的监督运行在这个运行时上, pipe() 返回后继续在后台运行
+static RUNTIME: OnceCell<Runtime> = OnceCell::new();
 
-            // 设置管道为消息读取模式 (可选，但有助于确保行为一致)
-            let mut mode = PIPE_READMODE_MESSAGE;
-            let set_mode_result = SetNamedPipeHandleState(
-                *pipe_handle.as_ref().unwrap(), // pipe_handle is Ok here
-                Some(&mut mode as *mut _),
-                None, // 不设置最大收集计数
-                None, // 不设置收集数据超时
-            );
+// agent 不再重启后, 下一次 pipe() 会替换成新的监督者
+static SUPERVISOR: Mutex<Option<Arc<Supervisor>>> = Mutex::new(None);
 
-            if !set_mode_result.is_ok() {
-                eprintln!("Failed to set pipe read mode. Error: {:?}", GetLastError());
-                return Err(Error::from_win32());
-            }
+fn runtime() -> io::Result<&'static Runtime> {
+    RUNTIME.get_or_try_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("agent-ipc")
+            .enable_all()
+            .build()
+    })
+}
 
-            // 从管道读取信息
-            let mut buffer: [u8; 512] = [0; 512];
-            let mut bytes_read = 0;
+// 回调中的 panic 不应该让之后的 JNI 调用也跟着失败
+fn supervisor() -> Option<Arc<Supervisor>> {
+    SUPERVISOR.lock().unwrap_or_else(PoisonError::into_inner).clone()
+}
 
-            println!("Reading from pipe...");
-            let read_result = ReadFile(
-                *pipe_handle.as_ref().unwrap(),
-                Some(&mut buffer),
-                Some(&mut bytes_read),
-                None,
-            );
-            if read_result.is_ok() {
-                if bytes_read > 0 {
-                    let message = String::from_utf8_lossy(&buffer[..bytes_read as usize]);
-                    println!("Message received from server: {}", message);
-                    let parts: Vec<&str> = message.split(',').collect();
-                    if parts.len() == 2 {
-                        let network_port: u16 = parts[0].parse().unwrap();
-                        let forward_port: u16 = parts[1].parse().unwrap();
-                        let _ = NETWORK_PORT.set(network_port);
-                        let _ = FORWARD_PORT.set(forward_port);
-                        rev_server("goodguys".as_bytes(), &pipe_handle.as_ref().unwrap());
-                        return Ok(vec![network_port, forward_port]);
-                    }
-                } else {
-                    println!("No message received or pipe closed.");
-                }
+// 第一次调用时启动 agent, 之后返回当前运行的 agent 的端口; agent 重启后端口会变化
+// 最多等待 Policy::wait_timeout, agent 不再重启时重新启动
+fn pipe(agent_path: Option<PathBuf>) -> Result<PortAssignment, StartError> {
+    let runtime = runtime()?;
+    let supervisor = {
+        let mut current = SUPERVISOR.lock().unwrap_or_else(PoisonError::into_inner);
+        match current.as_ref() {
+            Some(supervisor) if !matches!(supervisor.status(), Status::Stopped(_)) => supervisor.clone(),
+            _ => {
+                let policy = Policy::from_env()?;
+                let supervisor = Arc::new(Supervisor::start(
+                    runtime,
+                    agent_path,
+                    policy,
+                    callbacks::spawn_dispatcher()?,
+                )?);
+                *current = Some(supervisor.clone());
+                supervisor
             }
-            CloseHandle(*pipe_handle.as_ref().unwrap())?;
-            println!("Pipe handle closed.");
         }
-    }
+    };
+    runtime.block_on(supervisor.wait_running())
 }
 
-fn rev_server(message2: &[u8], pipe_handle: &HANDLE) {
-    unsafe {
-        let mut bytes_written = 0;
-        let _ = WriteFile(
-            *pipe_handle, // 管道句柄
-            Some(message2),
-            Some(&mut bytes_written),
-            None,
-        );
+// agent 没有在运行的原因, agent 正常运行时为空
+pub fn agent_error() -> Option<String> {
+    match supervisor() {
+        Some(supervisor) => supervisor.status().error(),
+        None => Some("agent is not started".to_string()),
     }
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn fake_entrypoint() {
-    let useful: Vec<u16> = pipe().unwrap();
-    println!("{}", useful[0]);
-    println!("{}", useful[1]);
+// 库被卸载时结束 agent
+fn shutdown() {
+    if let (Some(supervisor), Some(runtime)) = (supervisor(), RUNTIME.get()) {
+        supervisor.stop(runtime);
+    }
 }
@@ -0,0 +1,404 @@
+// 启动并监督 agent 子进程
+//
+// 每次启动生成新的 IPC 端点, 通过环境变量连同游戏的进程ID一起传给 agent (见 ipc::endpoint);
+// agent 的输出逐行转发到游戏日志, agent 崩溃或失去响应时按退避时间重启, 重启后端口会变化,
+// 连续崩溃超过 Policy 允许的次数后不再重启;
+// 连接状态和 agent 转发的隧道状态作为 Event 发送给 mod (见 callbacks 模块)
+use crate::error::StartError;
+use ipc::endpoint::{self, Credentials};
+use ipc::{Closed, Message, PortAssignment, Receiver, Sender};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError, mpsc};
+use std::time::Duration;
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+// mod 没有指定路径时, 依次查找这个环境变量和游戏目录下的 agent
+pub const AGENT_PATH_ENV: &str = "AGENT_PATH";
+#[cfg(windows)]
+const AGENT_FILE: &str = "agent.exe";
+#[cfg(not(windows))]
+const AGENT_FILE: &str = "agent";
+
+// 调整 Policy 的环境变量
+pub const START_TIMEOUT_ENV: &str = "AGENT_START_TIMEOUT_MS";
+pub const WAIT_TIMEOUT_ENV: &str = "AGENT_WAIT_TIMEOUT_MS";
+pub const MAX_RESTARTS_ENV: &str = "AGENT_MAX_RESTARTS";
+
+const CONNECT_RETRY: Duration = Duration::from_millis(50);
+const EXIT_GRACE: Duration = Duration::from_secs(5); // 心跳结束或要求退出后等待 agent 自行退出的时间
+
+// 重启的退避时间从 INITIAL_BACKOFF 开始翻倍, agent 稳定运行 STABLE_RUN 之后重新计算
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+// 超时和重启策略
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub start_timeout: Duration, // agent 启动后必须在这个时间内完成握手
+    pub wait_timeout: Duration,  // 等待 agent 开始运行的最长时间, 包括重启前的退避
+    pub max_restarts: u32,       // 连续崩溃后最多重启的次数, 0 表示不限制
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            start_timeout: Duration::from_secs(30),
+            wait_timeout: Duration::from_secs(60),
+            max_restarts: 5,
+        }
+    }
+}
+
+impl Policy {
+    // 没有设置的环境变量使用默认值
+    pub fn from_env() -> Result<Self, StartError> {
+        let mut policy = Policy::default();
+        if let Some(ms) = env_value::<u64>(START_TIMEOUT_ENV)? {
+            policy.start_timeout = non_zero_millis(START_TIMEOUT_ENV, ms)?;
+        }
+        if let Some(ms) = env_value::<u64>(WAIT_TIMEOUT_ENV)? {
+            policy.wait_timeout = non_zero_millis(WAIT_TIMEOUT_ENV, ms)?;
+        }
+        if let Some(max_restarts) = env_value(MAX_RESTARTS_ENV)? {
+            policy.max_restarts = max_restarts;
+        }
+        Ok(policy)
+    }
+}
+
+fn env_value<T: FromStr>(name: &'static str) -> Result<Option<T>, StartError> {
+    match env::var(name) {
+        Ok(value) => match value.trim().parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(StartError::InvalidConfig(name, value)),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+fn non_zero_millis(name: &'static str, ms: u64) -> Result<Duration, StartError> {
+    if ms == 0 {
+        return Err(StartError::InvalidConfig(name, ms.to_string()));
+    }
+    Ok(Duration::from_millis(ms))
+}
+
+#[derive(Debug, Clone)]
+pub enum Status {
+    Starting,
+    Running(PortAssignment),
+    Restarting(String), // agent 退出或失去响应的原因, 等待重启
+    Stopped(String),    // agent 正常退出或被要求退出, 不再重启
+}
+
+impl Status {
+    // agent 没有在运行时返回原因
+    pub fn error(&self) -> Option<String> {
+        match self {
+            Status::Starting => Some("agent is starting".to_string()),
+            Status::Running(_) => None,
+            Status::Restarting(reason) | Status::Stopped(reason) => Some(reason.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Connected(PortAssignment), // agent 完成握手, 每次重启后都会再次发送
+    Disconnected(String),      // agent 退出或失去响应的原因
+    TunnelRtt(Duration),
+    AuthRejected { status: String, reason: String },
+    ServerMessage(String),
+}
+
+pub struct Supervisor {
+    policy: Policy,
+    status: watch::Receiver<Status>,
+    stop: watch::Sender<bool>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Supervisor {
+    pub fn start(
+        runtime: &Runtime,
+        agent_path: Option<PathBuf>,
+        policy: Policy,
+        events: mpsc::Sender<Event>,
+    ) -> Result<Self, StartError> {
+        let path = locate(agent_path)?;
+        println!("Starting agent: {} ({:?})", path.display(), policy);
+        let (status_tx, status) = watch::channel(Status::Starting);
+        let (stop, stop_rx) = watch::channel(false);
+        let task = runtime.spawn(supervise(path, policy, status_tx, stop_rx, events));
+        Ok(Supervisor {
+            policy,
+            status,
+            stop,
+            task: Mutex::new(Some(task)),
+        })
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.borrow().clone()
+    }
+
+    // 等待 agent 完成握手, agent 正在重启时等待重启完成; 不再重启或超过 wait_timeout 时返回原因
+    pub async fn wait_running(&self) -> Result<PortAssignment, StartError> {
+        let mut status = self.status.clone();
+        let running = status.wait_for(|status| matches!(status, Status::Running(_) | Status::Stopped(_)));
+        let status = match tokio::time::timeout(self.policy.wait_timeout, running).await {
+            Ok(Ok(status)) => status,
+            // 监督任务意外结束
+            Ok(Err(_)) => return Err(StartError::Stopped("agent supervisor exited".to_string())),
+            Err(_) => return Err(StartError::Timeout(self.policy.wait_timeout)),
+        };
+        match &*status {
+            Status::Running(ports) => Ok(*ports),
+            other => Err(StartError::Stopped(other.error().unwrap_or_default())),
+        }
+    }
+
+    // 要求 agent 退出并等待进程结束, 不能在运行时的线程上调用
+    pub fn stop(&self, runtime: &Runtime) {
+        let _ = self.stop.send(true);
+        let task = self.task.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(task) = task {
+            let _ = runtime.block_on(async { tokio::time::timeout(EXIT_GRACE * 2, task).await });
+        }
+    }
+}
+
+fn locate(agent_path: Option<PathBuf>) -> Result<PathBuf, StartError> {
+    let path = match agent_path.or_else(|| env::var_os(AGENT_PATH_ENV).map(PathBuf::from)) {
+        Some(path) => path,
+        None => env::current_dir()?.join(AGENT_FILE),
+    };
+    if !path.is_file() {
+        return Err(StartError::AgentNotFound(path));
+    }
+    Ok(path)
+}
+
+enum Exit {
+    Crashed(String),
+    Stopped(String),
+}
+
+async fn supervise(
+    path: PathBuf,
+    policy: Policy,
+    status: watch::Sender<Status>,
+    mut stop: watch::Receiver<bool>,
+    events: mpsc::Sender<Event>,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        let reason = match run_agent(&path, &policy, &status, &mut stop, &events).await {
+            Exit::Crashed(reason) => reason,
+            Exit::Stopped(reason) => {
+                println!("Agent stopped: {}", reason);
+                status.send_replace(Status::Stopped(reason));
+                return;
+            }
+        };
+
+        if started.elapsed() >= STABLE_RUN {
+            backoff = INITIAL_BACKOFF;
+            restarts = 0;
+        }
+        if policy.max_restarts != 0 && restarts >= policy.max_restarts {
+            let reason = format!("{}; gave up after {} restarts", reason, restarts);
+            eprintln!("Agent crashed ({})", reason);
+            status.send_replace(Status::Stopped(reason));
+            return;
+        }
+        restarts += 1;
+        eprintln!("Agent crashed ({}), restarting in {} s", reason, backoff.as_secs());
+        status.send_replace(Status::Restarting(reason));
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = stop.wait_for(|stop| *stop) => {
+                status.send_replace(Status::Stopped("agent was stopped".to_string()));
+                return;
+            }
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+// 启动一次 agent 并运行到它退出
+async fn run_agent(
+    path: &Path,
+    policy: &Policy,
+    status: &watch::Sender<Status>,
+    stop: &mut watch::Receiver<bool>,
+    events: &mpsc::Sender<Event>,
+) -> Exit {
+    let credentials = match Credentials::generate() {
+        Ok(credentials) => credentials,
+        Err(e) => return Exit::Crashed(format!("cannot generate the IPC endpoint: {}", e)),
+    };
+    let mut child = match spawn(path, &credentials) {
+        Ok(child) => child,
+        Err(e) => return Exit::Crashed(format!("cannot start {}: {}", path.display(), e)),
+    };
+    status.send_replace(Status::Starting);
+
+    enum Started {
+        Connected(io::Result<(PortAssignment, Receiver, Sender)>),
+        TimedOut,
+        Exited(io::Result<ExitStatus>),
+        Stop,
+    }
+    let started = tokio::select! {
+        result = tokio::time::timeout(policy.start_timeout, handshake(&credentials)) => match result {
+            Ok(result) => Started::Connected(result),
+            Err(_) => Started::TimedOut,
+        },
+        exit = child.wait() => Started::Exited(exit),
+        _ = stop.wait_for(|stop| *stop) => Started::Stop,
+    };
+    let (ports, receiver, sender) = match started {
+        Started::Connected(Ok(connection)) => connection,
+        Started::Connected(Err(e)) => {
+            let _ = child.kill().await;
+            return Exit::Crashed(format!("handshake with the agent failed: {}", e));
+        }
+        Started::TimedOut => {
+            let _ = child.kill().await;
+            return Exit::Crashed("agent did not connect in time".to_string());
+        }
+        Started::Exited(exit) => return classify(exit, "agent exited before connecting".to_string()),
+        Started::Stop => {
+            let _ = child.kill().await;
+            return Exit::Stopped("agent was stopped".to_string());
+        }
+    };
+    println!("Agent is running, ports: {:?}", ports);
+    status.send_replace(Status::Running(ports));
+    let _ = events.send(Event::Connected(ports));
+
+    enum Ended {
+        Closed(Closed),
+        Exited(io::Result<ExitStatus>),
+        Stop,
+    }
+    let on_message = |message| forward_message(events, message);
+    let ended = tokio::select! {
+        closed = ipc::heartbeat(receiver, sender.clone(), on_message) => Ended::Closed(closed),
+        exit = child.wait() => Ended::Exited(exit),
+        _ = stop.wait_for(|stop| *stop) => Ended::Stop,
+    };
+    let exit = match ended {
+        Ended::Closed(closed) => classify(wait_or_kill(&mut child).await, closed.to_string()),
+        Ended::Exited(exit) => classify(exit, "agent exited".to_string()),
+        Ended::Stop => {
+            let shutdown = Message::Shutdown {
+                reason: "game is unloading the agent".to_string(),
+            };
+            let _ = tokio::time::timeout(EXIT_GRACE, sender.send(&shutdown)).await;
+            let _ = wait_or_kill(&mut child).await;
+            Exit::Stopped("agent was stopped".to_string())
+        }
+    };
+    let (Exit::Crashed(reason) | Exit::Stopped(reason)) = &exit;
+    let _ = events.send(Event::Disconnected(reason.clone()));
+    exit
+}
+
+// agent 转发的隧道状态
+fn forward_message(events: &mpsc::Sender<Event>, message: Message) {
+    let event = match message {
+        Message::TunnelRtt { rtt_ms } => Event::TunnelRtt(Duration::from_millis(rtt_ms)),
+        Message::AuthRejected { status, reason } => Event::AuthRejected { status, reason },
+        Message::ServerNotice { message } => Event::ServerMessage(message),
+        other => {
+            eprintln!("Ignoring unexpected message from the agent: {:?}", other);
+            return;
+        }
+    };
+    let _ = events.send(event);
+}
+
+fn spawn(path: &Path, credentials: &Credentials) -> io::Result<Child> {
+    let mut command = std::process::Command::new(path);
+    credentials.apply(&mut command);
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        command.current_dir(dir);
+    }
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        // 不为 agent 弹出控制台窗口
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    let mut child = Command::from(command).kill_on_drop(true).spawn()?;
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(forward_output(stdout, false));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(forward_output(stderr, true));
+    }
+    Ok(child)
+}
+
+// 把 agent 的输出逐行转发到游戏日志
+async fn forward_output<R: AsyncRead + Unpin>(output: R, is_stderr: bool) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if is_stderr {
+            eprintln!("[agent] {}", line);
+        } else {
+            println!("[agent] {}", line);
+        }
+    }
+}
+
+// agent 启动后才会创建端点, 在此之前连接失败时重试
+async fn handshake(credentials: &Credentials) -> io::Result<(PortAssignment, Receiver, Sender)> {
+    let (mut receiver, sender) = loop {
+        match endpoint::connect(credentials).await {
+            Ok(connection) => break connection,
+            Err(_) => tokio::time::sleep(CONNECT_RETRY).await,
+        }
+    };
+    let ports = ipc::receive_ports(&mut receiver, &sender).await?;
+    Ok((ports, receiver, sender))
+}
+
+// 给 agent 一点时间自行退出, 超时则强制结束
+async fn wait_or_kill(child: &mut Child) -> io::Result<ExitStatus> {
+    match tokio::time::timeout(EXIT_GRACE, child.wait()).await {
+        Ok(exit) => exit,
+        Err(_) => {
+            child.kill().await?;
+            child.wait().await
+        }
+    }
+}
+
+// 正常退出的 agent 不再重启
+fn classify(exit: io::Result<ExitStatus>, reason: String) -> Exit {
+    match exit {
+        Ok(status) if status.success() => Exit::Stopped(reason),
+        Ok(status) => Exit::Crashed(format!("{} ({})", reason, status)),
+        Err(e) => Exit::Crashed(format!("{} ({})", reason, e)),
+    }
+}
@@ -0,0 +1,502 @@
+// 后端服务器池: 主动健康检查, 按策略选择后端, 连接失败时换一个后端重试
+use crate::proxy_protocol::ProxyProtocol;
+use log::{info, warn};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tunnel::minecraft::{self, Handshake, NEXT_STATE_STATUS};
+
+// 状态查询响应的最大长度 (包含服务器图标时可能有几十 KB)
+const MAX_STATUS_RESPONSE_LEN: usize = 256 * 1024;
+
+// 选择后端的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    LeastConnections, // 按权重折算后当前连接数最少的后端
+    Weighted,         // 按权重轮流分配
+}
+
+impl Balance {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "least_connections" => Some(Balance::LeastConnections),
+            "weighted" => Some(Balance::Weighted),
+            _ => None,
+        }
+    }
+}
+
+// 主动健康检查的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthCheckMode {
+    Tcp,    // 只检查能否建立 TCP 连接
+    Status, // 发送 Minecraft 状态查询, 要求后端给出响应
+}
+
+impl HealthCheckMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "tcp" => Some(HealthCheckMode::Tcp),
+            "status" => Some(HealthCheckMode::Status),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HealthCheckConfig {
+    pub mode: HealthCheckMode,
+    pub interval: Duration, // 两次检查之间的间隔
+    pub timeout: Duration,  // 单次检查的最长时间
+    pub rise: u32,          // 连续成功多少次后重新启用
+    pub fall: u32,          // 连续失败多少次后停用
+}
+
+// 池中的一个后端
+#[derive(Debug)]
+pub struct Backend {
+    pub addr: SocketAddr,
+    pub weight: u32,
+    healthy: AtomicBool,
+    active: AtomicUsize, // 当前转发中的连接数
+}
+
+impl Backend {
+    pub fn new(addr: SocketAddr, weight: u32) -> Self {
+        Backend {
+            addr,
+            weight,
+            healthy: AtomicBool::new(true),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Acquire)
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    // 更新健康状态, 状态变化时记录日志
+    fn set_healthy(&self, healthy: bool, reason: &dyn fmt::Display) {
+        if self.healthy.swap(healthy, Ordering::AcqRel) != healthy {
+            if healthy {
+                info!("Backend {} is healthy again", self.addr);
+            } else {
+                warn!("Backend {} marked unhealthy: {}", self.addr, reason);
+            }
+        }
+    }
+}
+
+// 占用后端的一个连接计数, drop 时归还
+pub struct BackendGuard {
+    backend: Arc<Backend>,
+}
+
+impl BackendGuard {
+    fn acquire(backend: &Arc<Backend>) -> Self {
+        backend.active.fetch_add(1, Ordering::AcqRel);
+        BackendGuard {
+            backend: backend.clone(),
+        }
+    }
+
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[derive(Debug)]
+pub struct BackendPool {
+    backends: Vec<Arc<Backend>>,
+    balance: Balance,
+    health_check: Option<HealthCheckConfig>,
+    next: AtomicU64, // 轮流分配的计数器
+}
+
+impl BackendPool {
+    pub fn new(backends: Vec<Backend>, balance: Balance, health_check: Option<HealthCheckConfig>) -> Self {
+        BackendPool {
+            backends: backends.into_iter().map(Arc::new).collect(),
+            balance,
+            health_check,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    pub fn balance(&self) -> Balance {
+        self.balance
+    }
+
+    // 本次连接尝试后端的顺序: 按策略排列的健康后端在前, 不健康的后端作为最后的尝试
+    fn candidates(&self) -> Vec<Arc<Backend>> {
+        let (mut healthy, unhealthy): (Vec<_>, Vec<_>) =
+            self.backends.iter().cloned().partition(|backend| backend.is_healthy());
+
+        match self.balance {
+            Balance::LeastConnections => {
+                // 比较 active / weight, 交叉相乘避免浮点数
+                healthy.sort_by(|a, b| {
+                    let a_load = a.active_connections() as u64 * u64::from(b.weight);
+                    let b_load = b.active_connections() as u64 * u64::from(a.weight);
+                    a_load.cmp(&b_load)
+                });
+            }
+            Balance::Weighted => {
+                let total: u64 = healthy.iter().map(|backend| u64::from(backend.weight)).sum();
+                if total > 0 {
+                    let mut ticket = self.next.fetch_add(1, Ordering::Relaxed) % total;
+                    let first = healthy
+                        .iter()
+                        .position(|backend| {
+                            let weight = u64::from(backend.weight);
+                            if ticket < weight {
+                                return true;
+                            }
+                            ticket -= weight;
+                            false
+                        })
+                        .unwrap_or(0);
+                    healthy.rotate_left(first);
+                }
+            }
+        }
+
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    // 按顺序连接候选后端, 直到有一个成功; 返回最后一个错误
+    pub async fn connect(&self, connect_timeout: Duration) -> io::Result<(TcpStream, BackendGuard)> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no backend configured");
+        for backend in self.candidates() {
+            match connect(backend.addr, connect_timeout).await {
+                Ok(stream) => return Ok((stream, BackendGuard::acquire(&backend))),
+                Err(e) => {
+                    warn!("Failed to connect to backend {}: {}", backend.addr, e);
+                    // 没有主动检查时不能自动恢复, 只有开启检查时才根据连接失败停用后端
+                    if self.health_check.is_some() {
+                        backend.set_healthy(false, &e);
+                    }
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    // 为每个后端启动一个健康检查任务; proxy_protocol 为后端要求的 PROXY protocol 头部
+    pub fn spawn_health_checks(&self, proxy_protocol: Option<ProxyProtocol>) {
+        let Some(config) = self.health_check else {
+            return;
+        };
+        for backend in &self.backends {
+            let backend = backend.clone();
+            tokio::spawn(async move {
+                let mut counter = HealthCounter::default();
+                let mut interval = tokio::time::interval(config.interval);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    interval.tick().await;
+                    let result = tokio::time::timeout(
+                        config.timeout,
+                        check(backend.addr, config.mode, proxy_protocol),
+                    )
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "health check timed out"))
+                    });
+                    match result {
+                        Ok(()) => {
+                            if let Some(healthy) = counter.record(true, &config) {
+                                backend.set_healthy(healthy, &"");
+                            }
+                        }
+                        Err(e) => {
+                            if let Some(healthy) = counter.record(false, &config) {
+                                backend.set_healthy(healthy, &e);
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    }
+}
+
+// 连续成功 / 失败的检查次数
+#[derive(Debug, Default)]
+struct HealthCounter {
+    successes: u32,
+    failures: u32,
+}
+
+impl HealthCounter {
+    // 记录一次检查结果; 连续成功 rise 次后返回 Some(true), 连续失败 fall 次后返回 Some(false)
+    fn record(&mut self, ok: bool, config: &HealthCheckConfig) -> Option<bool> {
+        if ok {
+            self.failures = 0;
+            self.successes = self.successes.saturating_add(1);
+            (self.successes >= config.rise).then_some(true)
+        } else {
+            self.successes = 0;
+            self.failures = self.failures.saturating_add(1);
+            (self.failures >= config.fall).then_some(false)
+        }
+    }
+}
+
+async fn connect(addr: SocketAddr, connect_timeout: Duration) -> io::Result<TcpStream> {
+    let stream = tokio::time::timeout(connect_timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+    let _ = stream.set_nodelay(true);
+    Ok(stream)
+}
+
+// 检查一次后端
+async fn check(addr: SocketAddr, mode: HealthCheckMode, proxy_protocol: Option<ProxyProtocol>) -> io::Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    if mode == HealthCheckMode::Tcp {
+        return Ok(());
+    }
+
+    // 要求 PROXY protocol 的后端会拒绝没有头部的连接, 使用 LOCAL 命令表示这是健康检查
+    let mut request = proxy_protocol
+        .map(ProxyProtocol::local_header)
+        .unwrap_or_default();
+    request.extend_from_slice(
+        &Handshake {
+            protocol_version: -1,
+            server_address: addr.ip().to_string(),
+            server_port: addr.port(),
+            next_state: NEXT_STATE_STATUS,
+        }
+        .encode(),
+    );
+    // 状态请求 (packet id 0x00, 无内容)
+    request.extend_from_slice(&minecraft::frame(vec![0x00]));
+    stream.write_all(&request).await?;
+
+    let packet = minecraft::read_packet(&mut stream, MAX_STATUS_RESPONSE_LEN).await?;
+    let packet_id = minecraft::take_varint(&mut &packet[..])?;
+    if packet_id != 0x00 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected status response packet id {:#04x}", packet_id),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn health_check(mode: HealthCheckMode) -> HealthCheckConfig {
+        HealthCheckConfig {
+            mode,
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_secs(1),
+            rise: 2,
+            fall: 3,
+        }
+    }
+
+    fn pool(weights: &[u32], balance: Balance) -> BackendPool {
+        let backends = weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| {
+                Backend::new(SocketAddr::from(([127, 0, 0, 1], 30001 + i as u16)), *weight)
+            })
+            .collect();
+        BackendPool::new(backends, balance, None)
+    }
+
+    // 候选后端的端口, 用来表示顺序
+    fn order(pool: &BackendPool) -> Vec<u16> {
+        pool.candidates().iter().map(|backend| backend.addr.port()).collect()
+    }
+
+    // 一个已经关闭的端口, 连接会被拒绝
+    fn closed_addr() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn least_connections_compares_load_per_weight() {
+        let pool = pool(&[1, 2, 1], Balance::LeastConnections);
+        assert_eq!(order(&pool), [30001, 30002, 30003]); // 负载相同时保持配置顺序
+
+        let backends = pool.backends();
+        let _guards = [
+            BackendGuard::acquire(&backends[0]),
+            BackendGuard::acquire(&backends[1]),
+        ];
+        // 30003: 0/1, 30002: 1/2, 30001: 1/1
+        assert_eq!(order(&pool), [30003, 30002, 30001]);
+    }
+
+    #[test]
+    fn guards_return_their_connection_when_dropped() {
+        let pool = pool(&[1], Balance::LeastConnections);
+        let backend = &pool.backends()[0];
+        let guard = BackendGuard::acquire(backend);
+        assert_eq!(backend.active_connections(), 1);
+        drop(guard);
+        assert_eq!(backend.active_connections(), 0);
+    }
+
+    #[test]
+    fn weighted_takes_turns_in_proportion_to_weight() {
+        let pool = pool(&[3, 1], Balance::Weighted);
+        let firsts: Vec<u16> = (0..8).map(|_| order(&pool)[0]).collect();
+        assert_eq!(firsts, [30001, 30001, 30001, 30002, 30001, 30001, 30001, 30002]);
+        // 其他后端仍然作为后备
+        assert_eq!(order(&pool).len(), 2);
+    }
+
+    #[test]
+    fn unhealthy_backends_are_tried_last() {
+        for balance in [Balance::LeastConnections, Balance::Weighted] {
+            let pool = pool(&[1, 1, 1], balance);
+            pool.backends()[0].set_healthy(false, &"test");
+            let order = order(&pool);
+            assert_eq!(order.len(), 3);
+            assert_eq!(order[2], 30001, "{:?}", balance);
+        }
+    }
+
+    #[test]
+    fn health_changes_after_rise_or_fall_consecutive_results() {
+        let config = health_check(HealthCheckMode::Tcp); // rise 2, fall 3
+        let cases: [(&[bool], Option<bool>); 6] = [
+            (&[true], None),
+            (&[true, true], Some(true)),
+            (&[false, false], None),
+            (&[false, false, false], Some(false)),
+            (&[false, false, true, false, false], None), // 成功打断了连续失败
+            (&[true, false, true], None),
+        ];
+        for (results, expected) in cases {
+            let mut counter = HealthCounter::default();
+            let last = results.iter().map(|ok| counter.record(*ok, &config)).last().unwrap();
+            assert_eq!(last, expected, "{:?}", results);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_fails_over_and_marks_failed_backends_only_with_health_checks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        for health_check in [None, Some(health_check(HealthCheckMode::Tcp))] {
+            let backends = vec![Backend::new(closed_addr(), 1), Backend::new(live, 1)];
+            let pool = BackendPool::new(backends, Balance::LeastConnections, health_check);
+            let (_stream, guard) = pool.connect(CONNECT_TIMEOUT).await.unwrap();
+            assert_eq!(guard.backend().addr, live);
+            assert_eq!(guard.backend().active_connections(), 1);
+            // 没有主动检查时无法恢复, 所以不会因为一次失败停用后端
+            assert_eq!(pool.backends()[0].is_healthy(), health_check.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_returns_the_last_error_when_every_backend_fails() {
+        let pool = BackendPool::new(vec![Backend::new(closed_addr(), 1)], Balance::Weighted, None);
+        let e = pool.connect(CONNECT_TIMEOUT).await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+
+        let empty = BackendPool::new(Vec::new(), Balance::Weighted, None);
+        let e = empty.connect(CONNECT_TIMEOUT).await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn status_check_sends_a_local_header_and_a_status_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let backend = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut header = [0u8; 16];
+            stream.read_exact(&mut header).await.unwrap();
+            let handshake = minecraft::read_handshake(&mut stream).await.unwrap().unwrap();
+            let request = minecraft::read_packet(&mut stream, 16).await.unwrap();
+            stream.write_all(&minecraft::frame(vec![0x00, 0x02, b'{', b'}'])).await.unwrap();
+            (header, handshake, request)
+        });
+
+        check(addr, HealthCheckMode::Status, Some(ProxyProtocol::V2)).await.unwrap();
+        let (header, handshake, request) = backend.await.unwrap();
+        assert_eq!(header.to_vec(), ProxyProtocol::V2.local_header());
+        assert_eq!(handshake.next_state, NEXT_STATE_STATUS);
+        assert_eq!(handshake.server_port, addr.port());
+        assert_eq!(request, [0x00]);
+    }
+
+    #[tokio::test]
+    async fn status_check_rejects_other_responses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            minecraft::read_handshake(&mut stream).await.unwrap();
+            // 0x01 不是状态响应的数据包 id
+            stream.write_all(&minecraft::frame(vec![0x01])).await.unwrap();
+        });
+        let e = check(addr, HealthCheckMode::Status, None).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // 只检查 TCP 时不需要后端回应
+        assert!(check(closed_addr(), HealthCheckMode::Tcp, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn health_checks_disable_and_restore_a_backend() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = BackendPool::new(
+            vec![Backend::new(addr, 1)],
+            Balance::LeastConnections,
+            Some(health_check(HealthCheckMode::Tcp)),
+        );
+        let backend = pool.backends()[0].clone();
+        drop(listener);
+        pool.spawn_health_checks(None);
+
+        let wait_for = |healthy: bool| {
+            let backend = backend.clone();
+            tokio::time::timeout(Duration::from_secs(10), async move {
+                while backend.is_healthy() != healthy {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+        wait_for(false).await.unwrap();
+        let _listener = TcpListener::bind(addr).await.unwrap();
+        wait_for(true).await.unwrap();
+    }
+}
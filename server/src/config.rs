@@ -0,0 +1,1037 @@
+// 服务器配置: TOML 配置文件 + 命令行参数 / 环境变量覆盖
+// 优先级: 命令行参数 > 环境变量 > 配置文件 > 默认值 (命令行与环境变量由 clap 合并)
+use clap::Parser;
+use jsonwebtoken::Algorithm;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use crate::backend::{Backend, BackendPool, Balance, HealthCheckConfig, HealthCheckMode};
+use crate::proxy_protocol::ProxyProtocol;
+use crate::routing::{self, Route, RouteTable};
+use std::time::Duration;
+use tokio_kcp::{KcpConfig, KcpNoDelayConfig};
+use tunnel::mux::MAX_FRAME_PAYLOAD;
+use tunnel::transport::quic::QuicOptions;
+
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:19132";
+const DEFAULT_BACKEND_ADDR: &str = "127.0.0.1:25565";
+const DEFAULT_JWT_ALGORITHMS: &[&str] = &["HS256", "RS256", "ES256", "EdDSA"];
+const DEFAULT_KEY_RELOAD_SECS: u64 = 30;
+const DEFAULT_JWT_LEEWAY_SECS: u64 = 60;
+const DEFAULT_HANDSHAKE_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_MAX_TOKEN_SIZE: usize = 8 * 1024;
+const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 5_000;
+const DEFAULT_HEARTBEAT_TIMEOUT_MS: u64 = 20_000;
+const DEFAULT_QUIC_IDLE_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_QUIC_KEEP_ALIVE_SECS: u64 = 10;
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_HEALTH_CHECK_INTERVAL_SECS: u64 = 10;
+const DEFAULT_HEALTH_CHECK_TIMEOUT_MS: u64 = 2_000;
+const DEFAULT_HEALTH_CHECK_RISE: u32 = 2;
+const DEFAULT_HEALTH_CHECK_FALL: u32 = 2;
+
+// 命令行参数, 每一项都可以通过同名环境变量提供
+#[derive(Debug, Parser)]
+#[command(name = "server", version, about = "ClientsideAgent relay server")]
+pub struct Cli {
+    /// TOML 配置文件路径
+    #[arg(short, long, env = "AGENT_SERVER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// 监听地址 (UDP), 例如 0.0.0.0:19132
+    #[arg(long, env = "AGENT_SERVER_LISTEN")]
+    pub listen: Option<String>,
+
+    /// 隧道传输协议: kcp, quic, tcp 或 tls
+    #[arg(long, env = "AGENT_SERVER_TRANSPORT")]
+    pub transport: Option<String>,
+
+    /// 默认后端 Minecraft 服务器地址 (未匹配任何路由时使用), 例如 127.0.0.1:25565
+    #[arg(long, env = "AGENT_SERVER_BACKEND")]
+    pub backend: Option<String>,
+
+    /// JWT HS256 共享密钥
+    #[arg(long, env = "AGENT_SERVER_JWT_SECRET", hide_env_values = true)]
+    pub jwt_secret: Option<String>,
+
+    /// 从文件读取 JWT HS256 共享密钥 (首尾空白会被去掉)
+    #[arg(long, env = "AGENT_SERVER_JWT_SECRET_FILE")]
+    pub jwt_secret_file: Option<PathBuf>,
+
+    /// 存放 PEM 公钥的目录, 文件名 (不含 .pem) 作为 kid
+    #[arg(long, env = "AGENT_SERVER_JWT_PUBLIC_KEYS_DIR")]
+    pub jwt_public_keys_dir: Option<PathBuf>,
+
+    /// JWKS 公钥集合文件
+    #[arg(long, env = "AGENT_SERVER_JWT_JWKS_FILE")]
+    pub jwt_jwks_file: Option<PathBuf>,
+
+    /// 日志级别: off, error, warn, info, debug, trace
+    #[arg(long, env = "AGENT_SERVER_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// KCP MTU (64..=1500)
+    #[arg(long, env = "AGENT_SERVER_KCP_MTU")]
+    pub kcp_mtu: Option<usize>,
+
+    /// KCP nodelay 模式: true 或 false
+    #[arg(long, env = "AGENT_SERVER_KCP_NODELAY")]
+    pub kcp_nodelay: Option<bool>,
+
+    /// KCP 内部刷新间隔 (毫秒, 10..=5000)
+    #[arg(long, env = "AGENT_SERVER_KCP_INTERVAL")]
+    pub kcp_interval: Option<i32>,
+
+    /// KCP 快速重传的重复 ACK 次数, 0 表示关闭
+    #[arg(long, env = "AGENT_SERVER_KCP_RESEND")]
+    pub kcp_resend: Option<i32>,
+
+    /// 关闭 KCP 拥塞控制: true 或 false
+    #[arg(long, env = "AGENT_SERVER_KCP_NO_CONGESTION_CONTROL")]
+    pub kcp_no_congestion_control: Option<bool>,
+
+    /// KCP 发送窗口 (包数)
+    #[arg(long, env = "AGENT_SERVER_KCP_SEND_WINDOW")]
+    pub kcp_send_window: Option<u16>,
+
+    /// KCP 接收窗口 (包数)
+    #[arg(long, env = "AGENT_SERVER_KCP_RECV_WINDOW")]
+    pub kcp_recv_window: Option<u16>,
+}
+
+// 配置文件的原始结构, 所有字段都是可选的
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<String>,
+    backend: Option<String>,
+    backends: Vec<BackendSection>,
+    balance: Option<String>,
+    connect_timeout_ms: Option<u64>,
+    proxy_protocol: Option<String>,
+    transport: Option<String>,
+    log_level: Option<String>,
+    notice: Option<String>,
+    jwt: JwtSection,
+    kcp: KcpSection,
+    quic: QuicSection,
+    tls: TlsSection,
+    access: AccessSection,
+    handshake: HandshakeSection,
+    heartbeat: HeartbeatSection,
+    health_check: HealthCheckSection,
+    routes: Vec<RouteSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct JwtSection {
+    secret: Option<String>,
+    secret_file: Option<PathBuf>,
+    public_keys_dir: Option<PathBuf>,
+    jwks_file: Option<PathBuf>,
+    algorithms: Option<Vec<String>>,
+    reload_interval_secs: Option<u64>,
+    issuers: Vec<String>,
+    audiences: Vec<String>,
+    leeway_secs: Option<u64>,
+}
+
+// 访问控制, 名单中的条目会被拒绝
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AccessSection {
+    max_sessions: usize,
+    banned_subjects: Vec<String>,
+    banned_hwids: Vec<String>,
+    revoked_token_ids: Vec<String>,
+}
+
+// 按主机名路由到的后端 ([[routes]])
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteSection {
+    name: String,
+    hosts: Vec<String>,
+    #[serde(default)]
+    backend: Option<String>,
+    #[serde(default)]
+    backends: Vec<BackendSection>,
+    #[serde(default)]
+    balance: Option<String>,
+    #[serde(default)]
+    connect_timeout_ms: Option<u64>,
+    #[serde(default)]
+    proxy_protocol: Option<String>,
+}
+
+// 后端池中的一个后端
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BackendSection {
+    addr: String,
+    #[serde(default)]
+    weight: Option<u32>,
+}
+
+// 后端主动健康检查, 对所有路由生效
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HealthCheckSection {
+    mode: Option<String>,
+    interval_secs: Option<u64>,
+    timeout_ms: Option<u64>,
+    rise: Option<u32>,
+    fall: Option<u32>,
+}
+
+// 一个路由的后端: backend (单个地址) 和 backends (带权重的列表) 二选一
+struct PoolSection {
+    backend: Option<String>,
+    backends: Vec<BackendSection>,
+    balance: Option<String>,
+}
+
+// 握手限制
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HandshakeSection {
+    timeout_ms: Option<u64>,
+    max_token_size: Option<usize>,
+}
+
+// 隧道心跳
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HeartbeatSection {
+    interval_ms: Option<u64>,
+    timeout_ms: Option<u64>,
+}
+
+// KCP 参数, 未填写的字段沿用 tokio_kcp 的默认值
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct KcpSection {
+    mtu: Option<usize>,
+    nodelay: Option<bool>,
+    interval: Option<i32>,
+    resend: Option<i32>,
+    no_congestion_control: Option<bool>,
+    send_window: Option<u16>,
+    recv_window: Option<u16>,
+    session_expire_secs: Option<u64>,
+    stream: Option<bool>,
+}
+
+// QUIC 参数, 使用 QUIC 传输时必须提供证书和私钥
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct QuicSection {
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    idle_timeout_secs: Option<u64>,
+    keep_alive_secs: Option<u64>,
+}
+
+// TLS over TCP 参数, 使用 TLS 传输时必须提供证书和私钥
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+}
+
+// 校验后的最终配置
+#[derive(Debug)]
+pub struct ServerConfig {
+    pub listen_addr: SocketAddr,
+    pub routes: RouteTable,
+    pub jwt: JwtConfig,
+    pub transport: TransportConfig,
+    pub access: AccessConfig,
+    pub handshake: HandshakeConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub log_level: LevelFilter,
+    pub notice: Option<String>, // 隧道建立后推送给支持 CAP_NOTICE 的 agent 的消息
+}
+
+// JWT 验证配置, 至少需要一种密钥来源
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    pub secret: Option<Vec<u8>>,          // HS256 共享密钥
+    pub public_keys_dir: Option<PathBuf>, // PEM 公钥目录
+    pub jwks_file: Option<PathBuf>,       // JWKS 文件
+    pub algorithms: Vec<Algorithm>,       // 允许的签名算法
+    pub reload_interval: Duration,        // 检查密钥文件变化的间隔
+    pub issuers: Vec<String>,             // 接受的 iss, 为空表示不校验
+    pub audiences: Vec<String>,           // 接受的 aud, 为空表示不校验
+    pub leeway: u64,                      // exp / nbf 允许的时钟偏差 (秒)
+}
+
+// 隧道传输协议及其参数
+#[derive(Debug, Clone)]
+pub enum TransportConfig {
+    Kcp(KcpConfig),
+    Quic(QuicConfig),
+    Tcp,
+    Tls(TlsConfig),
+}
+
+#[derive(Debug, Clone)]
+pub struct QuicConfig {
+    pub cert_file: PathBuf, // PEM 证书链
+    pub key_file: PathBuf,  // PEM 私钥
+    pub options: QuicOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf, // PEM 证书链
+    pub key_file: PathBuf,  // PEM 私钥
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessConfig {
+    pub max_sessions: usize,                // 同时在线的会话上限, 0 表示不限制
+    pub banned_subjects: HashSet<String>,   // 被封禁的 sub
+    pub banned_hwids: HashSet<String>,      // 被封禁的硬件ID
+    pub revoked_token_ids: HashSet<String>, // 被吊销的 jti
+}
+
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    pub timeout: Duration,     // 从连接建立到完成认证的最长时间
+    pub max_token_size: usize, // hello 帧中令牌的最大字节数
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration, // 发送 PING 的间隔
+    pub timeout: Duration,  // 超过这个时间没有收到对端的数据时认为隧道已经断开
+}
+
+// 配置加载 / 校验错误
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    InvalidAddress(&'static str, String),
+    InvalidLogLevel(String),
+    InvalidValue(&'static str, String),
+    MissingJwtKeys,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => {
+                write!(f, "invalid config file {}: {}", path.display(), e)
+            }
+            ConfigError::InvalidAddress(field, value) => {
+                write!(
+                    f,
+                    "{}: '{}' is not a valid socket address (expected ip:port)",
+                    field, value
+                )
+            }
+            ConfigError::InvalidLogLevel(value) => write!(
+                f,
+                "log_level: '{}' is not one of off, error, warn, info, debug, trace",
+                value
+            ),
+            ConfigError::InvalidValue(field, reason) => write!(f, "{}: {}", field, reason),
+            ConfigError::MissingJwtKeys => write!(
+                f,
+                "no JWT verification key configured; set jwt.secret, jwt.secret_file, \
+                 jwt.public_keys_dir or jwt.jwks_file in the config file, or the matching \
+                 --jwt-* flag / AGENT_SERVER_JWT_* variable"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ServerConfig {
+    // 解析命令行 (含环境变量) 并加载配置
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_cli(Cli::parse())
+    }
+
+    pub fn from_cli(cli: Cli) -> Result<Self, ConfigError> {
+        let file = match &cli.config {
+            Some(path) => read_file_config(path)?,
+            None => FileConfig::default(),
+        };
+
+        let listen = cli
+            .listen
+            .or(file.listen)
+            .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.to_string());
+        // 命令行给出的后端覆盖配置文件中的默认后端; 没有配置任何路由时使用默认后端地址
+        let default_pool = match (cli.backend, file.backend) {
+            (Some(backend), _) => Some(PoolSection {
+                backend: Some(backend),
+                backends: Vec::new(),
+                balance: file.balance,
+            }),
+            (None, backend) if backend.is_some() || !file.backends.is_empty() => Some(PoolSection {
+                backend,
+                backends: file.backends,
+                balance: file.balance,
+            }),
+            _ => file.routes.is_empty().then(|| PoolSection {
+                backend: Some(DEFAULT_BACKEND_ADDR.to_string()),
+                backends: Vec::new(),
+                balance: file.balance,
+            }),
+        };
+        let connect_timeout_ms = file
+            .connect_timeout_ms
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS);
+        let transport = cli
+            .transport
+            .or(file.transport)
+            .unwrap_or_else(|| "kcp".to_string());
+        let log_level = cli
+            .log_level
+            .or(file.log_level)
+            .unwrap_or_else(|| "info".to_string());
+        // 命令行给出的 KCP 参数逐项覆盖配置文件中的 [kcp]
+        let kcp = KcpSection {
+            mtu: cli.kcp_mtu.or(file.kcp.mtu),
+            nodelay: cli.kcp_nodelay.or(file.kcp.nodelay),
+            interval: cli.kcp_interval.or(file.kcp.interval),
+            resend: cli.kcp_resend.or(file.kcp.resend),
+            no_congestion_control: cli
+                .kcp_no_congestion_control
+                .or(file.kcp.no_congestion_control),
+            send_window: cli.kcp_send_window.or(file.kcp.send_window),
+            recv_window: cli.kcp_recv_window.or(file.kcp.recv_window),
+            session_expire_secs: file.kcp.session_expire_secs,
+            stream: file.kcp.stream,
+        };
+
+        // 命令行给出的共享密钥 (直接或文件) 整体覆盖配置文件中的共享密钥
+        let secret = if cli.jwt_secret.is_some() || cli.jwt_secret_file.is_some() {
+            resolve_secret(cli.jwt_secret, cli.jwt_secret_file.as_deref())?
+        } else {
+            resolve_secret(file.jwt.secret, file.jwt.secret_file.as_deref())?
+        };
+        let jwt = JwtConfig {
+            secret,
+            public_keys_dir: cli.jwt_public_keys_dir.or(file.jwt.public_keys_dir),
+            jwks_file: cli.jwt_jwks_file.or(file.jwt.jwks_file),
+            algorithms: parse_algorithms(file.jwt.algorithms)?,
+            reload_interval: Duration::from_secs(
+                file.jwt
+                    .reload_interval_secs
+                    .unwrap_or(DEFAULT_KEY_RELOAD_SECS)
+                    .max(1),
+            ),
+            issuers: file.jwt.issuers,
+            audiences: file.jwt.audiences,
+            leeway: file.jwt.leeway_secs.unwrap_or(DEFAULT_JWT_LEEWAY_SECS),
+        };
+        if jwt.leeway > 300 {
+            return Err(ConfigError::InvalidValue(
+                "jwt.leeway_secs",
+                format!("{} seconds is more than the allowed 300", jwt.leeway),
+            ));
+        }
+        if jwt.secret.is_none() && jwt.public_keys_dir.is_none() && jwt.jwks_file.is_none() {
+            return Err(ConfigError::MissingJwtKeys);
+        }
+
+        let handshake = HandshakeConfig {
+            timeout: Duration::from_millis(
+                file.handshake
+                    .timeout_ms
+                    .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT_MS),
+            ),
+            max_token_size: file
+                .handshake
+                .max_token_size
+                .unwrap_or(DEFAULT_MAX_TOKEN_SIZE),
+        };
+        if handshake.timeout.is_zero() {
+            return Err(ConfigError::InvalidValue(
+                "handshake.timeout_ms",
+                "must be greater than 0".to_string(),
+            ));
+        }
+        if !(256..=64 * 1024).contains(&handshake.max_token_size) {
+            return Err(ConfigError::InvalidValue(
+                "handshake.max_token_size",
+                format!("{} is out of range 256..=65536", handshake.max_token_size),
+            ));
+        }
+
+        let heartbeat = HeartbeatConfig {
+            interval: Duration::from_millis(
+                file.heartbeat
+                    .interval_ms
+                    .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_MS),
+            ),
+            timeout: Duration::from_millis(
+                file.heartbeat
+                    .timeout_ms
+                    .unwrap_or(DEFAULT_HEARTBEAT_TIMEOUT_MS),
+            ),
+        };
+        if heartbeat.interval.is_zero() {
+            return Err(ConfigError::InvalidValue(
+                "heartbeat.interval_ms",
+                "must be greater than 0".to_string(),
+            ));
+        }
+        if heartbeat.timeout <= heartbeat.interval {
+            return Err(ConfigError::InvalidValue(
+                "heartbeat.timeout_ms",
+                "must be greater than heartbeat.interval_ms".to_string(),
+            ));
+        }
+        let notice = file.notice.filter(|notice| !notice.is_empty());
+        if let Some(notice) = &notice
+            && notice.len() > MAX_FRAME_PAYLOAD
+        {
+            return Err(ConfigError::InvalidValue(
+                "notice",
+                format!("must not be longer than {} bytes", MAX_FRAME_PAYLOAD),
+            ));
+        }
+
+        Ok(ServerConfig {
+            listen_addr: parse_addr("listen", &listen)?,
+            routes: build_route_table(
+                default_pool,
+                connect_timeout_ms,
+                parse_proxy_protocol("proxy_protocol", file.proxy_protocol.as_deref())?,
+                build_health_check(file.health_check)?,
+                file.routes,
+            )?,
+            jwt,
+            transport: match transport.as_str() {
+                "kcp" => TransportConfig::Kcp(build_kcp_config(&kcp)?),
+                "quic" => TransportConfig::Quic(build_quic_config(file.quic)?),
+                "tcp" => TransportConfig::Tcp,
+                "tls" => TransportConfig::Tls(build_tls_config(file.tls)?),
+                _ => {
+                    return Err(ConfigError::InvalidValue(
+                        "transport",
+                        format!("'{}' is not one of kcp, quic, tcp, tls", transport),
+                    ));
+                }
+            },
+            access: AccessConfig {
+                max_sessions: file.access.max_sessions,
+                banned_subjects: file.access.banned_subjects.into_iter().collect(),
+                banned_hwids: file.access.banned_hwids.into_iter().collect(),
+                revoked_token_ids: file.access.revoked_token_ids.into_iter().collect(),
+            },
+            handshake,
+            heartbeat,
+            log_level: log_level
+                .parse()
+                .map_err(|_| ConfigError::InvalidLogLevel(log_level))?,
+            notice,
+        })
+    }
+}
+
+fn read_file_config(path: &Path) -> Result<FileConfig, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+}
+
+fn parse_addr(field: &'static str, value: &str) -> Result<SocketAddr, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::InvalidAddress(field, value.to_string()))
+}
+
+fn build_route_table(
+    default_pool: Option<PoolSection>,
+    connect_timeout_ms: u64,
+    proxy_protocol: Option<ProxyProtocol>, // 默认后端使用的 PROXY protocol
+    health_check: Option<HealthCheckConfig>,
+    sections: Vec<RouteSection>,
+) -> Result<RouteTable, ConfigError> {
+    let mut table = RouteTable::new();
+    for section in sections {
+        let pool = PoolSection {
+            backend: section.backend,
+            backends: section.backends,
+            balance: section.balance,
+        };
+        let route = Route {
+            pool: build_pool("routes", pool, health_check)?,
+            connect_timeout: connect_timeout(
+                "routes.connect_timeout_ms",
+                section.connect_timeout_ms.unwrap_or(connect_timeout_ms),
+            )?,
+            proxy_protocol: parse_proxy_protocol(
+                "routes.proxy_protocol",
+                section.proxy_protocol.as_deref(),
+            )?,
+            name: section.name,
+        };
+        table
+            .add(route, &section.hosts)
+            .map_err(|e| ConfigError::InvalidValue("routes", e))?;
+    }
+    if let Some(pool) = default_pool {
+        let route = Route {
+            name: routing::DEFAULT_ROUTE_NAME.to_string(),
+            pool: build_pool("backend", pool, health_check)?,
+            connect_timeout: connect_timeout("connect_timeout_ms", connect_timeout_ms)?,
+            proxy_protocol,
+        };
+        table
+            .set_default(route)
+            .map_err(|e| ConfigError::InvalidValue("routes", e))?;
+    }
+    Ok(table)
+}
+
+fn build_pool(
+    field: &'static str,
+    section: PoolSection,
+    health_check: Option<HealthCheckConfig>,
+) -> Result<BackendPool, ConfigError> {
+    let backends = match (section.backend, section.backends.is_empty()) {
+        (Some(addr), true) => vec![Backend::new(parse_addr(field, &addr)?, 1)],
+        (None, false) => section
+            .backends
+            .into_iter()
+            .map(|backend| {
+                let weight = backend.weight.unwrap_or(1);
+                if !(1..=1000).contains(&weight) {
+                    return Err(ConfigError::InvalidValue(
+                        field,
+                        format!("weight {} is out of range 1..=1000", weight),
+                    ));
+                }
+                Ok(Backend::new(parse_addr(field, &backend.addr)?, weight))
+            })
+            .collect::<Result<_, _>>()?,
+        (Some(_), false) => {
+            return Err(ConfigError::InvalidValue(
+                field,
+                "backend and backends are mutually exclusive".to_string(),
+            ));
+        }
+        (None, true) => {
+            return Err(ConfigError::InvalidValue(
+                field,
+                "either backend or backends is required".to_string(),
+            ));
+        }
+    };
+    let balance = match section.balance.as_deref() {
+        None => Balance::LeastConnections,
+        Some(value) => Balance::parse(value).ok_or_else(|| {
+            ConfigError::InvalidValue(
+                "balance",
+                format!("'{}' is not one of least_connections, weighted", value),
+            )
+        })?,
+    };
+    Ok(BackendPool::new(backends, balance, health_check))
+}
+
+// 默认使用 TCP 连接检查, mode = "none" 关闭主动检查
+fn build_health_check(section: HealthCheckSection) -> Result<Option<HealthCheckConfig>, ConfigError> {
+    let mode = match section.mode.as_deref().unwrap_or("tcp") {
+        "none" => return Ok(None),
+        value => HealthCheckMode::parse(value).ok_or_else(|| {
+            ConfigError::InvalidValue(
+                "health_check.mode",
+                format!("'{}' is not one of none, tcp, status", value),
+            )
+        })?,
+    };
+    let config = HealthCheckConfig {
+        mode,
+        interval: Duration::from_secs(
+            section
+                .interval_secs
+                .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL_SECS),
+        ),
+        timeout: Duration::from_millis(
+            section
+                .timeout_ms
+                .unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT_MS),
+        ),
+        rise: section.rise.unwrap_or(DEFAULT_HEALTH_CHECK_RISE).max(1),
+        fall: section.fall.unwrap_or(DEFAULT_HEALTH_CHECK_FALL).max(1),
+    };
+    if config.interval.is_zero() || config.timeout.is_zero() || config.timeout > config.interval {
+        return Err(ConfigError::InvalidValue(
+            "health_check",
+            "timeout_ms must be greater than 0 and not longer than interval_secs".to_string(),
+        ));
+    }
+    Ok(Some(config))
+}
+
+fn parse_proxy_protocol(
+    field: &'static str,
+    value: Option<&str>,
+) -> Result<Option<ProxyProtocol>, ConfigError> {
+    match value {
+        None | Some("none") => Ok(None),
+        Some(value) => ProxyProtocol::parse(value).map(Some).ok_or_else(|| {
+            ConfigError::InvalidValue(field, format!("'{}' is not one of none, v1, v2", value))
+        }),
+    }
+}
+
+fn connect_timeout(field: &'static str, millis: u64) -> Result<Duration, ConfigError> {
+    if millis == 0 {
+        return Err(ConfigError::InvalidValue(
+            field,
+            "must be greater than 0".to_string(),
+        ));
+    }
+    Ok(Duration::from_millis(millis))
+}
+
+fn resolve_secret(
+    secret: Option<String>,
+    secret_file: Option<&Path>,
+) -> Result<Option<Vec<u8>>, ConfigError> {
+    let secret = match (secret, secret_file) {
+        (Some(_), Some(_)) => {
+            return Err(ConfigError::InvalidValue(
+                "jwt",
+                "secret and secret_file are mutually exclusive".to_string(),
+            ));
+        }
+        (Some(secret), None) => secret,
+        (None, Some(path)) => fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(path.to_path_buf(), e))?
+            .trim()
+            .to_string(),
+        (None, None) => return Ok(None),
+    };
+
+    if secret.is_empty() {
+        return Err(ConfigError::InvalidValue(
+            "jwt.secret",
+            "must not be empty".to_string(),
+        ));
+    }
+    Ok(Some(secret.into_bytes()))
+}
+
+fn parse_algorithms(names: Option<Vec<String>>) -> Result<Vec<Algorithm>, ConfigError> {
+    let names = names.unwrap_or_else(|| {
+        DEFAULT_JWT_ALGORITHMS
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    });
+    if names.is_empty() {
+        return Err(ConfigError::InvalidValue(
+            "jwt.algorithms",
+            "at least one algorithm must be allowed".to_string(),
+        ));
+    }
+    names
+        .iter()
+        .map(|name| {
+            name.parse().map_err(|_| {
+                ConfigError::InvalidValue("jwt.algorithms", format!("unknown algorithm '{}'", name))
+            })
+        })
+        .collect()
+}
+
+fn build_kcp_config(section: &KcpSection) -> Result<KcpConfig, ConfigError> {
+    let mut config = KcpConfig::default();
+
+    if let Some(mtu) = section.mtu {
+        // KCP 包头 24 字节, 太小的 MTU 没有意义; 超过以太网 MTU 会导致 IP 分片
+        if !(64..=1500).contains(&mtu) {
+            return Err(ConfigError::InvalidValue(
+                "kcp.mtu",
+                format!("{} is out of range 64..=1500", mtu),
+            ));
+        }
+        config.mtu = mtu;
+    }
+
+    let interval = section.interval.unwrap_or(config.nodelay.interval);
+    if !(10..=5000).contains(&interval) {
+        return Err(ConfigError::InvalidValue(
+            "kcp.interval",
+            format!("{} ms is out of range 10..=5000", interval),
+        ));
+    }
+    let resend = section.resend.unwrap_or(config.nodelay.resend);
+    if resend < 0 {
+        return Err(ConfigError::InvalidValue(
+            "kcp.resend",
+            "must not be negative".to_string(),
+        ));
+    }
+    config.nodelay = KcpNoDelayConfig {
+        nodelay: section.nodelay.unwrap_or(config.nodelay.nodelay),
+        interval,
+        resend,
+        nc: section.no_congestion_control.unwrap_or(config.nodelay.nc),
+    };
+
+    let send_window = section.send_window.unwrap_or(config.wnd_size.0);
+    let recv_window = section.recv_window.unwrap_or(config.wnd_size.1);
+    if send_window == 0 || recv_window == 0 {
+        return Err(ConfigError::InvalidValue(
+            "kcp.send_window/recv_window",
+            "window size must be greater than 0".to_string(),
+        ));
+    }
+    config.wnd_size = (send_window, recv_window);
+
+    if let Some(secs) = section.session_expire_secs {
+        if secs == 0 {
+            return Err(ConfigError::InvalidValue(
+                "kcp.session_expire_secs",
+                "must be greater than 0".to_string(),
+            ));
+        }
+        config.session_expire = Duration::from_secs(secs);
+    }
+    if let Some(stream) = section.stream {
+        config.stream = stream;
+    }
+
+    Ok(config)
+}
+
+fn build_quic_config(section: QuicSection) -> Result<QuicConfig, ConfigError> {
+    let (Some(cert_file), Some(key_file)) = (section.cert_file, section.key_file) else {
+        return Err(ConfigError::InvalidValue(
+            "quic",
+            "cert_file and key_file are required for the QUIC transport".to_string(),
+        ));
+    };
+
+    let options = QuicOptions {
+        idle_timeout: Duration::from_secs(
+            section
+                .idle_timeout_secs
+                .unwrap_or(DEFAULT_QUIC_IDLE_TIMEOUT_SECS),
+        ),
+        keep_alive: Duration::from_secs(
+            section
+                .keep_alive_secs
+                .unwrap_or(DEFAULT_QUIC_KEEP_ALIVE_SECS),
+        ),
+    };
+    // 保活间隔必须小于空闲超时, 否则空闲连接会在保活包发出前被关闭
+    if options.keep_alive.is_zero() || options.keep_alive >= options.idle_timeout {
+        return Err(ConfigError::InvalidValue(
+            "quic.keep_alive_secs",
+            format!(
+                "must be greater than 0 and less than idle_timeout_secs ({})",
+                options.idle_timeout.as_secs()
+            ),
+        ));
+    }
+
+    Ok(QuicConfig {
+        cert_file,
+        key_file,
+        options,
+    })
+}
+
+fn build_tls_config(section: TlsSection) -> Result<TlsConfig, ConfigError> {
+    let (Some(cert_file), Some(key_file)) = (section.cert_file, section.key_file) else {
+        return Err(ConfigError::InvalidValue(
+            "tls",
+            "cert_file and key_file are required for the TLS transport".to_string(),
+        ));
+    };
+    Ok(TlsConfig {
+        cert_file,
+        key_file,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 测试用的配置文件, 文件名带上进程ID避免并行的测试进程互相覆盖
+    fn write_config(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("agent-server-{}-{}.toml", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn load(args: &[&str]) -> Result<ServerConfig, ConfigError> {
+        let mut argv = vec!["server"];
+        argv.extend_from_slice(args);
+        ServerConfig::from_cli(Cli::try_parse_from(argv).unwrap())
+    }
+
+    fn load_file(name: &str, text: &str) -> Result<ServerConfig, ConfigError> {
+        let path = write_config(name, text);
+        let result = load(&["--config", path.to_str().unwrap(), "--jwt-secret", "secret"]);
+        let _ = fs::remove_file(path);
+        result
+    }
+
+    fn error_message(result: Result<ServerConfig, ConfigError>) -> String {
+        result.map(|_| ()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn defaults_need_only_a_jwt_key() {
+        let config = load(&["--jwt-secret", "secret"]).unwrap();
+        assert_eq!(config.listen_addr, DEFAULT_LISTEN_ADDR.parse().unwrap());
+        assert_eq!(config.log_level, LevelFilter::Info);
+        assert!(matches!(config.transport, TransportConfig::Kcp(_)));
+    }
+
+    #[test]
+    fn missing_jwt_keys_are_rejected() {
+        assert!(matches!(load(&[]), Err(ConfigError::MissingJwtKeys)));
+    }
+
+    #[test]
+    fn invalid_cli_values_name_the_field() {
+        let cases: &[(&[&str], &str)] = &[
+            (
+                &["--listen", "localhost"],
+                "listen: 'localhost' is not a valid socket address (expected ip:port)",
+            ),
+            (
+                &["--backend", "25565"],
+                "backend: '25565' is not a valid socket address (expected ip:port)",
+            ),
+            (
+                &["--log-level", "verbose"],
+                "log_level: 'verbose' is not one of off, error, warn, info, debug, trace",
+            ),
+            (&["--transport", "udp"], "transport: 'udp' is not one of kcp, quic, tcp, tls"),
+            (&["--kcp-mtu", "9000"], "kcp.mtu: 9000 is out of range 64..=1500"),
+            (&["--kcp-interval", "5"], "kcp.interval: 5 ms is out of range 10..=5000"),
+            (&["--kcp-resend=-1"], "kcp.resend: must not be negative"),
+            (
+                &["--kcp-send-window", "0"],
+                "kcp.send_window/recv_window: window size must be greater than 0",
+            ),
+            (
+                &["--transport", "quic"],
+                "quic: cert_file and key_file are required for the QUIC transport",
+            ),
+        ];
+        for (args, expected) in cases {
+            let mut args = args.to_vec();
+            args.extend_from_slice(&["--jwt-secret", "secret"]);
+            assert_eq!(error_message(load(&args)), *expected, "args {:?}", args);
+        }
+    }
+
+    #[test]
+    fn invalid_file_values_name_the_field() {
+        let cases = [
+            (
+                "kcp_expire",
+                "[kcp]\nsession_expire_secs = 0\n",
+                "kcp.session_expire_secs: must be greater than 0",
+            ),
+            (
+                "handshake",
+                "[handshake]\ntimeout_ms = 0\n",
+                "handshake.timeout_ms: must be greater than 0",
+            ),
+            (
+                "token_size",
+                "[handshake]\nmax_token_size = 100\n",
+                "handshake.max_token_size: 100 is out of range 256..=65536",
+            ),
+            (
+                "heartbeat",
+                "[heartbeat]\ninterval_ms = 5000\ntimeout_ms = 5000\n",
+                "heartbeat.timeout_ms: must be greater than heartbeat.interval_ms",
+            ),
+            (
+                "leeway",
+                "[jwt]\nleeway_secs = 600\n",
+                "jwt.leeway_secs: 600 seconds is more than the allowed 300",
+            ),
+            (
+                "algorithms",
+                "[jwt]\nalgorithms = [\"XS256\"]\n",
+                "jwt.algorithms: unknown algorithm 'XS256'",
+            ),
+            (
+                "pool",
+                "backend = \"127.0.0.1:25565\"\nbackends = [{ addr = \"127.0.0.1:25566\" }]\n",
+                "backend: backend and backends are mutually exclusive",
+            ),
+            (
+                "weight",
+                "backends = [{ addr = \"127.0.0.1:25566\", weight = 0 }]\n",
+                "backend: weight 0 is out of range 1..=1000",
+            ),
+            (
+                "proxy",
+                "proxy_protocol = \"v3\"\n",
+                "proxy_protocol: 'v3' is not one of none, v1, v2",
+            ),
+            (
+                "quic_keep_alive",
+                "transport = \"quic\"\n[quic]\ncert_file = \"a.crt\"\nkey_file = \"a.key\"\n\
+                 idle_timeout_secs = 10\nkeep_alive_secs = 10\n",
+                "quic.keep_alive_secs: must be greater than 0 and less than idle_timeout_secs (10)",
+            ),
+        ];
+        for (name, text, expected) in cases {
+            assert_eq!(error_message(load_file(name, text)), expected, "config {:?}", text);
+        }
+    }
+
+    #[test]
+    fn unknown_file_fields_are_rejected() {
+        let message = error_message(load_file("unknown", "[kcp]\nwindow = 10\n"));
+        assert!(message.starts_with("invalid config file"), "{}", message);
+        assert!(message.contains("unknown field `window`"), "{}", message);
+    }
+
+    #[test]
+    fn cli_kcp_flags_override_the_file() {
+        let path = write_config("kcp_override", "[kcp]\nmtu = 1200\ninterval = 40\nresend = 2\n");
+        let config = load(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--jwt-secret",
+            "secret",
+            "--kcp-interval",
+            "20",
+            "--kcp-nodelay",
+            "true",
+            "--kcp-recv-window",
+            "512",
+        ]);
+        let _ = fs::remove_file(path);
+        let TransportConfig::Kcp(kcp) = config.unwrap().transport else {
+            panic!("expected the KCP transport");
+        };
+        assert_eq!(kcp.mtu, 1200);
+        assert_eq!(kcp.nodelay.interval, 20);
+        assert_eq!(kcp.nodelay.resend, 2);
+        assert!(kcp.nodelay.nodelay);
+        assert_eq!(kcp.wnd_size.1, 512);
+    }
+}
@@ -0,0 +1,523 @@
+// JWT 验证密钥管理
+// 支持 HS256 共享密钥、PEM 公钥目录 (文件名即 kid) 和 JWKS 文件, 按 kid 选择密钥,
+// 多个密钥可以同时生效以便轮换, 文件变化后后台任务会自动重新加载
+use crate::config::JwtConfig;
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet, PublicKeyUse};
+use jsonwebtoken::{Algorithm, DecodingKey};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+// 密钥类型, 决定了可以用来验证哪些签名算法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyFamily {
+    Hmac,
+    Rsa,
+    Ec,
+    Ed,
+}
+
+impl KeyFamily {
+    fn algorithms(self) -> &'static [Algorithm] {
+        match self {
+            KeyFamily::Hmac => &[Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+            KeyFamily::Rsa => &[
+                Algorithm::RS256,
+                Algorithm::RS384,
+                Algorithm::RS512,
+                Algorithm::PS256,
+                Algorithm::PS384,
+                Algorithm::PS512,
+            ],
+            KeyFamily::Ec => &[Algorithm::ES256, Algorithm::ES384],
+            KeyFamily::Ed => &[Algorithm::EdDSA],
+        }
+    }
+}
+
+pub struct KeyEntry {
+    pub key: DecodingKey,
+    pub algorithms: Vec<Algorithm>, // 这个密钥允许验证的算法
+}
+
+// 某一时刻生效的全部密钥
+#[derive(Default)]
+pub struct KeySet {
+    by_kid: HashMap<String, KeyEntry>,
+    without_kid: Vec<KeyEntry>, // 共享密钥等没有 kid 的密钥
+}
+
+impl KeySet {
+    // 令牌带 kid 时只匹配同名密钥, 否则在没有 kid 的密钥中按算法匹配
+    pub fn candidates(&self, kid: Option<&str>, alg: Algorithm) -> Vec<&KeyEntry> {
+        match kid {
+            Some(kid) => self
+                .by_kid
+                .get(kid)
+                .filter(|entry| entry.algorithms.contains(&alg))
+                .into_iter()
+                .collect(),
+            None => self
+                .without_kid
+                .iter()
+                .filter(|entry| entry.algorithms.contains(&alg))
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_kid.len() + self.without_kid.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn insert(&mut self, kid: Option<String>, entry: KeyEntry) -> Result<(), KeyError> {
+        match kid {
+            Some(kid) => {
+                if self.by_kid.contains_key(&kid) {
+                    return Err(KeyError::DuplicateKid(kid));
+                }
+                self.by_kid.insert(kid, entry);
+            }
+            None => self.without_kid.push(entry),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    Read(PathBuf, std::io::Error),
+    InvalidKey(PathBuf, String),
+    DuplicateKid(String),
+    NoKeys,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            KeyError::InvalidKey(path, reason) => {
+                write!(f, "invalid key in {}: {}", path.display(), reason)
+            }
+            KeyError::DuplicateKid(kid) => write!(f, "key id '{}' is defined more than once", kid),
+            KeyError::NoKeys => write!(f, "no usable JWT verification keys were loaded"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+pub struct KeyStore {
+    config: JwtConfig,
+    current: RwLock<Arc<KeySet>>,
+    fingerprint: RwLock<Vec<(PathBuf, Option<SystemTime>)>>,
+}
+
+impl KeyStore {
+    // 启动时加载一次, 加载失败直接返回错误
+    pub fn load(config: &JwtConfig) -> Result<Self, KeyError> {
+        let keys = load_keys(config)?;
+        info!("Server: Loaded {} JWT verification key(s)", keys.len());
+        Ok(KeyStore {
+            config: config.clone(),
+            current: RwLock::new(Arc::new(keys)),
+            fingerprint: RwLock::new(fingerprint(config)),
+        })
+    }
+
+    // 当前生效的密钥快照, 验证过程中不会被重新加载打断
+    pub fn snapshot(&self) -> Arc<KeySet> {
+        self.current.read().unwrap().clone()
+    }
+
+    // 如果密钥文件有变化则重新加载; 新密钥无效时保留旧密钥
+    // 加载成功后才记录指纹, 轮换时读到写了一半的文件会在下次检查时重试
+    pub fn reload_if_changed(&self) {
+        let latest = fingerprint(&self.config);
+        if *self.fingerprint.read().unwrap() == latest {
+            return;
+        }
+
+        match load_keys(&self.config) {
+            Ok(keys) => {
+                info!("Server: Reloaded {} JWT verification key(s)", keys.len());
+                *self.current.write().unwrap() = Arc::new(keys);
+                *self.fingerprint.write().unwrap() = latest;
+            }
+            Err(e) => error!(
+                "Server: Failed to reload JWT keys, keeping previous set: {}",
+                e
+            ),
+        }
+    }
+
+    // 后台任务: 定期检查密钥文件
+    pub fn spawn_reload_task(self: Arc<Self>) {
+        let interval = self.config.reload_interval;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let store = self.clone();
+                // 读文件和解析 PEM 是阻塞操作
+                if let Err(e) = tokio::task::spawn_blocking(move || store.reload_if_changed()).await
+                {
+                    error!("Server: JWT key reload task failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+fn load_keys(config: &JwtConfig) -> Result<KeySet, KeyError> {
+    let mut keys = KeySet::default();
+
+    if let Some(secret) = &config.secret {
+        keys.insert(
+            None,
+            KeyEntry {
+                key: DecodingKey::from_secret(secret),
+                algorithms: allowed(config, KeyFamily::Hmac.algorithms()),
+            },
+        )?;
+    }
+    if let Some(dir) = &config.public_keys_dir {
+        for path in pem_files(dir)? {
+            let kid = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .ok_or_else(|| {
+                    KeyError::InvalidKey(path.clone(), "file has no name".to_string())
+                })?;
+            let pem = fs::read(&path).map_err(|e| KeyError::Read(path.clone(), e))?;
+            let (key, family) =
+                parse_pem(&pem).map_err(|reason| KeyError::InvalidKey(path.clone(), reason))?;
+            keys.insert(
+                Some(kid),
+                KeyEntry {
+                    key,
+                    algorithms: allowed(config, family.algorithms()),
+                },
+            )?;
+        }
+    }
+    if let Some(path) = &config.jwks_file {
+        let text = fs::read_to_string(path).map_err(|e| KeyError::Read(path.clone(), e))?;
+        let set: JwkSet = serde_json::from_str(&text)
+            .map_err(|e| KeyError::InvalidKey(path.clone(), e.to_string()))?;
+        for jwk in &set.keys {
+            let kid = jwk.common.key_id.clone();
+            // 加密用的密钥 (use = "enc") 不能用来验证签名, 即使密钥类型相同
+            if let Some(key_use) = &jwk.common.public_key_use
+                && *key_use != PublicKeyUse::Signature
+            {
+                warn!(
+                    "Server: Ignoring key {:?} in {} with use {:?}",
+                    kid,
+                    path.display(),
+                    key_use
+                );
+                continue;
+            }
+            let family = match &jwk.algorithm {
+                AlgorithmParameters::RSA(_) => KeyFamily::Rsa,
+                AlgorithmParameters::EllipticCurve(_) => KeyFamily::Ec,
+                AlgorithmParameters::OctetKeyPair(_) => KeyFamily::Ed,
+                // 对称密钥不应该出现在公开的 JWKS 中
+                AlgorithmParameters::OctetKey(_) => {
+                    warn!(
+                        "Server: Ignoring symmetric key {:?} in {}",
+                        kid,
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+            // JWK 声明了 alg 时只允许该算法; 不是签名算法 (例如 RSA-OAEP) 时忽略这个密钥,
+            // 不能当作没有声明而允许整个算法族
+            let declared = match jwk.common.key_algorithm {
+                None => None,
+                Some(alg) => match alg.to_string().parse::<Algorithm>() {
+                    Ok(alg) => Some(alg),
+                    Err(_) => {
+                        warn!(
+                            "Server: Ignoring key {:?} in {} with non-signature algorithm {}",
+                            kid,
+                            path.display(),
+                            alg
+                        );
+                        continue;
+                    }
+                },
+            };
+            let algorithms = match declared {
+                Some(alg) if family.algorithms().contains(&alg) => allowed(config, &[alg]),
+                Some(alg) => {
+                    return Err(KeyError::InvalidKey(
+                        path.clone(),
+                        format!(
+                            "key {:?} declares {:?} which does not match its key type",
+                            kid, alg
+                        ),
+                    ));
+                }
+                None => allowed(config, family.algorithms()),
+            };
+            let key = DecodingKey::from_jwk(jwk)
+                .map_err(|e| KeyError::InvalidKey(path.clone(), format!("key {:?}: {}", kid, e)))?;
+            keys.insert(kid, KeyEntry { key, algorithms })?;
+        }
+    }
+
+    if keys.is_empty() {
+        return Err(KeyError::NoKeys);
+    }
+    Ok(keys)
+}
+
+// 依次尝试 RSA / EC / Ed25519, PEM 解析器会检查密钥类型
+fn parse_pem(pem: &[u8]) -> Result<(DecodingKey, KeyFamily), String> {
+    if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
+        return Ok((key, KeyFamily::Rsa));
+    }
+    if let Ok(key) = DecodingKey::from_ec_pem(pem) {
+        return Ok((key, KeyFamily::Ec));
+    }
+    DecodingKey::from_ed_pem(pem)
+        .map(|key| (key, KeyFamily::Ed))
+        .map_err(|e| format!("not an RSA, EC or Ed25519 public key: {}", e))
+}
+
+// 与配置中允许的算法取交集
+fn allowed(config: &JwtConfig, algorithms: &[Algorithm]) -> Vec<Algorithm> {
+    algorithms
+        .iter()
+        .copied()
+        .filter(|alg| config.algorithms.contains(alg))
+        .collect()
+}
+
+fn pem_files(dir: &Path) -> Result<Vec<PathBuf>, KeyError> {
+    let entries = fs::read_dir(dir).map_err(|e| KeyError::Read(dir.to_path_buf(), e))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "pem"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// 所有密钥文件的修改时间, 用来判断是否需要重新加载
+fn fingerprint(config: &JwtConfig) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut paths = Vec::new();
+    if let Some(dir) = &config.public_keys_dir {
+        // 目录本身的修改时间能反映文件的增删
+        paths.push(dir.clone());
+        paths.extend(pem_files(dir).unwrap_or_default());
+    }
+    if let Some(path) = &config.jwks_file {
+        paths.push(path.clone());
+    }
+    paths
+        .into_iter()
+        .map(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // 测试用的公钥, 对应的私钥没有保存
+    const RSA_PEM: &str = "-----BEGIN PUBLIC KEY-----
+MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAzjT1NaNCZxHZAO4zRvoI
+YXvLDtIglrksxzITA+jFRpVbrHf04ycF2M3VzgWs7pIwMwYIxJ3wkiRID8rxlsTM
+haCRnU/FwO17xoZhujS4qQLa95z9BHTYT+QOmMMgmQXPtAzmUdXgzA3zYTP0JFNG
+gjldpSlcoqmU+HHaOHhB/SXysMXyztgDjHlHl64XbXqy6ggYAdPg153FhFox43Wn
+yvQrvQTdoXdbeHO/RFm2276xuYoLyfT9akmIPJUzDf0F0Z5m7ueLa9ZKY2xGMdeD
+uriqFks7sbaOftTVN2ihwe1iOPpAq/2qkkdK1fy6gIG7jAtHjn6LYlLqGBRYE8QK
+qwIDAQAB
+-----END PUBLIC KEY-----
+";
+    const EC_PEM: &str = "-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE4Aia9SN/oyGuN4/Nqiqh5BXHkZvo
+n0DYJEKmM2Puw4h3Hdl2D1Mw1CCUHvSimOdOO5BZcNeDhKSno48+hYeWsA==
+-----END PUBLIC KEY-----
+";
+    const ED_PEM: &str = "-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEAc4S47Dk8lkUCUKe05ZXOwr2en3G5X1OzF3Rgx9F8ACU=
+-----END PUBLIC KEY-----
+";
+    // 与上面的 RSA / EC 公钥相同
+    const RSA_N: &str = "zjT1NaNCZxHZAO4zRvoIYXvLDtIglrksxzITA-jFRpVbrHf04ycF2M3VzgWs7pIwMwYIxJ3wkiRI\
+        D8rxlsTMhaCRnU_FwO17xoZhujS4qQLa95z9BHTYT-QOmMMgmQXPtAzmUdXgzA3zYTP0JFNGgjldpSlcoqmU-HHaOH\
+        hB_SXysMXyztgDjHlHl64XbXqy6ggYAdPg153FhFox43WnyvQrvQTdoXdbeHO_RFm2276xuYoLyfT9akmIPJUzDf0F\
+        0Z5m7ueLa9ZKY2xGMdeDuriqFks7sbaOftTVN2ihwe1iOPpAq_2qkkdK1fy6gIG7jAtHjn6LYlLqGBRYE8QKqw";
+    const EC_X: &str = "4Aia9SN_oyGuN4_Nqiqh5BXHkZvon0DYJEKmM2Puw4g";
+    const EC_Y: &str = "dx3Zdg9TMNQglB70opjnTjuQWXDXg4Skp6OPPoWHlrA";
+
+    // 每个测试使用自己的临时目录
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("agent-keys-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn jwt_config(algorithms: &[Algorithm]) -> JwtConfig {
+        JwtConfig {
+            secret: None,
+            public_keys_dir: None,
+            jwks_file: None,
+            algorithms: algorithms.to_vec(),
+            reload_interval: Duration::from_secs(30),
+            issuers: Vec::new(),
+            audiences: Vec::new(),
+            leeway: 0,
+        }
+    }
+
+    fn candidate_count(keys: &KeySet, kid: Option<&str>, alg: Algorithm) -> usize {
+        keys.candidates(kid, alg).len()
+    }
+
+    #[test]
+    fn kid_selects_only_the_key_with_that_name() {
+        let dir = temp_dir("kid");
+        fs::write(dir.join("rsa-1.pem"), RSA_PEM).unwrap();
+        fs::write(dir.join("ec-1.pem"), EC_PEM).unwrap();
+        fs::write(dir.join("ed-1.pem"), ED_PEM).unwrap();
+        fs::write(dir.join("notes.txt"), "not a key").unwrap();
+        let mut config = jwt_config(&[
+            Algorithm::HS256,
+            Algorithm::RS256,
+            Algorithm::ES256,
+            Algorithm::EdDSA,
+        ]);
+        config.secret = Some(b"secret".to_vec());
+        config.public_keys_dir = Some(dir.clone());
+
+        let keys = load_keys(&config).unwrap();
+        assert_eq!(keys.len(), 4);
+        assert_eq!(candidate_count(&keys, Some("rsa-1"), Algorithm::RS256), 1);
+        assert_eq!(candidate_count(&keys, Some("ec-1"), Algorithm::ES256), 1);
+        assert_eq!(candidate_count(&keys, Some("ed-1"), Algorithm::EdDSA), 1);
+        // kid 对应的密钥类型与算法不符
+        assert_eq!(candidate_count(&keys, Some("rsa-1"), Algorithm::ES256), 0);
+        assert_eq!(candidate_count(&keys, Some("unknown"), Algorithm::RS256), 0);
+        // 没有 kid 的令牌只能匹配共享密钥
+        assert_eq!(candidate_count(&keys, None, Algorithm::HS256), 1);
+        assert_eq!(candidate_count(&keys, None, Algorithm::RS256), 0);
+        // 共享密钥没有 kid, 带 kid 的令牌不会用它验证
+        assert_eq!(candidate_count(&keys, Some("rsa-1"), Algorithm::HS256), 0);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn configured_algorithms_restrict_every_key() {
+        let dir = temp_dir("algorithms");
+        fs::write(dir.join("rsa-1.pem"), RSA_PEM).unwrap();
+        let mut config = jwt_config(&[Algorithm::RS256]);
+        config.secret = Some(b"secret".to_vec());
+        config.public_keys_dir = Some(dir.clone());
+
+        let keys = load_keys(&config).unwrap();
+        assert_eq!(candidate_count(&keys, Some("rsa-1"), Algorithm::RS256), 1);
+        assert_eq!(candidate_count(&keys, Some("rsa-1"), Algorithm::PS256), 0);
+        assert_eq!(candidate_count(&keys, None, Algorithm::HS256), 0);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn jwks_keys_are_limited_to_signatures_and_their_declared_algorithm() {
+        let dir = temp_dir("jwks");
+        let path = dir.join("jwks.json");
+        let jwks = serde_json::json!({ "keys": [
+            { "kty": "RSA", "kid": "rsa-any", "n": RSA_N, "e": "AQAB" },
+            { "kty": "RSA", "kid": "rsa-ps", "alg": "PS256", "use": "sig",
+              "n": RSA_N, "e": "AQAB" },
+            { "kty": "RSA", "kid": "rsa-oaep", "alg": "RSA-OAEP", "n": RSA_N, "e": "AQAB" },
+            { "kty": "RSA", "kid": "rsa-enc", "use": "enc", "n": RSA_N, "e": "AQAB" },
+            { "kty": "EC", "kid": "ec", "alg": "ES256", "crv": "P-256", "x": EC_X, "y": EC_Y },
+            { "kty": "oct", "kid": "shared", "k": "c2VjcmV0" },
+        ]});
+        fs::write(&path, jwks.to_string()).unwrap();
+        let mut config = jwt_config(KeyFamily::Rsa.algorithms());
+        config.algorithms.push(Algorithm::ES256);
+        config.jwks_file = Some(path);
+
+        let keys = load_keys(&config).unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(candidate_count(&keys, Some("rsa-any"), Algorithm::RS512), 1);
+        assert_eq!(candidate_count(&keys, Some("rsa-ps"), Algorithm::PS256), 1);
+        assert_eq!(candidate_count(&keys, Some("rsa-ps"), Algorithm::RS256), 0);
+        assert_eq!(candidate_count(&keys, Some("rsa-oaep"), Algorithm::RS256), 0);
+        assert_eq!(candidate_count(&keys, Some("rsa-enc"), Algorithm::RS256), 0);
+        assert_eq!(candidate_count(&keys, Some("ec"), Algorithm::ES256), 1);
+        assert_eq!(candidate_count(&keys, Some("shared"), Algorithm::HS256), 0);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn jwks_alg_must_match_the_key_type() {
+        let dir = temp_dir("jwks_mismatch");
+        let path = dir.join("jwks.json");
+        let jwks = serde_json::json!({ "keys": [
+            { "kty": "EC", "kid": "ec", "alg": "RS256", "crv": "P-256", "x": EC_X, "y": EC_Y },
+        ]});
+        fs::write(&path, jwks.to_string()).unwrap();
+        let mut config = jwt_config(&[Algorithm::RS256, Algorithm::ES256]);
+        config.jwks_file = Some(path);
+
+        let error = load_keys(&config).map(|_| ()).unwrap_err();
+        assert!(matches!(error, KeyError::InvalidKey(..)), "{}", error);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reload_picks_up_changed_files_and_keeps_keys_on_failure() {
+        let dir = temp_dir("reload");
+        fs::write(dir.join("old.pem"), RSA_PEM).unwrap();
+        let mut config = jwt_config(&[Algorithm::RS256, Algorithm::ES256]);
+        config.public_keys_dir = Some(dir.clone());
+        let store = KeyStore::load(&config).unwrap();
+
+        // 没有变化时不重新加载
+        let before = store.snapshot();
+        store.reload_if_changed();
+        assert!(Arc::ptr_eq(&before, &store.snapshot()));
+
+        // 轮换: 新增一个密钥
+        fs::write(dir.join("new.pem"), EC_PEM).unwrap();
+        store.reload_if_changed();
+        assert_eq!(candidate_count(&store.snapshot(), Some("new"), Algorithm::ES256), 1);
+        assert_eq!(candidate_count(&store.snapshot(), Some("old"), Algorithm::RS256), 1);
+
+        // 写了一半的文件: 保留之前的密钥, 修复后下次检查时加载
+        fs::write(dir.join("next.pem"), &RSA_PEM[..100]).unwrap();
+        store.reload_if_changed();
+        assert_eq!(store.snapshot().len(), 2);
+        fs::write(dir.join("next.pem"), RSA_PEM).unwrap();
+        store.reload_if_changed();
+        assert_eq!(candidate_count(&store.snapshot(), Some("next"), Algorithm::RS256), 1);
+
+        // 删除旧密钥
+        fs::remove_file(dir.join("old.pem")).unwrap();
+        store.reload_if_changed();
+        assert_eq!(candidate_count(&store.snapshot(), Some("old"), Algorithm::RS256), 0);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -1,21 +1,48 @@
 // 声明使用 server_core 模块
+mod backend;
+mod config;
+mod keys;
+mod proxy_protocol;
+mod routing;
 mod server_core;
 
+use config::ServerConfig;
+use log::{debug, error};
 use std::error::Error;
-use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::sync::Arc;
 use tokio::runtime::Runtime;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let listen_addr = "0.0.0.0:19132";
-    let backend_addr = "127.0.0.1:25565";
+fn main() -> ExitCode {
+    // 配置错误需要在日志初始化之前直接打印出来
+    let config = match ServerConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Server: configuration error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    let listen_addr: SocketAddr = listen_addr.parse()?;
-    let backend_addr: SocketAddr = backend_addr.parse()?;
+    // RUST_LOG 可以在配置的级别之上做更细粒度的覆盖
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .parse_default_env()
+        .init();
 
+    match run(config) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("Server: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(config: ServerConfig) -> Result<(), Box<dyn Error>> {
     let rt = Runtime::new()?;
-    println!("Server: Tokio runtime created.");
+    debug!("Server: Tokio runtime created.");
 
-    rt.block_on(server_core::run_server(listen_addr, backend_addr))?;
+    rt.block_on(server_core::run_server(Arc::new(config)))?;
 
     Ok(())
 }
@@ -0,0 +1,220 @@
+// HAProxy PROXY protocol 头部, 在后端连接的最前面告诉 Minecraft 服务器 (Velocity / BungeeCord / Paper)
+// 玩家的真实地址
+// 参考: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+// v2 头部的固定签名
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_COMMAND_PROXY: u8 = 0x21; // 版本 2, PROXY 命令
+const V2_COMMAND_LOCAL: u8 = 0x20; // 版本 2, LOCAL 命令 (代理自己发起的连接)
+const V2_UNSPEC: u8 = 0x00;
+const V2_TCP_OVER_IPV4: u8 = 0x11;
+const V2_TCP_OVER_IPV6: u8 = 0x21;
+
+// 自定义 TLV 类型 (0xE0..=0xEF 保留给应用使用)
+pub const TLV_SUBJECT: u8 = 0xe0; // JWT 的 sub
+pub const TLV_PLAYER_NAME: u8 = 0xe1; // JWT 中的玩家名
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    V1, // 文本格式, 不能携带 TLV
+    V2, // 二进制格式, 附带 TLV
+}
+
+impl fmt::Display for ProxyProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyProtocol::V1 => write!(f, "PROXY v1"),
+            ProxyProtocol::V2 => write!(f, "PROXY v2"),
+        }
+    }
+}
+
+impl ProxyProtocol {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "v1" => Some(ProxyProtocol::V1),
+            "v2" => Some(ProxyProtocol::V2),
+            _ => None,
+        }
+    }
+
+    // 构造头部; source 是玩家 (隧道对端) 的地址, destination 是玩家连接的中继地址
+    pub fn header(self, source: SocketAddr, destination: SocketAddr, tlvs: &[(u8, &[u8])]) -> Vec<u8> {
+        let (source, destination) = same_family(source, destination);
+        match self {
+            ProxyProtocol::V1 => v1_header(source, destination),
+            ProxyProtocol::V2 => v2_header(source, destination, tlvs),
+        }
+    }
+
+    // 代理自己发起的连接 (例如健康检查) 使用的头部, 后端会使用连接本身的地址
+    pub fn local_header(self) -> Vec<u8> {
+        match self {
+            ProxyProtocol::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+            ProxyProtocol::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                header.extend_from_slice(&[V2_COMMAND_LOCAL, V2_UNSPEC, 0, 0]);
+                header
+            }
+        }
+    }
+}
+
+fn v1_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+fn v2_header(source: SocketAddr, destination: SocketAddr, tlvs: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(36);
+    let family = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            body.extend_from_slice(&src.octets());
+            body.extend_from_slice(&dst.octets());
+            V2_TCP_OVER_IPV4
+        }
+        (src, dst) => {
+            body.extend_from_slice(&to_ipv6(src).octets());
+            body.extend_from_slice(&to_ipv6(dst).octets());
+            V2_TCP_OVER_IPV6
+        }
+    };
+    body.extend_from_slice(&source.port().to_be_bytes());
+    body.extend_from_slice(&destination.port().to_be_bytes());
+    for (kind, value) in tlvs {
+        // 头部的长度字段是 u16, 放不下的值直接截断, 连类型和长度都放不下时丢弃
+        let Some(room) = (u16::MAX as usize).checked_sub(body.len() + 3) else {
+            break;
+        };
+        let value = &value[..value.len().min(room)];
+        body.push(*kind);
+        body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        body.extend_from_slice(value);
+    }
+
+    let mut header = Vec::with_capacity(16 + body.len());
+    header.extend_from_slice(V2_SIGNATURE);
+    header.push(V2_COMMAND_PROXY);
+    header.push(family);
+    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    header.extend_from_slice(&body);
+    header
+}
+
+// 两个地址必须属于同一地址族, 不同时都转换为 IPv6 (IPv4 映射地址)
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    (
+        SocketAddr::new(IpAddr::V6(to_ipv6(source.ip())), source.port()),
+        SocketAddr::new(IpAddr::V6(to_ipv6(destination.ip())), destination.port()),
+    )
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYER: &str = "203.0.113.7:51234";
+    const RELAY: &str = "192.0.2.1:19132";
+
+    fn addr(value: &str) -> SocketAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn v1_header_is_a_text_line() {
+        let cases = [
+            (PLAYER, RELAY, "PROXY TCP4 203.0.113.7 192.0.2.1 51234 19132\r\n"),
+            (
+                "[2001:db8::7]:51234",
+                "[2001:db8::1]:19132",
+                "PROXY TCP6 2001:db8::7 2001:db8::1 51234 19132\r\n",
+            ),
+            // 地址族不同时都使用 IPv4 映射地址
+            (
+                PLAYER,
+                "[2001:db8::1]:19132",
+                "PROXY TCP6 ::ffff:203.0.113.7 2001:db8::1 51234 19132\r\n",
+            ),
+        ];
+        for (source, destination, expected) in cases {
+            let header = ProxyProtocol::V1.header(addr(source), addr(destination), &[]);
+            assert_eq!(String::from_utf8(header).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn v1_header_ignores_tlvs() {
+        let tlvs: [(u8, &[u8]); 1] = [(TLV_SUBJECT, b"player-1")];
+        let header = ProxyProtocol::V1.header(addr(PLAYER), addr(RELAY), &tlvs);
+        assert_eq!(header, b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 19132\r\n");
+    }
+
+    #[test]
+    fn v2_ipv4_header_carries_subject_and_player_name() {
+        let tlvs: [(u8, &[u8]); 2] = [(TLV_SUBJECT, b"player-1"), (TLV_PLAYER_NAME, b"Steve")];
+        let header = ProxyProtocol::V2.header(addr(PLAYER), addr(RELAY), &tlvs);
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 31]); // PROXY, TCP over IPv4, 长度
+        expected.extend_from_slice(&[203, 0, 113, 7, 192, 0, 2, 1]);
+        expected.extend_from_slice(&[0xc8, 0x22, 0x4a, 0xbc]); // 51234, 19132
+        expected.extend_from_slice(&[0xe0, 0x00, 0x08]);
+        expected.extend_from_slice(b"player-1");
+        expected.extend_from_slice(&[0xe1, 0x00, 0x05]);
+        expected.extend_from_slice(b"Steve");
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn v2_ipv6_header_uses_mapped_addresses_for_mixed_families() {
+        let header = ProxyProtocol::V2.header(addr(PLAYER), addr("[2001:db8::1]:19132"), &[]);
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x21, 0x00, 36]); // PROXY, TCP over IPv6, 长度
+        expected.extend_from_slice(&[0; 10]);
+        expected.extend_from_slice(&[0xff, 0xff, 203, 0, 113, 7]);
+        expected.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        expected.extend_from_slice(&[0; 11]);
+        expected.push(1);
+        expected.extend_from_slice(&[0xc8, 0x22, 0x4a, 0xbc]);
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn v2_tlvs_are_truncated_to_fit_the_length_field() {
+        let long = vec![b'a'; u16::MAX as usize];
+        let tlvs: [(u8, &[u8]); 2] = [(TLV_PLAYER_NAME, &long), (TLV_SUBJECT, b"player-1")];
+        let header = ProxyProtocol::V2.header(addr(PLAYER), addr(RELAY), &tlvs);
+        // 16 字节头部 + 12 字节地址, 之后是 TLV; 第二个 TLV 已经放不下
+        assert_eq!(&header[14..16], &[0xff, 0xff]);
+        assert_eq!(header.len(), 16 + u16::MAX as usize);
+        assert_eq!(&header[28..31], &[0xe1, 0xff, 0xf0]);
+    }
+
+    #[test]
+    fn local_headers_carry_no_addresses() {
+        assert_eq!(ProxyProtocol::V1.local_header(), b"PROXY UNKNOWN\r\n");
+        assert_eq!(
+            ProxyProtocol::V2.local_header(),
+            b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00"
+        );
+    }
+}
@@ -0,0 +1,239 @@
+// 按 Minecraft 握手包中的服务器地址选择后端
+// 匹配顺序: 完整主机名 > 通配符 (最长后缀优先) > 默认路由
+use crate::backend::BackendPool;
+use crate::proxy_protocol::ProxyProtocol;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+// 默认路由的名称, 令牌的 backends 声明中使用这个名称授权访问默认后端
+pub const DEFAULT_ROUTE_NAME: &str = "default";
+
+// 一组后端及其连接参数
+#[derive(Debug)]
+pub struct Route {
+    pub name: String, // 路由名, 与令牌中的 backends 声明对应
+    pub pool: BackendPool,
+    pub connect_timeout: Duration,
+    pub proxy_protocol: Option<ProxyProtocol>, // 连接后端时发送的 PROXY protocol 头部
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    routes: Vec<Arc<Route>>, // 按配置顺序排列的所有路由 (含默认路由)
+    exact: HashMap<String, Arc<Route>>,
+    wildcards: Vec<(String, Arc<Route>)>, // (".example.com", 路由), 按后缀长度从长到短排列
+    default: Option<Arc<Route>>,
+}
+
+impl RouteTable {
+    pub fn new() -> Self {
+        RouteTable::default()
+    }
+
+    // 添加一条路由; hosts 可以是完整主机名或 "*.example.com" 形式的通配符
+    pub fn add(&mut self, route: Route, hosts: &[String]) -> Result<(), String> {
+        if self.routes().any(|existing| existing.name == route.name) {
+            return Err(format!("route name '{}' is used more than once", route.name));
+        }
+        if hosts.is_empty() {
+            return Err(format!("route '{}' has no hosts", route.name));
+        }
+
+        let route = Arc::new(route);
+        self.routes.push(route.clone());
+        for host in hosts {
+            let host = normalize_host(host);
+            if let Some(domain) = host.strip_prefix("*.") {
+                validate_host(domain)?;
+                let suffix = format!(".{}", domain);
+                if self.wildcards.iter().any(|(existing, _)| *existing == suffix) {
+                    return Err(format!("host '{}' is routed more than once", host));
+                }
+                self.wildcards.push((suffix, route.clone()));
+            } else {
+                validate_host(&host)?;
+                if self.exact.contains_key(&host) {
+                    return Err(format!("host '{}' is routed more than once", host));
+                }
+                self.exact.insert(host, route.clone());
+            }
+        }
+        self.wildcards
+            .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Ok(())
+    }
+
+    // 设置未匹配任何主机名时使用的路由
+    pub fn set_default(&mut self, route: Route) -> Result<(), String> {
+        if self.routes().any(|existing| existing.name == route.name) {
+            return Err(format!("route name '{}' is used more than once", route.name));
+        }
+        let route = Arc::new(route);
+        self.routes.push(route.clone());
+        self.default = Some(route);
+        Ok(())
+    }
+
+    // 按主机名查找路由, 未匹配时返回默认路由 (如果有)
+    pub fn lookup(&self, host: &str) -> Option<&Arc<Route>> {
+        let host = normalize_host(host);
+        self.exact
+            .get(&host)
+            .or_else(|| {
+                self.wildcards
+                    .iter()
+                    .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+                    .map(|(_, route)| route)
+            })
+            .or(self.default.as_ref())
+    }
+
+    pub fn default_route(&self) -> Option<&Arc<Route>> {
+        self.default.as_ref()
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Arc<Route>> {
+        self.routes.iter()
+    }
+}
+
+// 主机名不区分大小写, 并忽略 Forge 等在 \0 之后附加的标记、端口和结尾的 '.' (完全限定域名)
+fn normalize_host(host: &str) -> String {
+    let host = host.split('\0').next().unwrap_or_default();
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            name
+        }
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn validate_host(host: &str) -> Result<(), String> {
+    let valid = !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("'{}' is not a valid host name", host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, Balance};
+
+    fn route(name: &str) -> Route {
+        Route {
+            name: name.to_string(),
+            pool: BackendPool::new(
+                vec![Backend::new("127.0.0.1:25565".parse().unwrap(), 1)],
+                Balance::LeastConnections,
+                None,
+            ),
+            connect_timeout: Duration::from_secs(5),
+            proxy_protocol: None,
+        }
+    }
+
+    fn table(with_default: bool) -> RouteTable {
+        let mut table = RouteTable::new();
+        let routes: [(&str, &[&str]); 4] = [
+            ("lobby", &["mc.example.com"]),
+            ("games", &["*.example.com"]),
+            ("eu-games", &["*.eu.example.com"]),
+            ("eu-lobby", &["lobby.eu.example.com"]),
+        ];
+        for (name, hosts) in routes {
+            let hosts: Vec<String> = hosts.iter().map(|host| host.to_string()).collect();
+            table.add(route(name), &hosts).unwrap();
+        }
+        if with_default {
+            table.set_default(route(DEFAULT_ROUTE_NAME)).unwrap();
+        }
+        table
+    }
+
+    #[test]
+    fn lookup_prefers_exact_then_longest_wildcard_then_default() {
+        let table = table(true);
+        let cases = [
+            ("mc.example.com", "lobby"),
+            ("lobby.eu.example.com", "eu-lobby"),
+            ("a.eu.example.com", "eu-games"),
+            ("eu.example.com", "games"),
+            ("a.b.example.com", "games"),
+            ("example.com", DEFAULT_ROUTE_NAME), // 通配符不匹配域名本身
+            ("other.net", DEFAULT_ROUTE_NAME),
+            ("", DEFAULT_ROUTE_NAME),
+        ];
+        for (host, expected) in cases {
+            assert_eq!(table.lookup(host).unwrap().name, expected, "{}", host);
+        }
+    }
+
+    #[test]
+    fn unmatched_hosts_have_no_route_without_a_default() {
+        let table = table(false);
+        assert!(table.lookup("other.net").is_none());
+        assert!(table.default_route().is_none());
+        assert_eq!(table.lookup("a.example.com").unwrap().name, "games");
+    }
+
+    #[test]
+    fn hosts_are_normalized_before_matching() {
+        let cases = [
+            ("MC.Example.COM", "mc.example.com"),
+            ("mc.example.com.", "mc.example.com"),
+            ("mc.example.com:25565", "mc.example.com"),
+            ("mc.example.com\0FML\0", "mc.example.com"),
+            ("MC.example.com.:25565\0FML3\0", "mc.example.com"),
+            ("mc.example.com:", "mc.example.com:"), // 没有端口号时不是端口
+        ];
+        for (host, expected) in cases {
+            assert_eq!(normalize_host(host), expected, "{:?}", host);
+        }
+
+        let table = table(true);
+        for host in ["MC.EXAMPLE.COM", "mc.example.com.", "mc.example.com:25565\0FML\0"] {
+            assert_eq!(table.lookup(host).unwrap().name, "lobby", "{:?}", host);
+        }
+        assert_eq!(table.lookup("A.EU.Example.com.").unwrap().name, "eu-games");
+    }
+
+    #[test]
+    fn invalid_or_duplicate_routes_are_rejected() {
+        let mut table = table(true);
+        let cases: [(&str, &[&str], &str); 5] = [
+            ("lobby", &["new.example.com"], "route name 'lobby' is used more than once"),
+            ("empty", &[], "route 'empty' has no hosts"),
+            ("again", &["MC.example.com."], "host 'mc.example.com' is routed more than once"),
+            ("wild", &["*.Example.com"], "host '*.example.com' is routed more than once"),
+            ("bad", &["bad host.com"], "'bad host.com' is not a valid host name"),
+        ];
+        for (name, hosts, error) in cases {
+            let hosts: Vec<String> = hosts.iter().map(|host| host.to_string()).collect();
+            assert_eq!(table.add(route(name), &hosts).unwrap_err(), error);
+        }
+        assert_eq!(
+            table.set_default(route(DEFAULT_ROUTE_NAME)).unwrap_err(),
+            "route name 'default' is used more than once"
+        );
+    }
+}
@@ -1,29 +1,210 @@
 // 声明这个模块需要使用外部 crates
-use futures::try_join;
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use crate::config::{AccessConfig, JwtConfig, ServerConfig, TransportConfig};
+use crate::keys::{KeyError, KeySet, KeyStore};
+use crate::proxy_protocol;
+use crate::routing::Route;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, Validation, decode, decode_header};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
-use std::net::SocketAddr;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio_kcp::{KcpConfig, KcpListener, KcpStream}; // 移除 UdpSocket，tokio-kcp 会处理
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{self, AsyncWriteExt};
+use tunnel::auth::{AuthResponse, AuthStatus};
+use tunnel::handshake::{
+    self, CAP_MUX, CAP_NOTICE, CAP_RESUME, ClientFrame, Hello, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SessionId,
+};
+use tunnel::minecraft::{self, Handshake, LEGACY_PING, NEXT_STATE_LOGIN};
+use tunnel::mux::{Heartbeat, MuxSession, Role};
+use tunnel::resume::{self, ResumableStream, ResumeRegistry};
+use tunnel::transport::kcp::KcpTunnelListener;
+use tunnel::transport::tcp::TcpTunnelListener;
+use tunnel::transport::{self, BoxedStream, PeerInfo, Tunnel, TunnelListener, quic, tls};
 
-// JWT声明结构体
+// 接受隧道失败后等待多久再重试
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// 所有连接共享的服务器状态
+struct ServerState {
+    config: Arc<ServerConfig>,
+    keys: Arc<KeyStore>,
+    active_sessions: AtomicUsize,
+    rejected_handshakes: AtomicU64, // 累计被拒绝的握手次数
+    resume: Arc<ResumeRegistry>,    // 可以恢复的隧道会话
+}
+
+impl ServerState {
+    // 加载 JWT 验证密钥; 密钥文件的变化由 run_server 启动的后台任务跟踪
+    fn new(config: Arc<ServerConfig>) -> Result<Arc<Self>, KeyError> {
+        Ok(Arc::new(ServerState {
+            keys: Arc::new(KeyStore::load(&config.jwt)?),
+            config,
+            active_sessions: AtomicUsize::new(0),
+            rejected_handshakes: AtomicU64::new(0),
+            resume: Arc::new(ResumeRegistry::new()),
+        }))
+    }
+}
+
+// 占用一个会话名额, drop 时归还
+struct SessionSlot {
+    state: Arc<ServerState>,
+}
+
+impl SessionSlot {
+    // max_sessions 为 0 表示不限制
+    fn acquire(state: &Arc<ServerState>) -> Option<SessionSlot> {
+        let max = state.config.access.max_sessions;
+        state
+            .active_sessions
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (max == 0 || active < max).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| SessionSlot {
+                state: state.clone(),
+            })
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.state.active_sessions.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// JWT声明结构体 (iss / aud / nbf 由 jsonwebtoken 校验, 这里不需要保存)
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     sub: String, // 主题 (通常是用户ID)
-    exp: usize,  // 过期时间 (Unix时间戳)
-                 // 可以根据需要添加更多字段
+    exp: u64,    // 过期时间 (Unix时间戳)
+    #[serde(default)]
+    jti: Option<String>, // 令牌ID, 用于吊销
+    #[serde(default)]
+    name: Option<String>, // 玩家名
+    #[serde(default)]
+    hwid: Option<String>, // 硬件ID
+    #[serde(default)]
+    backends: Vec<String>, // 允许访问的后端, 为空表示不限制
+}
+
+// 认证通过后的会话信息, 供后续的路由决策使用
+#[derive(Debug)]
+struct Session {
+    subject: String,
+    player_name: Option<String>,
+    hwid: Option<String>,
+    allowed_backends: Vec<String>,
+    expires_at: u64,
+    build_id: String,      // agent 的构建ID
+    protocol_version: u16, // 协商出的协议版本
+    capabilities: u32,     // 双方都支持的能力
+    resume_id: Option<SessionId>, // 启用 CAP_RESUME 时发给 agent 的会话ID
+}
+
+impl Session {
+    fn new(claims: Claims, hello: Hello, protocol_version: u16) -> Self {
+        Session {
+            subject: claims.sub,
+            player_name: claims.name,
+            hwid: claims.hwid,
+            allowed_backends: claims.backends,
+            expires_at: claims.exp,
+            build_id: hello.build_id,
+            protocol_version,
+            capabilities: hello.capabilities & handshake::SUPPORTED_CAPABILITIES,
+            resume_id: None,
+        }
+    }
 }
 
 // 定义JWT验证错误
 #[derive(Debug)]
 enum AuthError {
     InvalidToken,
+    InvalidSignature,
+    UnknownKey,
     TokenExpired,
+    TokenNotYetValid,
+    InvalidIssuer,
+    InvalidAudience,
+    MissingClaim(String),
+    Revoked,
+    Banned,
+    ServerFull,
+    VersionMismatch(u16, u16), // agent 支持的版本范围
+    HandshakeTimeout,
     IoError(io::Error),
 }
 
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::InvalidToken => write!(f, "Invalid JWT token"),
+            AuthError::InvalidSignature => write!(f, "JWT signature verification failed"),
+            AuthError::UnknownKey => write!(f, "JWT signed with an unknown key or algorithm"),
+            AuthError::TokenExpired => write!(f, "JWT token expired"),
+            AuthError::TokenNotYetValid => write!(f, "JWT token not valid yet (nbf)"),
+            AuthError::InvalidIssuer => write!(f, "JWT issuer not accepted"),
+            AuthError::InvalidAudience => write!(f, "JWT audience not accepted"),
+            AuthError::MissingClaim(claim) => write!(f, "JWT is missing claim '{}'", claim),
+            AuthError::Revoked => write!(f, "JWT token has been revoked"),
+            AuthError::Banned => write!(f, "user or hardware is banned"),
+            AuthError::ServerFull => write!(f, "session limit reached"),
+            AuthError::VersionMismatch(min, max) => write!(
+                f,
+                "agent protocol versions {}-{} are not supported (server supports {}-{})",
+                min, max, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            AuthError::HandshakeTimeout => write!(f, "handshake timed out"),
+            AuthError::IoError(e) => write!(f, "IO error during authentication: {}", e),
+        }
+    }
+}
+
+impl AuthError {
+    // 发送给 agent 的响应, 原因会显示给玩家, 所以不包含令牌校验的细节
+    fn response(&self) -> AuthResponse {
+        match self {
+            AuthError::TokenExpired => AuthResponse::reject(
+                AuthStatus::Expired,
+                "Your login has expired, please restart the game",
+            ),
+            AuthError::Revoked => AuthResponse::reject(
+                AuthStatus::Revoked,
+                "Your login has been revoked, please log in again",
+            ),
+            AuthError::Banned => {
+                AuthResponse::reject(AuthStatus::Banned, "You are banned from this server")
+            }
+            AuthError::ServerFull => AuthResponse::reject(
+                AuthStatus::ServerFull,
+                "The server is full, please try again later",
+            ),
+            AuthError::VersionMismatch(_, max) if *max < MIN_PROTOCOL_VERSION => {
+                AuthResponse::reject(
+                    AuthStatus::VersionMismatch,
+                    "Your client is outdated, please update it",
+                )
+            }
+            AuthError::VersionMismatch(..) => AuthResponse::reject(
+                AuthStatus::VersionMismatch,
+                "This server is older than your client, please try again later",
+            ),
+            AuthError::HandshakeTimeout | AuthError::IoError(_) => {
+                AuthResponse::reject(AuthStatus::InternalError, "Authentication failed")
+            }
+            _ => AuthResponse::reject(
+                AuthStatus::InvalidToken,
+                "Your login could not be verified, please restart the game",
+            ),
+        }
+    }
+}
+
 impl From<io::Error> for AuthError {
     fn from(err: io::Error) -> Self {
         AuthError::IoError(err)
@@ -31,124 +212,833 @@ impl From<io::Error> for AuthError {
 }
 
 impl From<jsonwebtoken::errors::Error> for AuthError {
-    fn from(_: jsonwebtoken::errors::Error) -> Self {
-        AuthError::InvalidToken
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.into_kind() {
+            ErrorKind::InvalidSignature => AuthError::InvalidSignature,
+            ErrorKind::ExpiredSignature => AuthError::TokenExpired,
+            ErrorKind::ImmatureSignature => AuthError::TokenNotYetValid,
+            ErrorKind::InvalidIssuer => AuthError::InvalidIssuer,
+            ErrorKind::InvalidAudience => AuthError::InvalidAudience,
+            ErrorKind::MissingRequiredClaim(claim) => AuthError::MissingClaim(claim),
+            _ => AuthError::InvalidToken,
+        }
     }
 }
 
-// 验证JWT令牌
-fn validate_token(token: &str) -> Result<Claims, AuthError> {
-    // 在实际应用中，这个密钥应该从安全的配置中获取
-    let secret = b"your_secret_key";
+// 按配置构造校验规则: exp 必须存在, nbf 存在时校验, 配置了 iss / aud 时它们也必须存在
+fn build_validation(alg: Algorithm, jwt: &JwtConfig) -> Validation {
+    let mut validation = Validation::new(alg);
+    validation.leeway = jwt.leeway;
+    validation.validate_nbf = true;
 
-    let validation = Validation::new(Algorithm::HS256);
-    let token_data = decode::<Claims>(token, &DecodingKey::from_secret(secret), &validation)?;
+    let mut required = vec!["exp"];
+    if !jwt.issuers.is_empty() {
+        validation.set_issuer(&jwt.issuers);
+        required.push("iss");
+    }
+    if jwt.audiences.is_empty() {
+        // 未配置受众时不校验 aud (jsonwebtoken 默认会拒绝所有带 aud 的令牌)
+        validation.validate_aud = false;
+    } else {
+        validation.set_audience(&jwt.audiences);
+        required.push("aud");
+    }
+    validation.set_required_spec_claims(&required);
+    validation
+}
+
+// 验证JWT令牌: 按头部的 kid / alg 选择密钥, 没有 kid 时依次尝试所有匹配算法的密钥
+fn validate_token(token: &str, keys: &KeySet, jwt: &JwtConfig) -> Result<Claims, AuthError> {
+    let header = decode_header(token)?;
+    let validation = build_validation(header.alg, jwt);
+
+    let mut last_error = None;
+    for entry in keys.candidates(header.kid.as_deref(), header.alg) {
+        match decode::<Claims>(token, &entry.key, &validation) {
+            Ok(token_data) => return Ok(token_data.claims),
+            Err(e) => last_error = Some(e),
+        }
+    }
 
-    Ok(token_data.claims)
+    // 没有可用的密钥: 未知 kid 或算法不被允许
+    Err(last_error.map_or(AuthError::UnknownKey, AuthError::from))
+}
+
+// 检查封禁和吊销名单
+fn check_access(claims: &Claims, access: &AccessConfig) -> Result<(), AuthError> {
+    if claims
+        .jti
+        .as_ref()
+        .is_some_and(|jti| access.revoked_token_ids.contains(jti))
+    {
+        return Err(AuthError::Revoked);
+    }
+    if access.banned_subjects.contains(&claims.sub)
+        || claims
+            .hwid
+            .as_ref()
+            .is_some_and(|hwid| access.banned_hwids.contains(hwid))
+    {
+        return Err(AuthError::Banned);
+    }
+    Ok(())
 }
 
-// 异步函数：处理单个 KCP 客户端连接和 TCP 后端连接之间的数据转发
-async fn handle_server_connection(mut client_stream: KcpStream, backend_addr: SocketAddr) {
-    // 首先读取并验证JWT令牌
-    let auth_result = authenticate_client(&mut client_stream).await;
+// 异步函数：处理单条隧道和 TCP 后端连接之间的数据转发
+async fn handle_server_connection(tunnel: Tunnel, state: Arc<ServerState>) {
+    let Tunnel {
+        stream: mut client_stream,
+        peer,
+    } = tunnel;
+
+    // 首先读取并验证JWT令牌, 整个握手必须在限定时间内完成
+    let deadline = tokio::time::Instant::now() + state.config.handshake.timeout;
+    let frame = match tokio::time::timeout_at(
+        deadline,
+        ClientFrame::read_from(&mut client_stream, state.config.handshake.max_token_size),
+    )
+    .await
+    {
+        Ok(result) => result.map_err(AuthError::from),
+        Err(_) => Err(AuthError::HandshakeTimeout),
+    };
+
+    let auth_result = match frame {
+        // 探测帧只用于 agent 测量往返时间, 回复后关闭连接
+        Ok(ClientFrame::Probe(nonce)) => {
+            let reply = handshake::write_probe_reply(&mut client_stream, nonce);
+            if let Ok(Ok(())) = tokio::time::timeout_at(deadline, reply).await {
+                debug!("Server: Answered latency probe from {}", peer);
+            }
+            return;
+        }
+        // 恢复帧: 把新连接交给原来的会话, 由会话继续转发; 同样要在握手时限内完成
+        Ok(ClientFrame::Resume(request)) => {
+            match tokio::time::timeout_at(deadline, state.resume.resume(client_stream, request))
+                .await
+            {
+                Ok(Ok(true)) => info!("Server: Resuming tunnel session from {}", peer),
+                Ok(Ok(false)) => record_rejection(&state, peer, &"unknown tunnel session"),
+                Ok(Err(e)) => record_rejection(&state, peer, &AuthError::IoError(e)),
+                Err(_) => record_rejection(&state, peer, &AuthError::HandshakeTimeout),
+            }
+            return;
+        }
+        Ok(ClientFrame::Hello(hello)) => {
+            match tokio::time::timeout_at(
+                deadline,
+                authenticate_client(&mut client_stream, hello, &state),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(AuthError::HandshakeTimeout),
+            }
+        }
+        Err(e) => Err(e),
+    };
 
     match auth_result {
-        Ok(claims) => {
-            println!("Server: Authentication successful for user: {}", claims.sub);
-            // 继续处理连接
-            process_connection(client_stream, backend_addr).await;
+        Ok((session, _slot)) => {
+            info!(
+                "Server: Authentication successful for user: {} (player {:?}, hwid {:?}, backends {:?}, expires at {}, agent {}, protocol v{}, capabilities {:#x})",
+                session.subject,
+                session.player_name,
+                session.hwid,
+                session.allowed_backends,
+                session.expires_at,
+                session.build_id,
+                session.protocol_version,
+                session.capabilities
+            );
+            // 继续处理连接, 会话名额在隧道关闭时归还
+            // 可恢复的会话在底层连接断开后等待 agent 重新连接, 期间后端连接保持不变;
+            // 复用会话的心跳保证连接上一直有数据, 这时才能按超时判断连接是否断开
+            let client_stream: BoxedStream = match session.resume_id {
+                Some(id) => Box::new(ResumableStream::server(
+                    client_stream,
+                    id,
+                    &state.resume,
+                    (session.capabilities & CAP_MUX != 0).then_some(state.config.heartbeat.timeout),
+                )),
+                None => client_stream,
+            };
+            let session = Arc::new(session);
+            if session.capabilities & CAP_MUX != 0 {
+                run_mux_session(client_stream, peer, session, &state).await;
+            } else {
+                process_connection(client_stream, peer, session, state).await;
+            }
         }
         Err(e) => {
-            match e {
-                AuthError::InvalidToken => eprintln!("Server: Invalid JWT token"),
-                AuthError::TokenExpired => eprintln!("Server: JWT token expired"),
-                AuthError::IoError(e) => eprintln!("Server: IO error during authentication: {}", e),
+            record_rejection(&state, peer, &e);
+            // 连接本身出错或对端无响应时不再尝试回复
+            if !matches!(e, AuthError::IoError(_) | AuthError::HandshakeTimeout)
+                && let Err(e) = e.response().write_to(&mut client_stream).await
+            {
+                warn!("Server: Failed to send authentication response: {}", e);
             }
             // 不处理连接，函数返回后连接会被关闭
         }
     }
 }
 
+// 记录一次被拒绝的握手 (hello 或恢复请求)
+fn record_rejection(state: &ServerState, peer: PeerInfo, reason: &dyn fmt::Display) {
+    let rejected = state.rejected_handshakes.fetch_add(1, Ordering::Relaxed) + 1;
+    warn!(
+        "Server: Rejected handshake from {}: {} ({} rejected so far)",
+        peer, reason, rejected
+    );
+}
+
 // 验证客户端身份
-async fn authenticate_client(client_stream: &mut KcpStream) -> Result<Claims, AuthError> {
-    // 读取JWT令牌长度 (4字节)
-    let mut len_bytes = [0u8; 4];
-    client_stream.read_exact(&mut len_bytes).await?;
-    let token_len = u32::from_be_bytes(len_bytes) as usize;
+// hello 帧包含魔数、协议版本范围、构建ID、能力和JWT令牌
+async fn authenticate_client(
+    client_stream: &mut BoxedStream,
+    hello: Hello,
+    state: &Arc<ServerState>,
+) -> Result<(Session, SessionSlot), AuthError> {
+    // 协商协议版本, 先于令牌校验, 这样旧版本 agent 能得到明确的提示
+    let version = handshake::negotiate_version(hello.min_version, hello.max_version).ok_or(
+        AuthError::VersionMismatch(hello.min_version, hello.max_version),
+    )?;
 
-    // 读取JWT令牌
-    let mut token_bytes = vec![0u8; token_len];
-    client_stream.read_exact(&mut token_bytes).await?;
+    // 验证令牌
+    let claims = validate_token(&hello.token, &state.keys.snapshot(), &state.config.jwt)?;
+    check_access(&claims, &state.config.access)?;
+    let slot = SessionSlot::acquire(state).ok_or(AuthError::ServerFull)?;
+    let mut session = Session::new(claims, hello, version);
+    if session.capabilities & CAP_RESUME != 0 {
+        session.resume_id = Some(resume::new_session_id()?);
+    }
 
-    // 转换为字符串
-    let token = String::from_utf8(token_bytes).map_err(|_| AuthError::InvalidToken)?;
+    // 发送验证成功响应, 之后是会话ID
+    AuthResponse::ok(session.protocol_version, session.capabilities)
+        .write_to(client_stream)
+        .await?;
+    if let Some(id) = &session.resume_id {
+        handshake::write_session_id(client_stream, id).await?;
+    }
 
-    // 验证令牌
-    let claims = validate_token(&token)?;
+    Ok((session, slot))
+}
 
-    // 发送验证成功响应
-    client_stream.write_all(&[1u8]).await?;
+// 复用会话: agent 的每个游戏连接是隧道上的一个流, 各自转发到后端
+async fn run_mux_session(
+    client_stream: BoxedStream,
+    peer: PeerInfo,
+    session: Arc<Session>,
+    state: &Arc<ServerState>,
+) {
+    // 可恢复的会话由 resume 模块检测断开, 心跳只用于测量往返时间
+    let heartbeat = Heartbeat {
+        interval: state.config.heartbeat.interval,
+        timeout: session
+            .resume_id
+            .is_none()
+            .then_some(state.config.heartbeat.timeout),
+    };
+    let mux = MuxSession::new(client_stream, Role::Server, heartbeat);
+    info!(
+        "Server: Tunnel session opened for {} from {}",
+        session.subject, peer
+    );
+    if session.capabilities & CAP_NOTICE != 0
+        && let Some(notice) = &state.config.notice
+        && let Err(e) = mux.notify(notice).await
+    {
+        warn!("Server: Failed to send notice to {}: {}", session.subject, e);
+    }
+
+    while let Some(stream) = mux.accept().await {
+        info!(
+            "Server: {} opened stream {} ({} active)",
+            session.subject,
+            stream.id(),
+            mux.stream_count()
+        );
+        tokio::spawn(process_connection(
+            Box::new(stream),
+            peer,
+            session.clone(),
+            state.clone(),
+        ));
+    }
+
+    let reason = mux.closed().await;
+    info!(
+        "Server: Tunnel session for {} closed: {} (last rtt {})",
+        session.subject,
+        reason,
+        mux.rtt()
+            .map_or_else(|| "unknown".to_string(), |rtt| format!("{} ms", rtt.as_millis()))
+    );
+}
 
-    Ok(claims)
+// 游戏连接无法路由到后端的原因
+#[derive(Debug)]
+enum RouteError {
+    UnknownHost(String),
+    NotAllowed(String), // 令牌的 backends 声明不包含这个路由
+    Unavailable(io::Error),
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::UnknownHost(host) => write!(f, "no route for host '{}'", host),
+            RouteError::NotAllowed(route) => write!(f, "token does not allow route '{}'", route),
+            RouteError::Unavailable(e) => write!(f, "backend is unavailable: {}", e),
+        }
+    }
 }
 
-// 处理已验证的连接
-async fn process_connection(client_stream: KcpStream, backend_addr: SocketAddr) {
-    // 连接到后端服务 (仍然使用 TCP)
-    let mut backend_stream = match TcpStream::connect(&backend_addr).await {
-        Ok(stream) => stream,
+impl RouteError {
+    // 显示在游戏断开界面上的原因
+    fn player_reason(&self) -> &'static str {
+        match self {
+            RouteError::UnknownHost(_) => "Unknown server address",
+            RouteError::NotAllowed(_) => "You do not have access to this server",
+            RouteError::Unavailable(_) => "The server is currently unavailable, please try again later",
+        }
+    }
+}
+
+// 按握手包中的主机名选择路由, 并检查令牌是否允许访问; 旧版本的列表查询使用默认路由
+fn select_route(
+    state: &ServerState,
+    session: &Session,
+    handshake: Option<&Handshake>,
+) -> Result<Arc<Route>, RouteError> {
+    let routes = &state.config.routes;
+    let route = match handshake {
+        Some(handshake) => routes
+            .lookup(handshake.host())
+            .ok_or_else(|| RouteError::UnknownHost(handshake.host().to_string()))?,
+        None => routes
+            .default_route()
+            .ok_or_else(|| RouteError::UnknownHost(String::new()))?,
+    };
+    if !session.allowed_backends.is_empty() && !session.allowed_backends.contains(&route.name) {
+        return Err(RouteError::NotAllowed(route.name.clone()));
+    }
+    Ok(route.clone())
+}
+
+// 玩家正在登录时回复断开数据包, 让游戏显示原因
+async fn disconnect_game(client_stream: &mut BoxedStream, handshake: Option<&Handshake>, reason: &str) {
+    if handshake.is_some_and(|handshake| handshake.next_state == NEXT_STATE_LOGIN) {
+        let _ = client_stream
+            .write_all(&minecraft::login_disconnect_packet(reason))
+            .await;
+        let _ = client_stream.shutdown().await;
+    }
+}
+
+// 后端 PROXY protocol 头部: 源地址是玩家的真实地址, 目标地址是 agent 连接的中继地址;
+// 传输无法提供隧道的本端地址时 (例如 KCP) 使用监听地址
+// v2 头部附带 JWT 的 sub 和玩家名, 后端插件可以据此执行封禁
+fn proxy_header(
+    version: proxy_protocol::ProxyProtocol,
+    peer: PeerInfo,
+    session: &Session,
+    state: &ServerState,
+) -> Vec<u8> {
+    let mut tlvs = vec![(proxy_protocol::TLV_SUBJECT, session.subject.as_bytes())];
+    if let Some(name) = &session.player_name {
+        tlvs.push((proxy_protocol::TLV_PLAYER_NAME, name.as_bytes()));
+    }
+    version.header(peer.addr, peer.local_addr.unwrap_or(state.config.listen_addr), &tlvs)
+}
+
+// 处理已验证的连接: 读取握手包, 路由到后端后转发
+async fn process_connection(
+    mut client_stream: BoxedStream,
+    peer: PeerInfo, // 隧道对端, 即玩家的真实地址
+    session: Arc<Session>,
+    state: Arc<ServerState>,
+) {
+    let handshake = match tokio::time::timeout(
+        state.config.handshake.timeout,
+        minecraft::read_handshake(&mut client_stream),
+    )
+    .await
+    {
+        Ok(Ok(handshake)) => handshake,
+        Ok(Err(e)) => {
+            warn!("Server: Invalid game handshake from {}: {}", session.subject, e);
+            return;
+        }
+        Err(_) => {
+            warn!("Server: Game handshake from {} timed out", session.subject);
+            return;
+        }
+    };
+
+    let route = match select_route(&state, &session, handshake.as_ref()) {
+        Ok(route) => route,
         Err(e) => {
-            eprintln!("Failed to connect to backend {}: {}", backend_addr, e);
-            // KcpStream 可能没有 shutdown，需要查阅 API，这里假设它会自动清理
+            warn!("Server: Cannot route connection for {}: {}", session.subject, e);
+            disconnect_game(&mut client_stream, handshake.as_ref(), e.player_reason()).await;
             return;
         }
     };
-    println!("Server: Connected to backend {} via TCP", backend_addr);
 
-    // 分割 KCP 客户端连接和 TCP 后端连接流为读写半部分
-    // 假设 KcpStream 实现了 AsyncRead/AsyncWrite，可以使用 io::split
-    let (mut client_read, mut client_write) = io::split(client_stream); // tokio_kcp::KcpStream 支持 split
-    let (mut backend_read, mut backend_write) = backend_stream.split();
+    // 连接到后端服务 (仍然使用 TCP), 失败时依次尝试池中的其他后端
+    // 连接计数在转发结束 (backend_slot 被 drop) 时归还
+    let (mut backend_stream, backend_slot) = match route.pool.connect(route.connect_timeout).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            error!("Failed to connect to any backend of route {}: {}", route, e);
+            let e = RouteError::Unavailable(e);
+            disconnect_game(&mut client_stream, handshake.as_ref(), e.player_reason()).await;
+            return;
+        }
+    };
+    info!(
+        "Server: Connected to backend {} (route {}) via TCP for {}",
+        backend_slot.backend().addr,
+        route,
+        session.subject
+    );
 
-    // 创建两个 futures：一个从 KCP 客户端读并写到 TCP 后端，另一个从 TCP 后端读并写到 KCP 客户端
-    let client_to_backend = io::copy(&mut client_read, &mut backend_write);
-    let backend_to_client = io::copy(&mut backend_read, &mut client_write);
+    // 先发送 PROXY protocol 头部 (如果配置了), 再转发已经读取的握手包, 之后的数据原样转发
+    let mut first_packet = match route.proxy_protocol {
+        Some(version) => proxy_header(version, peer, &session, &state),
+        None => Vec::new(),
+    };
+    match &handshake {
+        Some(handshake) => first_packet.extend_from_slice(&handshake.encode()),
+        None => first_packet.push(LEGACY_PING),
+    }
+    if let Err(e) = backend_stream.write_all(&first_packet).await {
+        error!("Failed to forward handshake to backend {}: {}", route, e);
+        return;
+    }
 
-    // 并发地运行这两个 futures
-    match try_join!(client_to_backend, backend_to_client) {
+    // 双向转发, 直到两个方向都结束
+    match transport::relay(&mut client_stream, &mut backend_stream).await {
         Ok((client_bytes, backend_bytes)) => {
-            println!(
-                "Server: KCP Connection closed. {} bytes client->backend, {} bytes backend->client",
+            info!(
+                "Server: Tunnel connection closed. {} bytes client->backend, {} bytes backend->client",
                 client_bytes, backend_bytes
             );
         }
-        Err(e) => {
-            eprintln!("Server: KCP Connection closed with error: {}", e);
+        Err(reason) => {
+            warn!("Server: Tunnel connection closed with error: {}", reason);
         }
     }
 }
 
-// 公共异步函数：运行服务器的主要监听循环 (监听 KCP)
+// 公共异步函数：运行服务器的主要监听循环 (按配置监听 KCP、QUIC、TCP 或 TLS)
 // 这个函数将在 main.rs 中由运行时调用
 pub async fn run_server(
-    listen_addr: SocketAddr,  // 服务器监听地址 (用于 KCP)
-    backend_addr: SocketAddr, // 后端服务地址 (用于 TCP)
+    config: Arc<ServerConfig>, // 监听地址、传输协议、路由表 (TCP 后端)、密钥等
 ) -> Result<(), Box<dyn Error>> {
-    println!("Server listening on {} (KCP)", listen_addr);
-    println!("Forwarding to backend {} (TCP)", backend_addr);
+    for route in config.routes.routes() {
+        let backends: Vec<String> = route
+            .pool
+            .backends()
+            .iter()
+            .map(|backend| format!("{} (weight {})", backend.addr, backend.weight))
+            .collect();
+        info!(
+            "Forwarding route {} to {} (TCP, {:?}, connect timeout {:?}{})",
+            route.name,
+            backends.join(", "),
+            route.pool.balance(),
+            route.connect_timeout,
+            route
+                .proxy_protocol
+                .map(|version| format!(", {}", version))
+                .unwrap_or_default()
+        );
+        route.pool.spawn_health_checks(route.proxy_protocol);
+    }
+
+    // 加载 JWT 验证密钥, 之后由后台任务跟踪密钥文件的变化
+    let state = ServerState::new(config.clone())?;
+    state.keys.clone().spawn_reload_task();
 
-    // 创建 KCP 监听器
-    // tokio-kcp 的 bind 会自动处理 UDP socket
-    let mut listener = KcpListener::bind(KcpConfig::default(), listen_addr).await?;
+    // 按配置创建隧道监听器
+    let listener: Box<dyn TunnelListener> = match &config.transport {
+        TransportConfig::Kcp(kcp) => Box::new(KcpTunnelListener::bind(*kcp, config.listen_addr).await?),
+        TransportConfig::Quic(quic) => Box::new(quic::listen(
+            config.listen_addr,
+            &quic.cert_file,
+            &quic.key_file,
+            &quic.options,
+        )?),
+        TransportConfig::Tcp => Box::new(TcpTunnelListener::bind(config.listen_addr).await?),
+        TransportConfig::Tls(tls) => {
+            Box::new(tls::listen(config.listen_addr, &tls.cert_file, &tls.key_file).await?)
+        }
+    };
+    info!(
+        "Server listening on {} ({})",
+        listener.local_addr()?,
+        transport_name(&config.transport)
+    );
 
-    // 循环接受新的 KCP 连接
+    serve(listener, state).await;
+    Ok(())
+}
+
+// 循环接受新的隧道, 每条隧道在单独的任务中认证和转发
+async fn serve(mut listener: Box<dyn TunnelListener>, state: Arc<ServerState>) {
     loop {
-        // accept 是一个异步操作，等待传入的 KCP 连接
-        // 返回的可能是 (KcpStream, SocketAddr)，具体取决于 kcp crate API
-        let (client_stream, client_addr) = listener.accept().await?;
-        println!("Server: Accepted KCP connection from {}", client_addr);
+        let tunnel = match listener.accept().await {
+            Ok(tunnel) => tunnel,
+            Err(e) => {
+                // 通常是文件描述符耗尽或连接在握手前被重置, 稍后重试而不是停止服务器
+                error!("Server: Failed to accept a tunnel: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        info!("Server: Accepted tunnel from {}", tunnel.peer);
+
+        // 为每条隧道 spawn 一个异步任务
+        tokio::spawn(handle_server_connection(tunnel, state.clone()));
+    }
+}
+
+fn transport_name(transport: &TransportConfig) -> &'static str {
+    match transport {
+        TransportConfig::Kcp(_) => "KCP",
+        TransportConfig::Quic(_) => "QUIC",
+        TransportConfig::Tcp => "TCP",
+        TransportConfig::Tls(_) => "TLS",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Cli;
+    use clap::Parser;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use std::net::SocketAddr;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tunnel::handshake::{PROTOCOL_VERSION, ResumeRequest};
+    use tunnel::transport::Transport;
+    use tunnel::transport::memory::{self, MemoryTransport};
+
+    const SECRET: &str = "test-secret";
+
+    // 测试不应该等待这么久, 超时说明卡住了
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // 只配置共享密钥和默认后端, 其余使用默认值
+    fn config(backend: SocketAddr) -> ServerConfig {
+        let backend = backend.to_string();
+        let cli = Cli::try_parse_from(["server", "--jwt-secret", SECRET, "--backend", &backend])
+            .unwrap();
+        ServerConfig::from_cli(cli).unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            sub: "player-1".to_string(),
+            exp: now() + 3600,
+            jti: Some("token-1".to_string()),
+            name: Some("Steve".to_string()),
+            hwid: None,
+            backends: Vec::new(),
+        }
+    }
+
+    fn token(claims: &Claims, secret: &str) -> String {
+        encode(&Header::default(), claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    // 在内存传输上运行真正的 accept 循环
+    fn start(config: ServerConfig) -> (MemoryTransport, Arc<ServerState>) {
+        let (transport, listener) = memory::pair(SocketAddr::from(([127, 0, 0, 1], 25565)));
+        let state = ServerState::new(Arc::new(config)).unwrap();
+        tokio::spawn(serve(Box::new(listener), state.clone()));
+        (transport, state)
+    }
+
+    async fn handshake_with(transport: &MemoryTransport, hello: &Hello) -> (BoxedStream, AuthResponse) {
+        let mut tunnel = transport.connect().await.unwrap();
+        let response = tokio::time::timeout(
+            TEST_TIMEOUT,
+            handshake::client_handshake(&mut tunnel.stream, hello),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        (tunnel.stream, response)
+    }
+
+    async fn wait_for_sessions(state: &ServerState, count: usize) {
+        tokio::time::timeout(TEST_TIMEOUT, async {
+            while state.active_sessions.load(Ordering::Acquire) != count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    fn unused_backend() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 9))
+    }
+
+    #[tokio::test]
+    async fn valid_token_is_accepted_with_common_capabilities() {
+        let (transport, state) = start(config(unused_backend()));
+        let mut hello = Hello::new("test", &token(&claims(), SECRET));
+        hello.capabilities = CAP_MUX | 1 << 31; // 服务器不认识的能力不会被启用
+        let (stream, response) = handshake_with(&transport, &hello).await;
+        assert!(response.is_ok(), "{:?}", response);
+        assert_eq!(response.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(response.capabilities, CAP_MUX);
+        wait_for_sessions(&state, 1).await;
+
+        // 隧道关闭后归还会话名额
+        drop(stream);
+        wait_for_sessions(&state, 0).await;
+        assert_eq!(state.rejected_handshakes.load(Ordering::Relaxed), 0);
+    }
+
+    // 一种应当被拒绝的 hello 和 agent 收到的响应
+    struct Rejection {
+        name: &'static str,
+        token: String,
+        configure: fn(&mut ServerConfig),
+        versions: (u16, u16),
+        status: AuthStatus,
+        reason: &'static str,
+    }
+
+    #[tokio::test]
+    async fn invalid_hellos_are_rejected_with_a_reason() {
+        let valid = token(&claims(), SECRET);
+        let expired = Claims {
+            exp: now() - 3600,
+            ..claims()
+        };
+        let current = (1, PROTOCOL_VERSION);
+        let cases = [
+            Rejection {
+                name: "expired",
+                token: token(&expired, SECRET),
+                configure: |_| {},
+                versions: current,
+                status: AuthStatus::Expired,
+                reason: "Your login has expired, please restart the game",
+            },
+            Rejection {
+                name: "wrong secret",
+                token: token(&claims(), "another-secret"),
+                configure: |_| {},
+                versions: current,
+                status: AuthStatus::InvalidToken,
+                reason: "Your login could not be verified, please restart the game",
+            },
+            Rejection {
+                name: "revoked",
+                token: valid.clone(),
+                configure: |config| {
+                    config.access.revoked_token_ids.insert("token-1".to_string());
+                },
+                versions: current,
+                status: AuthStatus::Revoked,
+                reason: "Your login has been revoked, please log in again",
+            },
+            Rejection {
+                name: "banned",
+                token: valid.clone(),
+                configure: |config| {
+                    config.access.banned_subjects.insert("player-1".to_string());
+                },
+                versions: current,
+                status: AuthStatus::Banned,
+                reason: "You are banned from this server",
+            },
+            Rejection {
+                name: "outdated agent",
+                token: valid.clone(),
+                configure: |_| {},
+                versions: (0, 0),
+                status: AuthStatus::VersionMismatch,
+                reason: "Your client is outdated, please update it",
+            },
+            Rejection {
+                name: "newer agent",
+                token: valid,
+                configure: |_| {},
+                versions: (PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2),
+                status: AuthStatus::VersionMismatch,
+                reason: "This server is older than your client, please try again later",
+            },
+        ];
+        for case in cases {
+            let mut config = config(unused_backend());
+            (case.configure)(&mut config);
+            let (transport, state) = start(config);
+            let mut hello = Hello::new("test", &case.token);
+            (hello.min_version, hello.max_version) = case.versions;
+            let (_stream, response) = handshake_with(&transport, &hello).await;
+            assert_eq!(response.status, case.status, "{}", case.name);
+            assert_eq!(response.reason, case.reason, "{}", case.name);
+            assert_eq!(response.capabilities, 0, "{}", case.name);
+            assert_eq!(state.rejected_handshakes.load(Ordering::Relaxed), 1, "{}", case.name);
+            assert_eq!(state.active_sessions.load(Ordering::Acquire), 0, "{}", case.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_above_the_limit_are_rejected_until_a_slot_is_free() {
+        let mut config = config(unused_backend());
+        config.access.max_sessions = 1;
+        let (transport, state) = start(config);
+        let mut hello = Hello::new("test", &token(&claims(), SECRET));
+        hello.capabilities = CAP_MUX;
+
+        let (first, response) = handshake_with(&transport, &hello).await;
+        assert!(response.is_ok());
+        let (_second, response) = handshake_with(&transport, &hello).await;
+        assert_eq!(response.status, AuthStatus::ServerFull);
+        assert_eq!(response.reason, "The server is full, please try again later");
+
+        drop(first);
+        wait_for_sessions(&state, 0).await;
+        let (_third, response) = handshake_with(&transport, &hello).await;
+        assert!(response.is_ok());
+    }
+
+    #[test]
+    fn proxy_header_uses_the_address_the_agent_connected_to() {
+        let state = ServerState::new(Arc::new(config(unused_backend()))).unwrap();
+        let session = Session::new(claims(), Hello::new("test", ""), PROTOCOL_VERSION);
+        let player = SocketAddr::from(([203, 0, 113, 7], 51234));
+        let mut peer = PeerInfo {
+            addr: player,
+            protocol: "test",
+            local_addr: Some(SocketAddr::from(([192, 0, 2, 1], 19132))),
+        };
+        let header = proxy_header(proxy_protocol::ProxyProtocol::V1, peer, &session, &state);
+        assert_eq!(header, b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 19132\r\n");
+
+        // 传输不知道本端地址时使用监听地址
+        peer.local_addr = None;
+        let header = proxy_header(proxy_protocol::ProxyProtocol::V1, peer, &session, &state);
+        let expected = format!(
+            "PROXY TCP4 203.0.113.7 {} 51234 {}\r\n",
+            state.config.listen_addr.ip(),
+            state.config.listen_addr.port()
+        );
+        assert_eq!(header, expected.as_bytes());
+    }
+
+    async fn resume_with(transport: &MemoryTransport, session_id: SessionId) -> Option<u64> {
+        let mut tunnel = transport.connect().await.unwrap();
+        let request = ResumeRequest {
+            session_id,
+            received: 0,
+        };
+        tokio::time::timeout(TEST_TIMEOUT, handshake::client_resume(&mut tunnel.stream, &request))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn resume_requests_are_handed_to_live_sessions_only() {
+        let (transport, state) = start(config(unused_backend()));
+        let hello = Hello::new("test", &token(&claims(), SECRET));
+        let (mut stream, response) = handshake_with(&transport, &hello).await;
+        assert_ne!(response.capabilities & CAP_RESUME, 0);
+        let id = handshake::read_session_id(&mut stream).await.unwrap();
+
+        assert_eq!(resume_with(&transport, id).await, Some(0));
+        assert_eq!(state.rejected_handshakes.load(Ordering::Relaxed), 0);
+
+        // 未知的会话按被拒绝的握手计数
+        assert_eq!(resume_with(&transport, resume::new_session_id().unwrap()).await, None);
+        tokio::time::timeout(TEST_TIMEOUT, async {
+            while state.rejected_handshakes.load(Ordering::Relaxed) != 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    // 后端读取握手包后把之后收到的数据原样发回
+    async fn echo_backend() -> (SocketAddr, tokio::task::JoinHandle<Handshake>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let backend = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let handshake = minecraft::read_handshake(&mut stream).await.unwrap().unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            handshake
+        });
+        (addr, backend)
+    }
+
+    fn game_handshake() -> Handshake {
+        Handshake {
+            protocol_version: 767,
+            server_address: "mc.example.com".to_string(),
+            server_port: 25565,
+            next_state: NEXT_STATE_LOGIN,
+        }
+    }
+
+    // 发送握手包和数据, 读回后端的回显
+    async fn exchange<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(stream: &mut S) {
+        stream.write_all(&game_handshake().encode()).await.unwrap();
+        stream.write_all(b"login start").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"login start");
+    }
+
+    #[tokio::test]
+    async fn connections_are_forwarded_to_the_backend() {
+        let (backend_addr, backend) = echo_backend().await;
+        let (transport, _state) = start(config(backend_addr));
+        let mut hello = Hello::new("test", &token(&claims(), SECRET));
+        hello.capabilities = 0; // 旧版本 agent: 每个游戏连接一条隧道
+        let (mut stream, response) = handshake_with(&transport, &hello).await;
+        assert!(response.is_ok());
+        assert_eq!(response.capabilities, 0);
+
+        tokio::time::timeout(TEST_TIMEOUT, exchange(&mut stream)).await.unwrap();
+        assert_eq!(backend.await.unwrap(), game_handshake());
+    }
+
+    #[tokio::test]
+    async fn mux_streams_are_forwarded_to_the_backend() {
+        let (backend_addr, backend) = echo_backend().await;
+        let (transport, _state) = start(config(backend_addr));
+        let mut hello = Hello::new("test", &token(&claims(), SECRET));
+        hello.capabilities = CAP_MUX;
+        let (stream, response) = handshake_with(&transport, &hello).await;
+        assert_eq!(response.capabilities, CAP_MUX);
 
-        // 为每个新的 KCP 客户端连接 spawn 一个异步任务
-        tokio::spawn(handle_server_connection(client_stream, backend_addr));
+        let heartbeat = Heartbeat {
+            interval: Duration::from_secs(1),
+            timeout: None,
+        };
+        let mux = MuxSession::new(stream, Role::Client, heartbeat);
+        let mut stream = mux.open().await.unwrap();
+        tokio::time::timeout(TEST_TIMEOUT, exchange(&mut stream)).await.unwrap();
+        assert_eq!(backend.await.unwrap(), game_handshake());
+        mux.close();
     }
 }
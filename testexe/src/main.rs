@@ -1,58 +1,56 @@
-use std::mem::transmute;
-use tokio::io::AsyncWriteExt;
-use tokio::net::windows::named_pipe::ClientOptions;
+use ipc::endpoint::Credentials;
 use tokio::runtime::Runtime;
-use windows::Win32::Foundation::FARPROC;
-use windows::Win32::Foundation::FreeLibrary;
-use windows::Win32::Foundation::HMODULE;
-use windows::Win32::System::LibraryLoader::{GetProcAddress, LoadLibraryW};
-use windows::core::PCWSTR;
-use windows::core::s;
-use windows::core::w;
+#[cfg(windows)]
+use std::mem::transmute;
+#[cfg(windows)]
+use windows::{
+    Win32::Foundation::{FARPROC, FreeLibrary, HMODULE},
+    Win32::System::LibraryLoader::{GetProcAddress, LoadLibraryW},
+    core::{PCWSTR, s, w},
+};
 
 use once_cell::sync::OnceCell;
 
 static NETWORK_PORT: OnceCell<u16> = OnceCell::new();
 static FORWARD_PORT: OnceCell<u16> = OnceCell::new();
-const PIPE_NAME: &str = r"\\.\pipe\novoline893";
-async fn aaaaaa() {
-    let mut client = loop {
-        match ClientOptions::new().open(PIPE_NAME) {
+async fn aaaaaa(credentials: &Credentials) {
+    let (mut receiver, sender) = loop {
+        match ipc::endpoint::connect(credentials).await {
             Ok( temp) => { break temp }
-            Err(_) => {}
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(50)).await,
         }
     };
 
-    let mut buffer = [0u8; 512];
-    loop {
-        match client.try_read(&mut buffer) {
-            Ok(0) => {
-                // Connection closed by client.
-                println!("Pipe client disconnected before sending 'goodguys'.");
-                break; // Exit the read loop
-            }
-            Ok(n) => {
-                let message = String::from_utf8_lossy(&buffer[..n]);
-                println!("Received from pipe client: {}", message);
-                let parts: Vec<&str> = message.split(',').collect();
-                if parts.len() == 2 {
-                    let network_port: u16 = parts[0].parse().unwrap();
-                    let forward_port: u16 = parts[1].parse().unwrap();
-                    let _ = NETWORK_PORT.set(network_port);
-                    let _ = FORWARD_PORT.set(forward_port);
-                    client.write_all("goodjobguy".as_bytes());
-                    break;
-                }
-            }
-            Err(_) => {}
+    match ipc::receive_ports(&mut receiver, &sender).await {
+        Ok(ports) => {
+            println!("Received ports from agent: {:?}", ports);
+            let _ = NETWORK_PORT.set(ports.network_port);
+            let _ = FORWARD_PORT.set(ports.forward_port);
+        }
+        Err(e) => {
+            println!("Failed to receive ports from agent: {}", e);
+            return;
         }
     }
 
-
+    // 像游戏一样保持管道打开并发送心跳, 直到 agent 退出; 打印 agent 转发的隧道状态
+    let closed = ipc::heartbeat(receiver, sender, |message| println!("Agent: {:?}", message)).await;
+    println!("Agent stopped responding over the pipe: {}", closed);
 }
+
 fn main() {
+    // 端点和随机数来自命令行参数 (testexe <端点> <随机数>) 或环境变量, 与 agent 启动时打印的一致
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let credentials = match args.as_slice() {
+        [name, nonce] => Credentials {
+            name: name.clone(),
+            nonce: nonce.clone(),
+            peer_pid: None,
+        },
+        _ => Credentials::from_env().expect("usage: testexe <endpoint> <nonce>"),
+    };
     let rt = Runtime::new().unwrap();
-    rt.block_on(aaaaaa());
+    rt.block_on(aaaaaa(&credentials));
 
     // 构造DLL路径
     // let wide: PCWSTR = w!("java_native.dll");
@@ -0,0 +1,165 @@
+// 认证响应帧: 服务器在处理 hello 帧后发送给 agent
+//
+// 格式 (大端序):
+//   u8  帧版本
+//   u8  状态码
+//   u16 协商出的协议版本 (拒绝时为服务器支持的最高版本)
+//   u32 启用的能力标志位
+//   u16 原因长度
+//   ..  原因 (UTF-8, 可以直接展示给玩家)
+use crate::handshake::PROTOCOL_VERSION;
+use std::fmt;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const AUTH_RESPONSE_VERSION: u8 = 2;
+
+// 原因文本的最大长度, 超出部分会被截断
+const MAX_REASON_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthStatus {
+    Ok,
+    InvalidToken,
+    Expired,
+    Revoked,
+    Banned,
+    ServerFull,
+    VersionMismatch,
+    InternalError,
+    Unknown(u8), // 更新的服务器可能会发送当前版本不认识的状态码
+}
+
+impl AuthStatus {
+    pub fn code(self) -> u8 {
+        match self {
+            AuthStatus::Ok => 0,
+            AuthStatus::InvalidToken => 1,
+            AuthStatus::Expired => 2,
+            AuthStatus::Revoked => 3,
+            AuthStatus::Banned => 4,
+            AuthStatus::ServerFull => 5,
+            AuthStatus::VersionMismatch => 6,
+            AuthStatus::InternalError => 7,
+            AuthStatus::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<u8> for AuthStatus {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => AuthStatus::Ok,
+            1 => AuthStatus::InvalidToken,
+            2 => AuthStatus::Expired,
+            3 => AuthStatus::Revoked,
+            4 => AuthStatus::Banned,
+            5 => AuthStatus::ServerFull,
+            6 => AuthStatus::VersionMismatch,
+            7 => AuthStatus::InternalError,
+            code => AuthStatus::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for AuthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthStatus::Ok => write!(f, "ok"),
+            AuthStatus::InvalidToken => write!(f, "invalid token"),
+            AuthStatus::Expired => write!(f, "token expired"),
+            AuthStatus::Revoked => write!(f, "token revoked"),
+            AuthStatus::Banned => write!(f, "banned"),
+            AuthStatus::ServerFull => write!(f, "server full"),
+            AuthStatus::VersionMismatch => write!(f, "version mismatch"),
+            AuthStatus::InternalError => write!(f, "internal error"),
+            AuthStatus::Unknown(code) => write!(f, "unknown status {}", code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthResponse {
+    pub status: AuthStatus,
+    pub protocol_version: u16,
+    pub capabilities: u32,
+    pub reason: String,
+}
+
+impl AuthResponse {
+    pub fn ok(protocol_version: u16, capabilities: u32) -> Self {
+        AuthResponse {
+            status: AuthStatus::Ok,
+            protocol_version,
+            capabilities,
+            reason: String::new(),
+        }
+    }
+
+    pub fn reject(status: AuthStatus, reason: impl Into<String>) -> Self {
+        AuthResponse {
+            status,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: 0,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == AuthStatus::Ok
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        let reason = truncate(&self.reason, MAX_REASON_LEN);
+        let mut frame = Vec::with_capacity(10 + reason.len());
+        frame.push(AUTH_RESPONSE_VERSION);
+        frame.push(self.status.code());
+        frame.extend_from_slice(&self.protocol_version.to_be_bytes());
+        frame.extend_from_slice(&self.capabilities.to_be_bytes());
+        frame.extend_from_slice(&(reason.len() as u16).to_be_bytes());
+        frame.extend_from_slice(reason.as_bytes());
+        writer.write_all(&frame).await?;
+        writer.flush().await
+    }
+
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let frame_version = reader.read_u8().await?;
+        if frame_version != AUTH_RESPONSE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported auth response version {}", frame_version),
+            ));
+        }
+        let status = AuthStatus::from(reader.read_u8().await?);
+        let protocol_version = reader.read_u16().await?;
+        let capabilities = reader.read_u32().await?;
+
+        let reason_len = reader.read_u16().await? as usize;
+        if reason_len > MAX_REASON_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("auth response reason too long ({} bytes)", reason_len),
+            ));
+        }
+        let mut reason = vec![0u8; reason_len];
+        reader.read_exact(&mut reason).await?;
+
+        Ok(AuthResponse {
+            status,
+            protocol_version,
+            capabilities,
+            reason: String::from_utf8_lossy(&reason).into_owned(),
+        })
+    }
+}
+
+// 按字节截断, 但不截断在 UTF-8 字符中间
+fn truncate(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
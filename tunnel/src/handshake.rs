@@ -0,0 +1,336 @@
+// agent 连接建立后发送的第一个帧 (hello), 服务器据此协商协议版本并校验令牌
+//
+// 格式 (大端序):
+//   [4] 魔数 "TZCA"
+//   u16 客户端支持的最低协议版本
+//   u16 客户端支持的最高协议版本
+//   u8  构建ID长度, 之后是构建ID (UTF-8)
+//   u32 能力标志位
+//   u32 令牌长度, 之后是令牌 (UTF-8)
+//
+// 也可以发送探测帧代替 hello, 用于测量到中继服务器的往返时间, 不需要令牌:
+//   [4] 魔数 "TZCP"
+//   u64 随机数, 服务器原样返回后关闭连接
+//
+// 协商了 CAP_RESUME 时, 服务器在认证成功响应之后发送 16 字节的会话ID;
+// 隧道断开后 agent 在新连接上发送恢复帧代替 hello:
+//   [4] 魔数 "TZCR"
+//   [16] 会话ID
+//   u64 agent 已经收到的字节数
+// 服务器回复 u8 状态 (0 = 成功, 1 = 会话不存在) 和 u64 服务器已经收到的字节数, 双方从对端收到的位置继续发送
+use crate::auth::AuthResponse;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const MAGIC: [u8; 4] = *b"TZCA";
+pub const PROBE_MAGIC: [u8; 4] = *b"TZCP";
+pub const RESUME_MAGIC: [u8; 4] = *b"TZCR";
+
+// 本实现支持的协议版本范围
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+pub const PROTOCOL_VERSION: u16 = 1;
+
+// 能力标志位, 双方都支持的能力才会启用
+pub const CAP_MUX: u32 = 1 << 0; // 认证后在同一条隧道上复用多个连接 (见 mux 模块)
+pub const CAP_RESUME: u32 = 1 << 1; // 隧道断开后可以在新连接上恢复 (见 resume 模块)
+pub const CAP_NOTICE: u32 = 1 << 2; // 服务器可以在复用会话上推送消息 (见 mux 模块的 NOTICE 帧)
+pub const SUPPORTED_CAPABILITIES: u32 = CAP_MUX | CAP_RESUME | CAP_NOTICE;
+
+const RESUME_OK: u8 = 0;
+const RESUME_UNKNOWN_SESSION: u8 = 1;
+
+// 可恢复会话的ID, 由服务器随机生成, 持有者可以恢复会话
+pub type SessionId = [u8; 16];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub build_id: String,
+    pub capabilities: u32,
+    pub token: String,
+}
+
+impl Hello {
+    // 使用本实现支持的版本范围和能力
+    pub fn new(build_id: &str, token: &str) -> Self {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            build_id: build_id.to_string(),
+            capabilities: SUPPORTED_CAPABILITIES,
+            token: token.to_string(),
+        }
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        let build_id = self.build_id.as_bytes();
+        if build_id.len() > u8::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "build id is longer than 255 bytes",
+            ));
+        }
+
+        let mut frame = Vec::with_capacity(17 + build_id.len() + self.token.len());
+        frame.extend_from_slice(&MAGIC);
+        frame.extend_from_slice(&self.min_version.to_be_bytes());
+        frame.extend_from_slice(&self.max_version.to_be_bytes());
+        frame.push(build_id.len() as u8);
+        frame.extend_from_slice(build_id);
+        frame.extend_from_slice(&self.capabilities.to_be_bytes());
+        frame.extend_from_slice(&(self.token.len() as u32).to_be_bytes());
+        frame.extend_from_slice(self.token.as_bytes());
+        writer.write_all(&frame).await?;
+        writer.flush().await
+    }
+
+    // max_token_len 限制令牌长度, 防止对端用一个很大的长度让我们分配内存
+    pub async fn read_from<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        max_token_len: usize,
+    ) -> io::Result<Self> {
+        match ClientFrame::read_from(reader, max_token_len).await? {
+            ClientFrame::Hello(hello) => Ok(hello),
+            ClientFrame::Probe(_) | ClientFrame::Resume(_) => Err(not_a_hello()),
+        }
+    }
+
+    // 读取魔数之后的部分
+    async fn read_body<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        max_token_len: usize,
+    ) -> io::Result<Self> {
+        let min_version = reader.read_u16().await?;
+        let max_version = reader.read_u16().await?;
+        let build_id_len = reader.read_u8().await? as usize;
+        let mut build_id = vec![0u8; build_id_len];
+        reader.read_exact(&mut build_id).await?;
+        let capabilities = reader.read_u32().await?;
+
+        let token_len = reader.read_u32().await? as usize;
+        if token_len > max_token_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "token length {} exceeds the limit of {} bytes",
+                    token_len, max_token_len
+                ),
+            ));
+        }
+        let mut token = vec![0u8; token_len];
+        reader.read_exact(&mut token).await?;
+
+        Ok(Hello {
+            min_version,
+            max_version,
+            build_id: String::from_utf8_lossy(&build_id).into_owned(),
+            capabilities,
+            token: String::from_utf8(token)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "token is not UTF-8"))?,
+        })
+    }
+}
+
+// agent 连接后发送的第一个帧
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientFrame {
+    Hello(Hello),
+    Probe(u64), // 探测帧中的随机数
+    Resume(ResumeRequest),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumeRequest {
+    pub session_id: SessionId,
+    pub received: u64, // agent 已经收到的字节数
+}
+
+impl ClientFrame {
+    pub async fn read_from<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        max_token_len: usize,
+    ) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).await?;
+        match magic {
+            MAGIC => Ok(ClientFrame::Hello(Hello::read_body(reader, max_token_len).await?)),
+            PROBE_MAGIC => Ok(ClientFrame::Probe(reader.read_u64().await?)),
+            RESUME_MAGIC => {
+                let mut session_id = SessionId::default();
+                reader.read_exact(&mut session_id).await?;
+                Ok(ClientFrame::Resume(ResumeRequest {
+                    session_id,
+                    received: reader.read_u64().await?,
+                }))
+            }
+            _ => Err(not_a_hello()),
+        }
+    }
+}
+
+// 服务器对探测帧的回复: 原样返回随机数
+pub async fn write_probe_reply<W: AsyncWrite + Unpin>(writer: &mut W, nonce: u64) -> io::Result<()> {
+    writer.write_all(&nonce.to_be_bytes()).await?;
+    writer.flush().await
+}
+
+// agent 端的探测: 发送探测帧并等待服务器返回相同的随机数
+pub async fn probe<S>(stream: &mut S, nonce: u64) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut frame = [0u8; 12];
+    frame[..4].copy_from_slice(&PROBE_MAGIC);
+    frame[4..].copy_from_slice(&nonce.to_be_bytes());
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+    if stream.read_u64().await? != nonce {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "probe reply does not match",
+        ));
+    }
+    Ok(())
+}
+
+// agent 端的恢复: 发送恢复帧, 返回服务器已经收到的字节数; 服务器不认识这个会话时返回 None
+pub async fn client_resume<S>(stream: &mut S, request: &ResumeRequest) -> io::Result<Option<u64>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut frame = [0u8; 28];
+    frame[..4].copy_from_slice(&RESUME_MAGIC);
+    frame[4..20].copy_from_slice(&request.session_id);
+    frame[20..].copy_from_slice(&request.received.to_be_bytes());
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+
+    let status = stream.read_u8().await?;
+    let received = stream.read_u64().await?;
+    Ok((status == RESUME_OK).then_some(received))
+}
+
+// 服务器对恢复帧的回复, received 为空表示会话不存在
+pub async fn write_resume_reply<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    received: Option<u64>,
+) -> io::Result<()> {
+    let mut reply = [0u8; 9];
+    reply[0] = if received.is_some() {
+        RESUME_OK
+    } else {
+        RESUME_UNKNOWN_SESSION
+    };
+    reply[1..].copy_from_slice(&received.unwrap_or(0).to_be_bytes());
+    writer.write_all(&reply).await?;
+    writer.flush().await
+}
+
+pub async fn write_session_id<W: AsyncWrite + Unpin>(writer: &mut W, id: &SessionId) -> io::Result<()> {
+    writer.write_all(id).await?;
+    writer.flush().await
+}
+
+pub async fn read_session_id<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<SessionId> {
+    let mut id = SessionId::default();
+    reader.read_exact(&mut id).await?;
+    Ok(id)
+}
+
+fn not_a_hello() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "not a ClientsideAgent hello frame",
+    )
+}
+
+// 选择双方都支持的最高版本, 没有交集时返回 None
+pub fn negotiate_version(min_version: u16, max_version: u16) -> Option<u16> {
+    let version = max_version.min(PROTOCOL_VERSION);
+    (version >= min_version.max(MIN_PROTOCOL_VERSION)).then_some(version)
+}
+
+// agent 端的握手: 发送 hello 帧并等待服务器的认证响应
+// 被拒绝时同样返回 Ok, 由调用方决定如何向玩家展示原因
+pub async fn client_handshake<S>(stream: &mut S, hello: &Hello) -> io::Result<AuthResponse>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    hello.write_to(stream).await?;
+    AuthResponse::read_from(stream).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_TOKEN_LEN: usize = 1024;
+
+    async fn encoded(hello: &Hello) -> Vec<u8> {
+        let mut frame = Vec::new();
+        hello.write_to(&mut frame).await.unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn hello_round_trips() {
+        let hello = Hello::new("agent/1.2.3", "header.claims.signature");
+        let frame = encoded(&hello).await;
+        assert_eq!(&frame[..4], &MAGIC);
+        assert_eq!(Hello::read_from(&mut &frame[..], MAX_TOKEN_LEN).await.unwrap(), hello);
+    }
+
+    #[tokio::test]
+    async fn hello_layout_is_stable() {
+        let hello = Hello {
+            min_version: 1,
+            max_version: 2,
+            build_id: "b".to_string(),
+            capabilities: CAP_MUX | CAP_NOTICE,
+            token: "tk".to_string(),
+        };
+        let expected = [
+            b'T', b'Z', b'C', b'A', // 魔数
+            0, 1, 0, 2, // 版本范围
+            1, b'b', // 构建ID
+            0, 0, 0, 5, // 能力
+            0, 0, 0, 2, b't', b'k', // 令牌
+        ];
+        assert_eq!(encoded(&hello).await, expected);
+    }
+
+    #[tokio::test]
+    async fn oversized_token_is_rejected_before_reading_it() {
+        let frame = encoded(&Hello::new("agent", &"x".repeat(MAX_TOKEN_LEN + 1))).await;
+        let error = Hello::read_from(&mut &frame[..], MAX_TOKEN_LEN).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("exceeds the limit"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn other_protocols_are_not_a_hello() {
+        // 例如直接连到中继端口的 Minecraft 握手包
+        let frame = [0x10, 0x00, 0xfd, 0x05, 0x09, b'l', b'o', b'c'];
+        let error = Hello::read_from(&mut &frame[..], MAX_TOKEN_LEN).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let truncated = &encoded(&Hello::new("agent", "token")).await[..10];
+        let error = Hello::read_from(&mut &truncated[..], MAX_TOKEN_LEN).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn negotiation_picks_the_highest_common_version() {
+        let cases = [
+            ((MIN_PROTOCOL_VERSION, PROTOCOL_VERSION), Some(PROTOCOL_VERSION)),
+            ((MIN_PROTOCOL_VERSION, PROTOCOL_VERSION + 5), Some(PROTOCOL_VERSION)), // 更新的 agent
+            ((0, PROTOCOL_VERSION), Some(PROTOCOL_VERSION)),
+            ((PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2), None), // agent 不再支持这个版本
+            ((0, MIN_PROTOCOL_VERSION - 1), None),                // 过旧的 agent
+            ((PROTOCOL_VERSION, MIN_PROTOCOL_VERSION.saturating_sub(1)), None), // 范围为空
+        ];
+        for ((min, max), expected) in cases {
+            assert_eq!(negotiate_version(min, max), expected, "agent range {}-{}", min, max);
+        }
+    }
+}
@@ -0,0 +1,7 @@
+// agent 与中继服务器共用的协议定义
+pub mod auth;
+pub mod handshake;
+pub mod minecraft;
+pub mod mux;
+pub mod resume;
+pub mod transport;
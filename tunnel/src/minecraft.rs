@@ -0,0 +1,300 @@
+// Minecraft Java 版协议中 agent / 中继需要理解的最小子集
+// 参考: https://minecraft.wiki/w/Java_Edition_protocol
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+// 握手阶段的数据包不会很大, 这个上限用来拒绝非 Minecraft 流量
+pub const MAX_HANDSHAKE_PACKET_LEN: usize = 1024;
+
+pub const NEXT_STATE_STATUS: i32 = 1;
+pub const NEXT_STATE_LOGIN: i32 = 2;
+
+// 1.6 及更早版本的服务器列表查询以这个字节开头, 不是普通的数据包
+pub const LEGACY_PING: u8 = 0xfe;
+
+// 握手包中服务器地址的最大字符数
+const MAX_SERVER_ADDRESS_LEN: usize = 255;
+
+// 握手数据包 (packet id 0x00)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: i32,
+    pub server_address: String,
+    pub server_port: u16,
+    pub next_state: i32,
+}
+
+impl Handshake {
+    // 解析数据包内容 (不含长度前缀, 含 packet id)
+    pub fn parse(packet: &[u8]) -> io::Result<Self> {
+        let mut cursor = packet;
+        let packet_id = take_varint(&mut cursor)?;
+        if packet_id != 0x00 {
+            return Err(invalid(format!("expected handshake packet, got id {:#04x}", packet_id)));
+        }
+        let protocol_version = take_varint(&mut cursor)?;
+        let server_address = take_string(&mut cursor, MAX_SERVER_ADDRESS_LEN)?;
+        let server_port = u16::from_be_bytes(take_bytes(&mut cursor, 2)?.try_into().unwrap());
+        let next_state = take_varint(&mut cursor)?;
+        Ok(Handshake {
+            protocol_version,
+            server_address,
+            server_port,
+            next_state,
+        })
+    }
+
+    // 编码为带长度前缀的数据包
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(self.server_address.len() + 16);
+        put_varint(&mut body, 0x00);
+        put_varint(&mut body, self.protocol_version);
+        put_string(&mut body, &self.server_address);
+        body.extend_from_slice(&self.server_port.to_be_bytes());
+        put_varint(&mut body, self.next_state);
+        frame(body)
+    }
+
+    // 地址中第一个 \0 之前的主机名部分
+    pub fn host(&self) -> &str {
+        self.server_address
+            .split('\0')
+            .next()
+            .unwrap_or_default()
+    }
+
+    // 替换目标地址, 保留 Forge 等在主机名后面以 \0 分隔附加的标记 (例如 "\0FML3\0")
+    pub fn rewrite_target(&mut self, host: &str, port: u16) {
+        let suffix = &self.server_address[self.host().len()..];
+        self.server_address = format!("{}{}", host, suffix);
+        self.server_port = port;
+    }
+}
+
+// 读取一个未压缩的数据包, 返回内容 (含 packet id)
+pub async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R, max_len: usize) -> io::Result<Vec<u8>> {
+    let len = read_varint(reader).await?;
+    if len <= 0 || len as usize > max_len {
+        return Err(invalid(format!("packet length {} out of range", len)));
+    }
+    let mut packet = vec![0u8; len as usize];
+    reader.read_exact(&mut packet).await?;
+    Ok(packet)
+}
+
+// 读取连接的第一个数据包并解析为握手包
+// 旧版本的服务器列表查询返回 None, 这时只读取了 LEGACY_PING 一个字节
+pub async fn read_handshake<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Handshake>> {
+    let first = reader.read_u8().await?;
+    if first == LEGACY_PING {
+        return Ok(None);
+    }
+    // 第一个字节是长度前缀的一部分
+    let prefix = [first];
+    let mut chained = (&prefix[..]).chain(reader);
+    let packet = read_packet(&mut chained, MAX_HANDSHAKE_PACKET_LEN).await?;
+    Handshake::parse(&packet).map(Some)
+}
+
+// 登录阶段的断开连接数据包 (packet id 0x00), 游戏会把 reason 显示在断开界面上
+pub fn login_disconnect_packet(reason: &str) -> Vec<u8> {
+    let text = serde_json::json!({ "text": reason }).to_string();
+    let mut body = Vec::with_capacity(text.len() + 8);
+    put_varint(&mut body, 0x00);
+    put_string(&mut body, &text);
+    frame(body)
+}
+
+// 加上 VarInt 长度前缀
+pub fn frame(body: Vec<u8>) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    put_varint(&mut packet, body.len() as i32);
+    packet.extend_from_slice(&body);
+    packet
+}
+
+pub async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<i32> {
+    let mut value: u32 = 0;
+    for i in 0..5 {
+        let byte = reader.read_u8().await?;
+        value |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+    Err(invalid("VarInt is too long".to_string()))
+}
+
+pub fn put_varint(buf: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7f == 0 {
+            buf.push(value as u8);
+            return;
+        }
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+pub fn put_string(buf: &mut Vec<u8>, value: &str) {
+    put_varint(buf, value.len() as i32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+pub fn take_varint(cursor: &mut &[u8]) -> io::Result<i32> {
+    let mut value: u32 = 0;
+    for i in 0..5 {
+        let byte = take_bytes(cursor, 1)?[0];
+        value |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+    Err(invalid("VarInt is too long".to_string()))
+}
+
+// max_chars 为协议规定的最大字符数, 字节长度上限是它的 3 倍 (UTF-8)
+pub fn take_string(cursor: &mut &[u8], max_chars: usize) -> io::Result<String> {
+    let len = take_varint(cursor)?;
+    if len < 0 || len as usize > max_chars * 3 {
+        return Err(invalid(format!("string length {} out of range", len)));
+    }
+    let bytes = take_bytes(cursor, len as usize)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid("string is not valid UTF-8".to_string()))
+}
+
+pub fn take_bytes<'a>(cursor: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if cursor.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "packet is truncated"));
+    }
+    let (head, rest) = cursor.split_at(len);
+    *cursor = rest;
+    Ok(head)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(server_address: &str) -> Handshake {
+        Handshake {
+            protocol_version: 767,
+            server_address: server_address.to_string(),
+            server_port: 25565,
+            next_state: NEXT_STATE_LOGIN,
+        }
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let cases: [(i32, &[u8]); 7] = [
+            (0, &[0x00]),
+            (1, &[0x01]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (25565, &[0xdd, 0xc7, 0x01]),
+            (i32::MAX, &[0xff, 0xff, 0xff, 0xff, 0x07]),
+            (-1, &[0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ];
+        for (value, encoded) in cases {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, value);
+            assert_eq!(buf, encoded, "{}", value);
+            let mut cursor = encoded;
+            assert_eq!(take_varint(&mut cursor).unwrap(), value);
+            assert!(cursor.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_and_oversized_varints_are_rejected() {
+        let cases: [(&[u8], io::ErrorKind); 4] = [
+            (&[], io::ErrorKind::UnexpectedEof),
+            (&[0x80], io::ErrorKind::UnexpectedEof),
+            (&[0xff, 0xff, 0xff, 0xff], io::ErrorKind::UnexpectedEof),
+            (&[0xff, 0xff, 0xff, 0xff, 0xff, 0x01], io::ErrorKind::InvalidData),
+        ];
+        for (bytes, kind) in cases {
+            let mut cursor = bytes;
+            assert_eq!(take_varint(&mut cursor).unwrap_err().kind(), kind, "{:02x?}", bytes);
+            let mut reader = bytes;
+            assert_eq!(read_varint(&mut reader).await.unwrap_err().kind(), kind, "{:02x?}", bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn packet_lengths_out_of_range_are_rejected() {
+        let mut too_long = Vec::new();
+        put_varint(&mut too_long, MAX_HANDSHAKE_PACKET_LEN as i32 + 1);
+        // 长度为 0、负数和超过上限
+        for prefix in [vec![0x00], vec![0xff, 0xff, 0xff, 0xff, 0x0f], too_long] {
+            let mut reader = &prefix[..];
+            let e = read_packet(&mut reader, MAX_HANDSHAKE_PACKET_LEN).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:02x?}", prefix);
+        }
+    }
+
+    #[test]
+    fn server_addresses_above_the_limit_are_rejected() {
+        let longest = "a".repeat(MAX_SERVER_ADDRESS_LEN * 3);
+        let mut packet = handshake(&longest).encode();
+        let mut cursor = &packet[..];
+        let body = read_body(&mut cursor);
+        assert_eq!(Handshake::parse(body).unwrap().server_address, longest);
+
+        packet = handshake(&format!("{}a", longest)).encode();
+        let mut cursor = &packet[..];
+        let e = Handshake::parse(read_body(&mut cursor)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "string length 766 out of range");
+    }
+
+    // 去掉长度前缀
+    fn read_body<'a>(cursor: &mut &'a [u8]) -> &'a [u8] {
+        let len = take_varint(cursor).unwrap() as usize;
+        take_bytes(cursor, len).unwrap()
+    }
+
+    #[tokio::test]
+    async fn handshake_is_read_back_as_encoded() {
+        let sent = handshake("mc.example.com");
+        let mut packet = sent.encode();
+        packet.extend_from_slice(b"next packet");
+        let mut reader = &packet[..];
+        assert_eq!(read_handshake(&mut reader).await.unwrap(), Some(sent));
+        assert_eq!(reader, b"next packet");
+    }
+
+    #[tokio::test]
+    async fn legacy_ping_consumes_only_its_first_byte() {
+        let mut reader: &[u8] = &[LEGACY_PING, 0x01, 0xfa];
+        assert_eq!(read_handshake(&mut reader).await.unwrap(), None);
+        assert_eq!(reader, &[0x01, 0xfa]);
+    }
+
+    #[tokio::test]
+    async fn other_packets_are_not_a_handshake() {
+        // 长度 1, packet id 0x01
+        let mut reader: &[u8] = &[0x01, 0x01];
+        let e = read_handshake(&mut reader).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rewrite_target_keeps_the_forge_marker() {
+        let mut forge = handshake("mc.example.com\0FML\0");
+        assert_eq!(forge.host(), "mc.example.com");
+        forge.rewrite_target("play.example.net", 25566);
+        assert_eq!(forge.server_address, "play.example.net\0FML\0");
+        assert_eq!(forge.host(), "play.example.net");
+        assert_eq!(forge.server_port, 25566);
+
+        let mut vanilla = handshake("mc.example.com");
+        vanilla.rewrite_target("play.example.net", 25565);
+        assert_eq!(vanilla.server_address, "play.example.net");
+    }
+}
@@ -0,0 +1,816 @@
+// 在一条已认证的隧道上复用多个 Minecraft 连接
+//
+// 帧格式 (大端序):
+//   u8  帧类型
+//   u32 流ID (agent 打开的流为奇数, 服务器打开的流为偶数)
+//   u32 负载长度, 之后是负载
+//
+// 帧类型:
+//   OPEN   打开一个新的流, 无负载
+//   DATA   流数据
+//   CLOSE  发送方不会再发送数据 (半关闭), 双方都发送 CLOSE 后流结束
+//   WINDOW 负载为 u32, 允许对端继续发送的字节数
+//   PING   流ID为 0, 负载为 u64, 对端用内容相同的 PONG 回复
+//   PONG   流ID为 0, 负载为收到的 PING 的内容
+//   NOTICE 流ID为 0, 负载为 UTF-8 文本, 服务器推送给 agent 的消息 (双方协商了 CAP_NOTICE 时才会发送)
+//
+// 每个流初始有 INITIAL_WINDOW 字节的发送额度, 接收方把数据交给使用者后再用 WINDOW 归还,
+// 这样一个读得慢的连接不会阻塞同一隧道上的其他连接
+//
+// 双方定期发送 PING 并测量往返时间, 长时间没有收到 PONG 时关闭会话 (所有流随之结束)
+use crate::transport::{BoxedStream, CloseReason, TunnelStream};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, DuplexStream, ReadBuf, ReadHalf,
+    WriteHalf,
+};
+use tokio::sync::{Semaphore, mpsc, watch};
+use tokio::time::Instant;
+
+const FRAME_OPEN: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_CLOSE: u8 = 3;
+const FRAME_WINDOW: u8 = 4;
+const FRAME_PING: u8 = 5;
+const FRAME_PONG: u8 = 6;
+const FRAME_NOTICE: u8 = 7;
+
+// 单个 DATA 帧的最大负载
+pub const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+
+// 每个流的初始接收窗口
+pub const INITIAL_WINDOW: usize = 256 * 1024;
+
+// 使用者一侧的缓冲区大小
+const STREAM_BUFFER: usize = 64 * 1024;
+
+// 等待写出的帧数量上限
+const FRAME_QUEUE: usize = 256;
+
+// 等待 accept 的新流数量上限, 超出时直接拒绝新流
+const ACCEPT_BACKLOG: usize = 64;
+
+// 对端同时打开的流数量上限, 超出按协议错误关闭会话
+const MAX_PEER_STREAMS: usize = 1024;
+
+// 尚未被读取的推送消息数量上限, 超出时丢弃新的消息
+const NOTICE_BACKLOG: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Frame {
+    Open(u32),
+    Data(u32, Vec<u8>),
+    Close(u32),
+    Window(u32, u32),
+    Ping(u64),
+    Pong(u64),
+    Notice(Vec<u8>),
+}
+
+impl Frame {
+    async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        let (kind, id, payload): (u8, u32, &[u8]) = match self {
+            Frame::Open(id) => (FRAME_OPEN, *id, &[]),
+            Frame::Data(id, data) => (FRAME_DATA, *id, data),
+            Frame::Close(id) => (FRAME_CLOSE, *id, &[]),
+            Frame::Notice(text) => (FRAME_NOTICE, 0, text),
+            Frame::Window(id, credit) => {
+                let mut header = [0u8; 13];
+                header[0] = FRAME_WINDOW;
+                header[1..5].copy_from_slice(&id.to_be_bytes());
+                header[5..9].copy_from_slice(&4u32.to_be_bytes());
+                header[9..13].copy_from_slice(&credit.to_be_bytes());
+                return writer.write_all(&header).await;
+            }
+            Frame::Ping(value) | Frame::Pong(value) => {
+                let mut header = [0u8; 17];
+                header[0] = if matches!(self, Frame::Ping(_)) {
+                    FRAME_PING
+                } else {
+                    FRAME_PONG
+                };
+                header[5..9].copy_from_slice(&8u32.to_be_bytes());
+                header[9..17].copy_from_slice(&value.to_be_bytes());
+                return writer.write_all(&header).await;
+            }
+        };
+        let mut header = [0u8; 9];
+        header[0] = kind;
+        header[1..5].copy_from_slice(&id.to_be_bytes());
+        header[5..9].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+        writer.write_all(&header).await?;
+        writer.write_all(payload).await
+    }
+
+    async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let kind = reader.read_u8().await?;
+        let id = reader.read_u32().await?;
+        let len = reader.read_u32().await? as usize;
+        if len > MAX_FRAME_PAYLOAD {
+            return Err(protocol_error(format!("frame payload of {} bytes is too large", len)));
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+
+        match kind {
+            FRAME_OPEN => Ok(Frame::Open(id)),
+            FRAME_DATA => Ok(Frame::Data(id, payload)),
+            FRAME_CLOSE => Ok(Frame::Close(id)),
+            FRAME_WINDOW if len == 4 => Ok(Frame::Window(
+                id,
+                u32::from_be_bytes(payload.try_into().unwrap()),
+            )),
+            FRAME_PING if len == 8 => Ok(Frame::Ping(u64::from_be_bytes(payload.try_into().unwrap()))),
+            FRAME_PONG if len == 8 => Ok(Frame::Pong(u64::from_be_bytes(payload.try_into().unwrap()))),
+            FRAME_NOTICE => Ok(Frame::Notice(payload)),
+            _ => Err(protocol_error(format!("invalid frame type {} (length {})", kind, len))),
+        }
+    }
+}
+
+// 会话中的一方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client, // agent, 打开奇数ID的流
+    Server, // 中继服务器, 打开偶数ID的流
+}
+
+// 心跳设置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration, // 发送 PING 的间隔
+    // 超过这个时间没有收到 PONG 时关闭会话; 为空时只测量往返时间,
+    // 用于下层隧道自己检测断开的情况 (例如可恢复的隧道, 断开后会先尝试恢复)
+    pub timeout: Option<Duration>,
+}
+
+// 复用会话, 丢弃后不会主动关闭: 会话在底层隧道断开时结束
+pub struct MuxSession {
+    shared: Arc<Shared>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<MuxStream>>,
+    notices: tokio::sync::Mutex<mpsc::Receiver<String>>,
+}
+
+struct Shared {
+    role: Role,
+    next_id: AtomicU32,
+    streams: Mutex<HashMap<u32, StreamState>>,
+    frames: mpsc::Sender<Frame>,
+    closed: watch::Sender<Option<CloseReason>>,
+    started: Instant,
+    last_pong: AtomicU64, // 最近一次收到 PONG 的时间 (会话开始后的微秒数)
+    rtt: watch::Sender<Option<Duration>>, // 最近一次测得的往返时间
+}
+
+// 会话中记录的流状态
+struct StreamState {
+    inbound: Option<mpsc::UnboundedSender<Vec<u8>>>, // 对端发来的数据, 对端关闭后为 None
+    unacked: Arc<AtomicUsize>,                       // 已收到但尚未归还额度的字节数
+    credit: Arc<Semaphore>,                          // 本端的发送额度
+    local_closed: bool,
+}
+
+impl MuxSession {
+    // 在已经完成认证的隧道上启动会话, 需要在 tokio 运行时内调用
+    pub fn new(stream: BoxedStream, role: Role, heartbeat: Heartbeat) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        let (frames, frame_queue) = mpsc::channel(FRAME_QUEUE);
+        let (incoming_sender, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let (notice_sender, notices) = mpsc::channel(NOTICE_BACKLOG);
+        let shared = Arc::new(Shared {
+            role,
+            next_id: AtomicU32::new(match role {
+                Role::Client => 1,
+                Role::Server => 2,
+            }),
+            streams: Mutex::new(HashMap::new()),
+            frames,
+            closed: watch::Sender::new(None),
+            started: Instant::now(),
+            last_pong: AtomicU64::new(0),
+            rtt: watch::Sender::new(None),
+        });
+
+        tokio::spawn(write_loop(writer, frame_queue, shared.clone()));
+        tokio::spawn(read_loop(reader, shared.clone(), incoming_sender, notice_sender));
+        tokio::spawn(heartbeat_loop(heartbeat, shared.clone()));
+        MuxSession {
+            shared,
+            incoming: tokio::sync::Mutex::new(incoming),
+            notices: tokio::sync::Mutex::new(notices),
+        }
+    }
+
+    // 打开一个新的流
+    pub async fn open(&self) -> io::Result<MuxStream> {
+        if let Some(reason) = self.close_reason() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("tunnel session is closed ({})", reason),
+            ));
+        }
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        // OPEN 必须先于该流的任何 DATA 帧发出
+        self.shared
+            .frames
+            .send(Frame::Open(id))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "tunnel session is closed"))?;
+        Ok(self.shared.start_stream(id))
+    }
+
+    // 等待对端打开的下一个流, 会话结束后返回 None
+    pub async fn accept(&self) -> Option<MuxStream> {
+        self.incoming.lock().await.recv().await
+    }
+
+    // 当前打开的流数量
+    pub fn stream_count(&self) -> usize {
+        self.shared.streams.lock().unwrap().len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.borrow().is_some()
+    }
+
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.shared.closed.borrow().clone()
+    }
+
+    // 心跳测得的往返时间, 还没有收到 PONG 时为空
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.shared.rtt.borrow()
+    }
+
+    // 每次测得新的往返时间时更新
+    pub fn rtt_updates(&self) -> watch::Receiver<Option<Duration>> {
+        self.shared.rtt.subscribe()
+    }
+
+    // 向对端推送一条消息, 只应在双方协商了 CAP_NOTICE 时调用
+    pub async fn notify(&self, text: &str) -> io::Result<()> {
+        if text.len() > MAX_FRAME_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("notice of {} bytes is too large", text.len()),
+            ));
+        }
+        self.shared
+            .frames
+            .send(Frame::Notice(text.as_bytes().to_vec()))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "tunnel session is closed"))
+    }
+
+    // 等待对端推送的下一条消息, 会话结束后返回 None
+    pub async fn next_notice(&self) -> Option<String> {
+        self.notices.lock().await.recv().await
+    }
+
+    // 等待会话结束并返回原因
+    pub async fn closed(&self) -> CloseReason {
+        let mut closed = self.shared.closed.subscribe();
+        match closed.wait_for(Option::is_some).await {
+            Ok(reason) => reason.clone().unwrap_or(CloseReason::ClosedLocally),
+            Err(_) => CloseReason::ClosedLocally,
+        }
+    }
+
+    // 主动关闭会话, 所有流都会结束
+    pub fn close(&self) {
+        self.shared.shutdown(CloseReason::ClosedLocally);
+    }
+}
+
+impl Shared {
+    // 创建流并启动收发任务
+    fn start_stream(self: &Arc<Self>, id: u32) -> MuxStream {
+        let (user, inner) = tokio::io::duplex(STREAM_BUFFER);
+        let (inner_read, inner_write) = tokio::io::split(inner);
+        let (inbound, inbound_queue) = mpsc::unbounded_channel();
+        let unacked = Arc::new(AtomicUsize::new(0));
+        let credit = Arc::new(Semaphore::new(INITIAL_WINDOW));
+
+        self.streams.lock().unwrap().insert(
+            id,
+            StreamState {
+                inbound: Some(inbound),
+                unacked: unacked.clone(),
+                credit: credit.clone(),
+                local_closed: false,
+            },
+        );
+        tokio::spawn(outbound_pump(id, inner_read, credit, self.clone()));
+        tokio::spawn(inbound_pump(id, inner_write, inbound_queue, unacked, self.frames.clone()));
+
+        MuxStream {
+            id,
+            inner: user,
+            shared: self.clone(),
+        }
+    }
+
+    // 会话开始后经过的微秒数, 用作 PING 的内容
+    fn elapsed_micros(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    // 对端打开的流ID必须属于对端
+    fn is_peer_id(&self, id: u32) -> bool {
+        match self.role {
+            Role::Client => id != 0 && id.is_multiple_of(2),
+            Role::Server => !id.is_multiple_of(2),
+        }
+    }
+
+    // 本端不会再发送数据
+    fn finish_local(&self, id: u32) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(state) = streams.get_mut(&id) {
+            state.local_closed = true;
+            if state.inbound.is_none() {
+                streams.remove(&id);
+            }
+        }
+    }
+
+    // 对端不会再发送数据
+    fn finish_remote(&self, id: u32) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(state) = streams.get_mut(&id) {
+            state.inbound = None;
+            if state.local_closed {
+                streams.remove(&id);
+            }
+        }
+    }
+
+    // 结束会话: 记录原因, 关闭所有流
+    fn shutdown(&self, reason: CloseReason) {
+        self.closed.send_if_modified(|closed| {
+            if closed.is_some() {
+                return false;
+            }
+            *closed = Some(reason);
+            true
+        });
+        for (_, state) in self.streams.lock().unwrap().drain() {
+            state.credit.close();
+        }
+    }
+}
+
+// 把帧写到底层隧道, 队列暂时为空时才 flush, 减少小包数量
+async fn write_loop(
+    writer: WriteHalf<BoxedStream>,
+    mut frame_queue: mpsc::Receiver<Frame>,
+    shared: Arc<Shared>,
+) {
+    let mut writer = BufWriter::new(writer);
+    let mut closed = shared.closed.subscribe();
+    loop {
+        let frame = tokio::select! {
+            _ = closed.wait_for(Option::is_some) => break,
+            frame = frame_queue.recv() => match frame {
+                Some(frame) => frame,
+                None => break,
+            },
+        };
+        let mut result = frame.write_to(&mut writer).await;
+        if result.is_ok() && frame_queue.is_empty() {
+            result = writer.flush().await;
+        }
+        if let Err(e) = result {
+            shared.shutdown(CloseReason::from(e));
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+// 读取对端的帧并分发到各个流
+async fn read_loop(
+    mut reader: ReadHalf<BoxedStream>,
+    shared: Arc<Shared>,
+    incoming: mpsc::Sender<MuxStream>,
+    notices: mpsc::Sender<String>,
+) {
+    let mut closed = shared.closed.subscribe();
+    let reason = loop {
+        let frame = tokio::select! {
+            _ = closed.wait_for(Option::is_some) => return,
+            frame = Frame::read_from(&mut reader) => match frame {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    break CloseReason::ClosedByPeer(String::new());
+                }
+                Err(e) => break CloseReason::from(e),
+            },
+        };
+
+        match frame {
+            Frame::Open(id) => {
+                {
+                    let streams = shared.streams.lock().unwrap();
+                    if !shared.is_peer_id(id) || streams.contains_key(&id) {
+                        break CloseReason::Error(format!("peer opened invalid stream {}", id));
+                    }
+                    let peer_streams = streams.keys().filter(|id| shared.is_peer_id(**id)).count();
+                    if peer_streams >= MAX_PEER_STREAMS {
+                        break CloseReason::Error(format!(
+                            "peer opened more than {} streams",
+                            MAX_PEER_STREAMS
+                        ));
+                    }
+                }
+                // 没有人及时接受新流时直接拒绝, 不为它保留状态; 之后收到的数据和 CLOSE 会被忽略
+                // 在单独的任务中发送 CLOSE, 写队列已满时不阻塞读取
+                let Ok(permit) = incoming.try_reserve() else {
+                    let frames = shared.frames.clone();
+                    tokio::spawn(async move {
+                        let _ = frames.send(Frame::Close(id)).await;
+                    });
+                    continue;
+                };
+                permit.send(shared.start_stream(id));
+            }
+            Frame::Data(id, data) => {
+                let mut streams = shared.streams.lock().unwrap();
+                // 本端已经完全关闭的流可能还会收到迟到的数据, 忽略即可
+                let Some(state) = streams.get_mut(&id) else {
+                    continue;
+                };
+                let Some(inbound) = &state.inbound else {
+                    break CloseReason::Error(format!("data on stream {} after close", id));
+                };
+                let unacked = state.unacked.fetch_add(data.len(), Ordering::AcqRel) + data.len();
+                if unacked > INITIAL_WINDOW {
+                    break CloseReason::Error(format!("peer overran the window of stream {}", id));
+                }
+                let _ = inbound.send(data);
+            }
+            Frame::Close(id) => shared.finish_remote(id),
+            // 对端只能归还已经发出的数据的额度, 额度不会超过初始窗口
+            Frame::Window(id, credit) => {
+                if let Some(state) = shared.streams.lock().unwrap().get(&id) {
+                    if state.credit.available_permits() + credit as usize > INITIAL_WINDOW {
+                        break CloseReason::Error(format!(
+                            "peer returned too much credit on stream {}",
+                            id
+                        ));
+                    }
+                    state.credit.add_permits(credit as usize);
+                }
+            }
+            // 队列已满时不回复, 对端会在下一次心跳时重新测量
+            Frame::Ping(value) => {
+                let _ = shared.frames.try_send(Frame::Pong(value));
+            }
+            Frame::Pong(sent) => {
+                let now = shared.elapsed_micros();
+                shared.last_pong.store(now, Ordering::Release);
+                shared
+                    .rtt
+                    .send_replace(Some(Duration::from_micros(now.saturating_sub(sent))));
+            }
+            // 使用者没有及时读取时丢弃
+            Frame::Notice(text) => {
+                let _ = notices.try_send(String::from_utf8_lossy(&text).into_owned());
+            }
+        }
+    };
+    shared.shutdown(reason);
+}
+
+// 定期发送 PING, 超时未收到 PONG 时关闭会话
+async fn heartbeat_loop(heartbeat: Heartbeat, shared: Arc<Shared>) {
+    let mut closed = shared.closed.subscribe();
+    let mut interval = tokio::time::interval(heartbeat.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = closed.wait_for(Option::is_some) => return,
+            _ = interval.tick() => {}
+        }
+        let now = shared.elapsed_micros();
+        if let Some(timeout) = heartbeat.timeout {
+            let last_pong = shared.last_pong.load(Ordering::Acquire);
+            if Duration::from_micros(now.saturating_sub(last_pong)) > timeout {
+                shared.shutdown(CloseReason::TimedOut);
+                return;
+            }
+        }
+        // 写出受阻时不排队等待, 否则无法按时检查超时
+        if let Err(mpsc::error::TrySendError::Closed(_)) = shared.frames.try_send(Frame::Ping(now)) {
+            return;
+        }
+    }
+}
+
+// 使用者写入的数据 -> DATA 帧, 受发送额度限制
+async fn outbound_pump(
+    id: u32,
+    mut inner: ReadHalf<DuplexStream>,
+    credit: Arc<Semaphore>,
+    shared: Arc<Shared>,
+) {
+    let mut buf = vec![0u8; MAX_FRAME_PAYLOAD];
+    loop {
+        let n = match inner.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        // 会话结束时额度被关闭
+        let Ok(permit) = credit.acquire_many(n as u32).await else {
+            return;
+        };
+        permit.forget();
+        if shared.frames.send(Frame::Data(id, buf[..n].to_vec())).await.is_err() {
+            return;
+        }
+    }
+    let _ = shared.frames.send(Frame::Close(id)).await;
+    shared.finish_local(id);
+}
+
+// 对端发来的数据 -> 使用者, 交付后归还额度
+async fn inbound_pump(
+    id: u32,
+    mut inner: WriteHalf<DuplexStream>,
+    mut inbound: mpsc::UnboundedReceiver<Vec<u8>>,
+    unacked: Arc<AtomicUsize>,
+    frames: mpsc::Sender<Frame>,
+) {
+    let mut discard = false;
+    while let Some(data) = inbound.recv().await {
+        // 使用者已经丢弃了流: 继续归还额度, 避免对端一直等待
+        if !discard && inner.write_all(&data).await.is_err() {
+            discard = true;
+        }
+        unacked.fetch_sub(data.len(), Ordering::AcqRel);
+        if frames.send(Frame::Window(id, data.len() as u32)).await.is_err() {
+            return;
+        }
+    }
+    let _ = inner.shutdown().await;
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// 复用会话中的一个流
+pub struct MuxStream {
+    id: u32,
+    inner: DuplexStream,
+    shared: Arc<Shared>,
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl TunnelStream for MuxStream {
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.shared.closed.borrow().clone()
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::memory;
+    use crate::transport::{Transport, TunnelListener};
+    use std::net::SocketAddr;
+
+    // 测试不应该等待这么久, 超时说明卡住了
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // 测试期间只在开始时发送一次 PING
+    const HEARTBEAT: Heartbeat = Heartbeat {
+        interval: Duration::from_secs(3600),
+        timeout: None,
+    };
+
+    // 通过内存传输连接的一对隧道 (agent 一端, 服务器一端)
+    async fn tunnel_pair() -> (BoxedStream, BoxedStream) {
+        let (transport, mut listener) = memory::pair(SocketAddr::from(([127, 0, 0, 1], 25565)));
+        let client = transport.connect().await.unwrap();
+        let server = listener.accept().await.unwrap();
+        (client.stream, server.stream)
+    }
+
+    // 读取对端发来的下一个帧, 跳过心跳
+    async fn next_frame(raw: &mut BoxedStream) -> Frame {
+        loop {
+            match Frame::read_from(raw).await.unwrap() {
+                Frame::Ping(_) | Frame::Pong(_) => continue,
+                frame => return frame,
+            }
+        }
+    }
+
+    async fn wait_closed(mux: &MuxSession) -> CloseReason {
+        tokio::time::timeout(TEST_TIMEOUT, mux.closed()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn streams_carry_data_in_both_directions_and_close() {
+        let (client, server) = tunnel_pair().await;
+        let client = MuxSession::new(client, Role::Client, HEARTBEAT);
+        let server = MuxSession::new(server, Role::Server, HEARTBEAT);
+
+        // 把每个流收到的数据原样发回, 对端关闭后也关闭
+        tokio::spawn(async move {
+            while let Some(mut stream) = server.accept().await {
+                tokio::spawn(async move {
+                    let mut data = Vec::new();
+                    stream.read_to_end(&mut data).await.unwrap();
+                    stream.write_all(&data).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        // 多个流同时传输, 数据量超过初始窗口, 需要对端归还额度
+        let mut transfers = Vec::new();
+        for i in 0..4u8 {
+            let mut stream = client.open().await.unwrap();
+            assert_eq!(stream.id() % 2, 1);
+            transfers.push(tokio::spawn(async move {
+                let sent: Vec<u8> = (0..600 * 1024).map(|n| (n % 251) as u8 ^ i).collect();
+                let (mut reader, mut writer) = tokio::io::split(&mut stream);
+                let write = async {
+                    writer.write_all(&sent).await.unwrap();
+                    writer.shutdown().await.unwrap();
+                };
+                let mut received = Vec::new();
+                let read = reader.read_to_end(&mut received);
+                let ((), read) = tokio::join!(write, read);
+                read.unwrap();
+                assert_eq!(received, sent);
+            }));
+        }
+        for transfer in transfers {
+            tokio::time::timeout(TEST_TIMEOUT, transfer).await.unwrap().unwrap();
+        }
+
+        // 双方都关闭后流从会话中移除
+        tokio::time::timeout(TEST_TIMEOUT, async {
+            while client.stream_count() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!client.is_closed());
+        client.close();
+        assert_eq!(wait_closed(&client).await, CloseReason::ClosedLocally);
+    }
+
+    #[tokio::test]
+    async fn sending_stops_at_the_window_until_credit_is_returned() {
+        let (client, mut raw) = tunnel_pair().await;
+        let client = MuxSession::new(client, Role::Client, HEARTBEAT);
+        let mut stream = client.open().await.unwrap();
+        tokio::spawn(async move {
+            stream.write_all(&vec![7u8; 2 * INITIAL_WINDOW]).await.unwrap();
+            // 保持流打开, 直到测试结束
+            std::future::pending::<()>().await;
+        });
+
+        assert_eq!(next_frame(&mut raw).await, Frame::Open(1));
+        let mut received = 0;
+        while received < INITIAL_WINDOW {
+            match next_frame(&mut raw).await {
+                Frame::Data(1, data) => received += data.len(),
+                other => panic!("expected data, got {:?}", other),
+            }
+        }
+        assert_eq!(received, INITIAL_WINDOW);
+        // 额度用完后不再发送
+        let idle = tokio::time::timeout(Duration::from_millis(200), next_frame(&mut raw)).await;
+        assert!(idle.is_err(), "sent beyond the window: {:?}", idle);
+
+        // 归还的额度正好允许再发送这么多
+        Frame::Window(1, MAX_FRAME_PAYLOAD as u32).write_to(&mut raw).await.unwrap();
+        let mut resumed = 0;
+        while resumed < MAX_FRAME_PAYLOAD {
+            match tokio::time::timeout(TEST_TIMEOUT, next_frame(&mut raw)).await.unwrap() {
+                Frame::Data(1, data) => resumed += data.len(),
+                other => panic!("expected data, got {:?}", other),
+            }
+        }
+        assert_eq!(resumed, MAX_FRAME_PAYLOAD);
+        let idle = tokio::time::timeout(Duration::from_millis(200), next_frame(&mut raw)).await;
+        assert!(idle.is_err(), "sent beyond the returned credit: {:?}", idle);
+    }
+
+    #[tokio::test]
+    async fn overrunning_the_window_closes_the_session() {
+        let (mut raw, server) = tunnel_pair().await;
+        let server = MuxSession::new(server, Role::Server, HEARTBEAT);
+
+        // 使用者不读取, 对端无视窗口继续发送
+        Frame::Open(1).write_to(&mut raw).await.unwrap();
+        let data = vec![0u8; MAX_FRAME_PAYLOAD];
+        for _ in 0..(2 * INITIAL_WINDOW / MAX_FRAME_PAYLOAD) {
+            if Frame::Data(1, data.clone()).write_to(&mut raw).await.is_err() {
+                break;
+            }
+        }
+        assert_eq!(
+            wait_closed(&server).await,
+            CloseReason::Error("peer overran the window of stream 1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn returning_more_credit_than_the_window_closes_the_session() {
+        let (client, mut raw) = tunnel_pair().await;
+        let client = MuxSession::new(client, Role::Client, HEARTBEAT);
+        let _stream = client.open().await.unwrap();
+        Frame::Window(1, 1).write_to(&mut raw).await.unwrap();
+        assert_eq!(
+            wait_closed(&client).await,
+            CloseReason::Error("peer returned too much credit on stream 1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_must_use_its_own_stream_ids() {
+        let (mut raw, server) = tunnel_pair().await;
+        let server = MuxSession::new(server, Role::Server, HEARTBEAT);
+        // 偶数ID属于服务器
+        Frame::Open(2).write_to(&mut raw).await.unwrap();
+        assert_eq!(
+            wait_closed(&server).await,
+            CloseReason::Error("peer opened invalid stream 2".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_streams_are_limited() {
+        let (mut raw, server) = tunnel_pair().await;
+        let server = Arc::new(MuxSession::new(server, Role::Server, HEARTBEAT));
+        // 及时接受所有新流, 否则它们会因为 accept 队列已满被拒绝
+        let acceptor = server.clone();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Some(stream) = acceptor.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        for n in 0..=MAX_PEER_STREAMS as u32 {
+            if Frame::Open(2 * n + 1).write_to(&mut raw).await.is_err() {
+                break;
+            }
+            // 让 accept 任务跟上
+            if n % 32 == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+        raw.flush().await.unwrap();
+        assert_eq!(
+            wait_closed(&server).await,
+            CloseReason::Error(format!("peer opened more than {} streams", MAX_PEER_STREAMS))
+        );
+    }
+}
@@ -0,0 +1,691 @@
+// 可恢复的隧道: 底层连接断开后 agent 建立新连接并从断点继续, 上层 (mux) 感觉不到中断
+// 新连接使用新的本地端口, 所以 NAT 重新映射后也能恢复
+//
+// 帧格式 (大端序):
+//   u8 帧类型
+//   DATA  u32 长度, 之后是数据
+//   ACK   u64 已经交给使用者的字节总数, 对端据此释放重放缓冲区
+//   CLOSE 无内容, 发送方不会再发送数据 (类似 TCP 的 FIN), 双方都发送过 CLOSE 后会话结束
+//
+// 每一端都保留已发送但未被确认的数据 (重放缓冲区), 恢复时对端告知已经收到的字节数,
+// 从那里重新发送; 握手过程见 handshake 模块
+// 可以设置连接超时: 这么长时间没有收到任何帧时认为连接已经断开并开始恢复,
+// 上层 (mux 的心跳) 需要保证正常情况下连接上一直有数据
+// 收到的数据先放入队列再交给使用者, 使用者读得慢时 ACK 帧也不会被挡在数据后面;
+// 只有交给使用者的数据才会被确认, 所以队列的长度不会超过对端的重放缓冲区,
+// 超过时说明对端不遵守协议, 按协议错误断开连接
+use crate::handshake::{self, ResumeRequest, SessionId};
+use crate::transport::{BoxedStream, CloseReason, TunnelStream};
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, DuplexStream,
+    ReadBuf, ReadHalf, WriteHalf,
+};
+use tokio::sync::{Notify, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+const FRAME_DATA: u8 = 1;
+const FRAME_ACK: u8 = 2;
+const FRAME_CLOSE: u8 = 3;
+
+// 单个 DATA 帧的最大负载
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+
+// 重放缓冲区的上限, 写满后上层的写入会等待对端确认
+const REPLAY_BUFFER: usize = 1024 * 1024;
+
+// 收到但还没有交给使用者的数据的上限: 对端的重放缓冲区在写满前最多再放入一次读取的数据
+const MAX_QUEUED: u64 = (REPLAY_BUFFER + MAX_FRAME_PAYLOAD) as u64;
+
+// 每交给使用者这么多字节回复一次 ACK
+const ACK_THRESHOLD: u64 = 64 * 1024;
+
+// 使用者一侧的缓冲区大小
+const STREAM_BUFFER: usize = 64 * 1024;
+
+// agent 尝试恢复的总时间, 以及单次尝试 (建立连接 + 恢复握手) 的最长时间
+pub const RESUME_WINDOW: Duration = Duration::from_secs(30);
+const RESUME_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// 服务器保留断开的会话的时间, 比 agent 的恢复时间长, 避免 agent 还在重试时会话已经被清理
+const SERVER_RESUME_WINDOW: Duration = Duration::from_secs(60);
+
+// 为恢复建立一条新的底层连接
+pub type Connector = Arc<dyn Fn() -> BoxFuture<'static, io::Result<BoxedStream>> + Send + Sync>;
+
+// 交给等待恢复的会话的新连接, 以及 agent 已经收到的字节数
+type Handoff = (BoxedStream, u64);
+
+// 生成随机的会话ID
+pub fn new_session_id() -> io::Result<SessionId> {
+    let mut id = SessionId::default();
+    rustls::crypto::aws_lc_rs::default_provider()
+        .secure_random
+        .fill(&mut id)
+        .map_err(|_| io::Error::other("cannot generate a session id"))?;
+    Ok(id)
+}
+
+// 服务器端: 正在运行的可恢复会话, 按会话ID查找
+#[derive(Default)]
+pub struct ResumeRegistry {
+    sessions: Mutex<HashMap<SessionId, mpsc::Sender<Handoff>>>,
+}
+
+impl ResumeRegistry {
+    pub fn new() -> Self {
+        ResumeRegistry::default()
+    }
+
+    // 把新连接交给对应的会话, 由会话回复 agent; 会话不存在时直接回复失败, 返回 false
+    pub async fn resume(&self, mut link: BoxedStream, request: ResumeRequest) -> io::Result<bool> {
+        let sender = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&request.session_id)
+            .cloned();
+        if let Some(sender) = sender {
+            match sender.send((link, request.received)).await {
+                Ok(()) => return Ok(true),
+                // 会话刚好结束
+                Err(mpsc::error::SendError((returned, _))) => link = returned,
+            }
+        }
+        handshake::write_resume_reply(&mut link, None).await?;
+        Ok(false)
+    }
+}
+
+// 会话结束时从登记表中移除
+struct Registration {
+    registry: Arc<ResumeRegistry>,
+    id: SessionId,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+// 底层连接断开后如何获得新连接
+enum Relink {
+    // agent: 主动重连并发送恢复帧
+    Client { id: SessionId, connector: Connector },
+    // 服务器: 等待 agent 的新连接, 新连接也可能在旧连接看起来还正常时到达 (NAT 重新映射)
+    Server {
+        incoming: mpsc::Receiver<Handoff>,
+        _registration: Registration,
+    },
+}
+
+// 已发送但未被确认的数据
+struct Outbound {
+    buf: VecDeque<u8>,
+    start: u64, // buf[0] 在整个会话中的偏移
+    eof: bool,  // 使用者不会再写入
+}
+
+struct Shared {
+    outbound: Mutex<Outbound>,
+    data: Notify,        // 有新数据、需要 ACK 或使用者关闭 -> 唤醒写出任务
+    space: Notify,       // 重放缓冲区有了空间 -> 唤醒读取使用者数据的任务
+    inbound: mpsc::UnboundedSender<Option<Vec<u8>>>, // 收到的数据, None 表示对端发送了 CLOSE
+    received: AtomicU64,  // 已经收到 (放入队列) 的字节数, 恢复时告知对端
+    delivered: AtomicU64, // 已经交给使用者的字节数
+    acked: AtomicU64,     // 最近一次 ACK 中告知对端的字节数
+    close_sent: AtomicBool,
+    close_received: AtomicBool,
+    link_timeout: Option<Duration>,
+}
+
+enum Outgoing {
+    Data(Vec<u8>),
+    Ack(u64),
+    Close,
+}
+
+impl Shared {
+    fn received(&self) -> u64 {
+        self.received.load(Ordering::Acquire)
+    }
+
+    // 对端确认收到了 received 字节, 丢弃这之前的数据
+    fn acknowledge(&self, received: u64) {
+        let mut outbound = self.outbound.lock().unwrap();
+        if received > outbound.start {
+            let count = ((received - outbound.start) as usize).min(outbound.buf.len());
+            outbound.buf.drain(..count);
+            outbound.start += count as u64;
+            self.space.notify_one();
+        }
+    }
+
+    // 恢复时对端告知已经收到的字节数, 这之后的数据必须还在重放缓冲区中
+    fn rewind(&self, peer_received: u64) -> bool {
+        let (start, end) = {
+            let outbound = self.outbound.lock().unwrap();
+            (outbound.start, outbound.start + outbound.buf.len() as u64)
+        };
+        if peer_received < start || peer_received > end {
+            return false;
+        }
+        self.acknowledge(peer_received);
+        true
+    }
+
+    // 下一个要写出的帧, cursor 是下一个要发送的数据偏移, close_sent 表示这条连接上是否已经发送过 CLOSE
+    fn next_outgoing(&self, cursor: &mut u64, close_sent: bool) -> Option<Outgoing> {
+        let delivered = self.delivered.load(Ordering::Acquire);
+        if delivered - self.acked.load(Ordering::Acquire) >= ACK_THRESHOLD {
+            self.acked.store(delivered, Ordering::Release);
+            return Some(Outgoing::Ack(delivered));
+        }
+
+        let outbound = self.outbound.lock().unwrap();
+        *cursor = (*cursor).max(outbound.start);
+        let offset = (*cursor - outbound.start) as usize;
+        if offset < outbound.buf.len() {
+            let len = (outbound.buf.len() - offset).min(MAX_FRAME_PAYLOAD);
+            let data = outbound.buf.range(offset..offset + len).copied().collect();
+            *cursor += len as u64;
+            return Some(Outgoing::Data(data));
+        }
+        (outbound.eof && !close_sent).then_some(Outgoing::Close)
+    }
+}
+
+// 一条底层连接结束的方式
+enum LinkEnd {
+    ClosedLocally, // 双方都发送过 CLOSE, 最后一个 CLOSE 由这一端发送
+    ClosedByPeer,  // 双方都发送过 CLOSE, 最后一个 CLOSE 由对端发送
+    Failed(io::Error),
+    Replaced(Handoff),
+}
+
+// 可恢复会话的使用者一端
+pub struct ResumableStream {
+    inner: DuplexStream,
+    closed: watch::Receiver<Option<CloseReason>>,
+}
+
+impl ResumableStream {
+    // agent 端: link 是已经完成认证的隧道, connector 用于断开后建立新连接
+    pub fn client(
+        link: BoxedStream,
+        id: SessionId,
+        connector: Connector,
+        link_timeout: Option<Duration>,
+    ) -> Self {
+        Self::start(link, Relink::Client { id, connector }, link_timeout)
+    }
+
+    // 服务器端: 在 registry 中登记, 恢复帧到达时由 registry 交给这个会话
+    pub fn server(
+        link: BoxedStream,
+        id: SessionId,
+        registry: &Arc<ResumeRegistry>,
+        link_timeout: Option<Duration>,
+    ) -> Self {
+        // 同一个会话同时只会有一个恢复中的连接
+        let (sender, incoming) = mpsc::channel(1);
+        registry.sessions.lock().unwrap().insert(id, sender);
+        Self::start(
+            link,
+            Relink::Server {
+                incoming,
+                _registration: Registration {
+                    registry: registry.clone(),
+                    id,
+                },
+            },
+            link_timeout,
+        )
+    }
+
+    fn start(link: BoxedStream, relink: Relink, link_timeout: Option<Duration>) -> Self {
+        let (inner, internal) = tokio::io::duplex(STREAM_BUFFER);
+        let (internal_reader, internal_writer) = tokio::io::split(internal);
+        let (inbound, queue) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            outbound: Mutex::new(Outbound {
+                buf: VecDeque::new(),
+                start: 0,
+                eof: false,
+            }),
+            data: Notify::new(),
+            space: Notify::new(),
+            inbound,
+            received: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            acked: AtomicU64::new(0),
+            close_sent: AtomicBool::new(false),
+            close_received: AtomicBool::new(false),
+            link_timeout,
+        });
+        let (closed_sender, closed) = watch::channel(None);
+
+        let pumps = [
+            tokio::spawn(outbound_pump(internal_reader, shared.clone())),
+            tokio::spawn(inbound_pump(internal_writer, queue, shared.clone())),
+        ];
+        tokio::spawn(drive(link, relink, shared, pumps, closed_sender));
+        ResumableStream { inner, closed }
+    }
+}
+
+// 使用者写入的数据 -> 重放缓冲区
+async fn outbound_pump(mut inner: ReadHalf<DuplexStream>, shared: Arc<Shared>) {
+    let mut chunk = vec![0u8; MAX_FRAME_PAYLOAD];
+    loop {
+        while shared.outbound.lock().unwrap().buf.len() >= REPLAY_BUFFER {
+            shared.space.notified().await;
+        }
+        let n = inner.read(&mut chunk).await.unwrap_or(0);
+        let mut outbound = shared.outbound.lock().unwrap();
+        if n == 0 {
+            outbound.eof = true;
+            drop(outbound);
+            shared.data.notify_one();
+            return;
+        }
+        outbound.buf.extend(&chunk[..n]);
+        drop(outbound);
+        shared.data.notify_one();
+    }
+}
+
+// 收到的数据 -> 使用者
+async fn inbound_pump(
+    mut inner: WriteHalf<DuplexStream>,
+    mut queue: mpsc::UnboundedReceiver<Option<Vec<u8>>>,
+    shared: Arc<Shared>,
+) {
+    let mut discard = false;
+    while let Some(Some(data)) = queue.recv().await {
+        // 使用者已经关闭时继续确认, 但丢弃数据
+        if !discard && inner.write_all(&data).await.is_err() {
+            discard = true;
+        }
+        let len = data.len() as u64;
+        let delivered = shared.delivered.fetch_add(len, Ordering::AcqRel) + len;
+        if delivered - shared.acked.load(Ordering::Acquire) >= ACK_THRESHOLD {
+            shared.data.notify_one();
+        }
+    }
+    // 对端不会再发送数据, 使用者读到 EOF
+    let _ = inner.shutdown().await;
+}
+
+// 管理底层连接: 连接断开时获取新连接并恢复, 直到会话正常关闭或无法恢复
+async fn drive(
+    mut link: BoxedStream,
+    mut relink: Relink,
+    shared: Arc<Shared>,
+    [outbound, inbound]: [JoinHandle<()>; 2],
+    closed: watch::Sender<Option<CloseReason>>,
+) {
+    let mut cursor = 0;
+    let reason = loop {
+        let end = run_link(link, &shared, cursor, &mut relink).await;
+        let (next, peer_received) = match end {
+            LinkEnd::ClosedLocally => break CloseReason::ClosedLocally,
+            LinkEnd::ClosedByPeer => break CloseReason::ClosedByPeer(String::new()),
+            LinkEnd::Replaced(handoff) => {
+                info!("Tunnel link replaced by a resumed connection");
+                handoff
+            }
+            LinkEnd::Failed(e) => {
+                info!("Tunnel link lost ({}), waiting to resume", e);
+                match reconnect(&mut relink, &shared).await {
+                    Ok(handoff) => handoff,
+                    Err(reason) => break reason,
+                }
+            }
+        };
+        link = next;
+
+        if !shared.rewind(peer_received) {
+            // 服务器端需要告诉 agent 恢复失败
+            if matches!(relink, Relink::Server { .. }) {
+                let _ = handshake::write_resume_reply(&mut link, None).await;
+            }
+            break CloseReason::Error("cannot resume: unacknowledged data was lost".to_string());
+        }
+        if matches!(relink, Relink::Server { .. })
+            && let Err(e) = handshake::write_resume_reply(&mut link, Some(shared.received())).await
+        {
+            // 新连接刚建立就出错, 继续等待下一次恢复
+            debug!("Failed to answer resume request: {}", e);
+        }
+        cursor = peer_received;
+        info!("Tunnel session resumed at offset {}", peer_received);
+    };
+
+    debug!("Resumable tunnel closed: {}", reason);
+    outbound.abort();
+    // 正常关闭时队列中的数据继续交给使用者, 之后使用者读到 EOF; 否则立即读到 EOF
+    if !matches!(reason, CloseReason::ClosedLocally | CloseReason::ClosedByPeer(_)) {
+        inbound.abort();
+    }
+    closed.send_replace(Some(reason));
+}
+
+async fn run_link(
+    link: BoxedStream,
+    shared: &Shared,
+    cursor: u64,
+    relink: &mut Relink,
+) -> LinkEnd {
+    let (reader, writer) = tokio::io::split(link);
+    let replaced = async {
+        match relink {
+            Relink::Server { incoming, .. } => incoming.recv().await,
+            Relink::Client { .. } => None,
+        }
+    };
+    tokio::select! {
+        result = read_link(reader, shared) => match result {
+            Ok(()) => LinkEnd::ClosedByPeer,
+            Err(e) => LinkEnd::Failed(e),
+        },
+        result = write_link(writer, shared, cursor) => match result {
+            Ok(()) => LinkEnd::ClosedLocally,
+            Err(e) => LinkEnd::Failed(e),
+        },
+        Some(handoff) = replaced => LinkEnd::Replaced(handoff),
+    }
+}
+
+// 获取新连接: agent 带退避地重连, 服务器等待 agent 的恢复帧
+async fn reconnect(relink: &mut Relink, shared: &Shared) -> Result<Handoff, CloseReason> {
+    match relink {
+        Relink::Client { id, connector } => {
+            let deadline = Instant::now() + RESUME_WINDOW;
+            let mut delay = Duration::from_millis(250);
+            loop {
+                let request = ResumeRequest {
+                    session_id: *id,
+                    received: shared.received(),
+                };
+                let attempt = tokio::time::timeout(RESUME_ATTEMPT_TIMEOUT, async {
+                    let mut link = connector().await?;
+                    let peer_received = handshake::client_resume(&mut link, &request).await?;
+                    Ok::<_, io::Error>(peer_received.map(|received| (link, received)))
+                })
+                .await;
+                match attempt {
+                    Ok(Ok(Some(handoff))) => return Ok(handoff),
+                    Ok(Ok(None)) => {
+                        return Err(CloseReason::ClosedByPeer(
+                            "relay no longer knows this session".to_string(),
+                        ));
+                    }
+                    Ok(Err(e)) => warn!("Failed to resume tunnel session: {}", e),
+                    Err(_) => warn!("Failed to resume tunnel session: timed out"),
+                }
+                if Instant::now() + delay >= deadline {
+                    return Err(CloseReason::TimedOut);
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+        Relink::Server { incoming, .. } => {
+            match tokio::time::timeout(SERVER_RESUME_WINDOW, incoming.recv()).await {
+                Ok(Some(handoff)) => Ok(handoff),
+                _ => Err(CloseReason::TimedOut),
+            }
+        }
+    }
+}
+
+// 对端的帧 -> 使用者
+async fn read_link(
+    reader: ReadHalf<BoxedStream>,
+    shared: &Shared,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    loop {
+        let kind = match shared.link_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, reader.read_u8())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no data from peer"))??,
+            None => reader.read_u8().await?,
+        };
+        match kind {
+            FRAME_DATA => {
+                let len = reader.read_u32().await? as usize;
+                if len == 0 || len > MAX_FRAME_PAYLOAD {
+                    return Err(protocol_error(format!("invalid data frame length {}", len)));
+                }
+                let queued = shared.received() - shared.delivered.load(Ordering::Acquire);
+                if queued + len as u64 > MAX_QUEUED {
+                    return Err(protocol_error(format!(
+                        "peer sent {} unacknowledged bytes, more than its replay buffer",
+                        queued + len as u64
+                    )));
+                }
+                let mut payload = vec![0u8; len];
+                reader.read_exact(&mut payload).await?;
+                // 放入队列和计数之间没有 await, 连接被替换时不会只完成一半
+                let _ = shared.inbound.send(Some(payload));
+                shared.received.fetch_add(len as u64, Ordering::AcqRel);
+            }
+            FRAME_ACK => shared.acknowledge(reader.read_u64().await?),
+            FRAME_CLOSE => {
+                // 恢复后对端可能重发 CLOSE
+                if !shared.close_received.swap(true, Ordering::SeqCst) {
+                    let _ = shared.inbound.send(None);
+                }
+                if shared.close_sent.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+            }
+            kind => return Err(protocol_error(format!("unknown frame type {}", kind))),
+        }
+    }
+}
+
+// 重放缓冲区中的数据和 ACK -> 对端, 没有更多数据时才 flush
+async fn write_link(writer: WriteHalf<BoxedStream>, shared: &Shared, mut cursor: u64) -> io::Result<()> {
+    let mut writer = BufWriter::new(writer);
+    let mut close_sent = false;
+    loop {
+        match shared.next_outgoing(&mut cursor, close_sent) {
+            Some(Outgoing::Data(data)) => {
+                writer.write_u8(FRAME_DATA).await?;
+                writer.write_u32(data.len() as u32).await?;
+                writer.write_all(&data).await?;
+            }
+            Some(Outgoing::Ack(received)) => {
+                writer.write_u8(FRAME_ACK).await?;
+                writer.write_u64(received).await?;
+            }
+            Some(Outgoing::Close) => {
+                writer.write_u8(FRAME_CLOSE).await?;
+                writer.flush().await?;
+                close_sent = true;
+                // 之后只发送 ACK, 直到对端也发送 CLOSE
+                shared.close_sent.store(true, Ordering::SeqCst);
+                if shared.close_received.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+            }
+            None => {
+                writer.flush().await?;
+                shared.data.notified().await;
+            }
+        }
+    }
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl TunnelStream for ResumableStream {
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.closed.borrow().clone()
+    }
+}
+
+impl AsyncRead for ResumableStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ResumableStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::ClientFrame;
+    use crate::transport::memory;
+    use crate::transport::{Transport, TunnelListener};
+    use std::net::SocketAddr;
+
+    // 测试不应该等待这么久, 超时说明卡住了
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // 在底层连接和会话之间插入一段转发, 中止返回的任务即可模拟连接断开
+    fn cuttable(mut stream: BoxedStream) -> (BoxedStream, JoinHandle<()>) {
+        let (near, mut far) = tokio::io::duplex(64 * 1024);
+        let relay = tokio::spawn(async move {
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut far).await;
+        });
+        (Box::new(near), relay)
+    }
+
+    #[tokio::test]
+    async fn stream_survives_a_dropped_link() {
+        let (transport, mut listener) = memory::pair(SocketAddr::from(([127, 0, 0, 1], 25565)));
+        let transport = Arc::new(transport);
+        let registry = Arc::new(ResumeRegistry::new());
+        let id = new_session_id().unwrap();
+
+        let client_link = transport.connect().await.unwrap().stream;
+        let (server_link, cut) = cuttable(listener.accept().await.unwrap().stream);
+        // 服务器原样发回收到的数据
+        let server = ResumableStream::server(server_link, id, &registry, None);
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(server);
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            writer.shutdown().await.unwrap();
+        });
+        // 之后的连接都是恢复请求
+        let resumes = registry.clone();
+        tokio::spawn(async move {
+            while let Ok(mut tunnel) = listener.accept().await {
+                match ClientFrame::read_from(&mut tunnel.stream, 1024).await.unwrap() {
+                    ClientFrame::Resume(request) => {
+                        assert!(resumes.resume(tunnel.stream, request).await.unwrap());
+                    }
+                    other => panic!("expected a resume request, got {:?}", other),
+                }
+            }
+        });
+
+        let connector: Connector = Arc::new(move || {
+            let transport = transport.clone();
+            Box::pin(async move { Ok(transport.connect().await?.stream) })
+        });
+        let client = ResumableStream::client(client_link, id, connector, None);
+        let (mut reader, mut writer) = tokio::io::split(client);
+        tokio::time::timeout(TEST_TIMEOUT, async {
+            writer.write_all(b"before the drop;").await.unwrap();
+            let mut echoed = [0u8; 16];
+            reader.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(&echoed, b"before the drop;");
+
+            // 断线期间写入的数据在恢复后送达, 不丢失也不重复
+            cut.abort();
+            writer.write_all(b"after the drop").await.unwrap();
+            writer.shutdown().await.unwrap();
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, b"after the drop");
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_sessions_are_not_resumed() {
+        let registry = ResumeRegistry::new();
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client: BoxedStream = Box::new(client);
+        let request = ResumeRequest {
+            session_id: new_session_id().unwrap(),
+            received: 0,
+        };
+        let reply = tokio::spawn(async move { handshake::client_resume(&mut client, &request).await });
+        let mut server: BoxedStream = Box::new(server);
+        let ClientFrame::Resume(received) = ClientFrame::read_from(&mut server, 1024).await.unwrap()
+        else {
+            panic!("expected a resume request");
+        };
+        assert_eq!(received, request);
+        assert!(!registry.resume(server, received).await.unwrap());
+        assert_eq!(reply.await.unwrap().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn peer_overrunning_its_replay_buffer_loses_the_link() {
+        let (raw, link) = tokio::io::duplex(64 * 1024);
+        let registry = Arc::new(ResumeRegistry::new());
+        // 使用者不读取, 对端无视确认继续发送
+        let server = ResumableStream::server(Box::new(link), new_session_id().unwrap(), &registry, None);
+        let (_raw_reader, mut raw_writer) = tokio::io::split(raw);
+        let mut frame = vec![FRAME_DATA];
+        frame.extend_from_slice(&(MAX_FRAME_PAYLOAD as u32).to_be_bytes());
+        frame.resize(5 + MAX_FRAME_PAYLOAD, 0);
+
+        let mut sent = 0u64;
+        let result = tokio::time::timeout(TEST_TIMEOUT, async {
+            while sent <= 2 * MAX_QUEUED {
+                raw_writer.write_all(&frame).await?;
+                sent += MAX_FRAME_PAYLOAD as u64;
+            }
+            Ok::<_, io::Error>(())
+        })
+        .await
+        .unwrap();
+        // 超出上限的帧按协议错误处理, 连接被丢弃, 会话等待 agent 恢复
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert!(sent <= MAX_QUEUED + STREAM_BUFFER as u64 + 2 * MAX_FRAME_PAYLOAD as u64);
+        assert_eq!(server.close_reason(), None);
+    }
+}
@@ -0,0 +1,81 @@
+// KCP 传输: 每条隧道是一个独立的 KCP 会话
+use super::{PeerInfo, Tunnel, TunnelListener, TunnelStream, Transport};
+use futures::future::BoxFuture;
+use std::io;
+use std::net::SocketAddr;
+use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+
+const PROTOCOL: &str = "KCP";
+
+impl TunnelStream for KcpStream {}
+
+pub struct KcpTransport {
+    server_addr: SocketAddr,
+    config: KcpConfig,
+}
+
+impl KcpTransport {
+    pub fn new(server_addr: SocketAddr, config: KcpConfig) -> Self {
+        KcpTransport {
+            server_addr,
+            config,
+        }
+    }
+}
+
+impl Transport for KcpTransport {
+    fn protocol(&self) -> &'static str {
+        PROTOCOL
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<Tunnel>> {
+        Box::pin(async move {
+            // tokio-kcp 的 connect 会自动处理 UDP socket
+            let stream = KcpStream::connect(&self.config, self.server_addr).await?;
+            Ok(Tunnel {
+                stream: Box::new(stream),
+                peer: PeerInfo {
+                    addr: self.server_addr,
+                    protocol: PROTOCOL,
+                    local_addr: None, // tokio_kcp 不提供会话的本端地址
+                },
+            })
+        })
+    }
+}
+
+pub struct KcpTunnelListener {
+    listener: KcpListener,
+}
+
+impl KcpTunnelListener {
+    pub async fn bind(config: KcpConfig, listen_addr: SocketAddr) -> io::Result<Self> {
+        Ok(KcpTunnelListener {
+            listener: KcpListener::bind(config, listen_addr).await?,
+        })
+    }
+}
+
+impl TunnelListener for KcpTunnelListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Tunnel>> {
+        Box::pin(async move {
+            let (stream, addr) = self.listener.accept().await?;
+            Ok(Tunnel {
+                stream: Box::new(stream),
+                peer: PeerInfo {
+                    addr,
+                    protocol: PROTOCOL,
+                    local_addr: None, // tokio_kcp 不提供会话的本端地址
+                },
+            })
+        })
+    }
+}
@@ -0,0 +1,58 @@
+// 内存传输: 两端在同一个进程内, 通过 tokio::io::duplex 连接
+// 用于在没有网络的情况下驱动握手和转发代码
+use super::{ChannelListener, PeerInfo, Transport, Tunnel, TunnelStream};
+use futures::future::BoxFuture;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+
+const PROTOCOL: &str = "memory";
+
+// 每个方向的缓冲区大小
+const BUFFER_SIZE: usize = 64 * 1024;
+
+impl TunnelStream for DuplexStream {}
+
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    listener: mpsc::Sender<Tunnel>,
+}
+
+// 创建一对互相连接的传输和监听器, addr 只用于 PeerInfo (两端的地址都是它)
+pub fn pair(addr: SocketAddr) -> (MemoryTransport, ChannelListener) {
+    let (listener, channel) = ChannelListener::new(addr);
+    (MemoryTransport { addr, listener }, channel)
+}
+
+impl Transport for MemoryTransport {
+    fn protocol(&self) -> &'static str {
+        PROTOCOL
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<Tunnel>> {
+        Box::pin(async move {
+            let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+            let peer = PeerInfo {
+                addr: self.addr,
+                protocol: PROTOCOL,
+                local_addr: Some(self.addr),
+            };
+            self.listener
+                .send(Tunnel {
+                    stream: Box::new(server),
+                    peer,
+                })
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, "listener has been closed"))?;
+            Ok(Tunnel {
+                stream: Box::new(client),
+                peer,
+            })
+        })
+    }
+}
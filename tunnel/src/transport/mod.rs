@@ -0,0 +1,146 @@
+// 隧道传输抽象: agent 通过 Transport 建立隧道, 中继服务器通过 TunnelListener 接受隧道
+// 握手和转发代码只依赖这里的接口, 不关心底层是 KCP、QUIC、TCP 还是 TLS
+pub mod kcp;
+pub mod memory;
+pub mod quic;
+pub mod tcp;
+pub mod tls;
+
+use futures::future::BoxFuture;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+
+// 一条隧道流, 对应一个 Minecraft 连接
+pub trait TunnelStream: AsyncRead + AsyncWrite + Unpin + Send {
+    // 底层连接关闭的原因 (如果传输协议能提供), 用于在日志中区分超时、对端关闭等情况
+    fn close_reason(&self) -> Option<CloseReason> {
+        None
+    }
+}
+
+pub type BoxedStream = Box<dyn TunnelStream>;
+
+// 隧道对端的信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub protocol: &'static str, // 传输协议名, 例如 "KCP"
+    // 这条隧道在本端的地址 (服务器端即 agent 实际连接的地址), 传输无法提供时为空
+    pub local_addr: Option<SocketAddr>,
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} via {}", self.addr, self.protocol)
+    }
+}
+
+// 建立好的隧道
+pub struct Tunnel {
+    pub stream: BoxedStream,
+    pub peer: PeerInfo,
+}
+
+// agent 端: 为每个游戏连接建立一条到中继服务器的隧道
+pub trait Transport: Send + Sync {
+    fn protocol(&self) -> &'static str;
+
+    fn server_addr(&self) -> SocketAddr;
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<Tunnel>>;
+}
+
+// 服务器端: 接受 agent 建立的隧道
+pub trait TunnelListener: Send {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Tunnel>>;
+}
+
+// 隧道关闭的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    TimedOut,             // 对端长时间无响应
+    Reset,                // 连接被重置或中断
+    ClosedByPeer(String), // 对端主动关闭, 附带对端给出的原因
+    ClosedLocally,
+    Error(String),
+}
+
+impl From<io::Error> for CloseReason {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut => CloseReason::TimedOut,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => CloseReason::Reset,
+            _ => CloseReason::Error(err.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::TimedOut => write!(f, "timed out"),
+            CloseReason::Reset => write!(f, "connection reset"),
+            CloseReason::ClosedByPeer(reason) if reason.is_empty() => write!(f, "closed by peer"),
+            CloseReason::ClosedByPeer(reason) => write!(f, "closed by peer: {}", reason),
+            CloseReason::ClosedLocally => write!(f, "closed locally"),
+            CloseReason::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// 在隧道和本地 (或后端) 连接之间双向转发, 直到两个方向都结束
+// 返回 (隧道 -> 本地, 本地 -> 隧道) 的字节数
+pub async fn relay<L>(tunnel: &mut BoxedStream, local: &mut L) -> Result<(u64, u64), CloseReason>
+where
+    L: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    tokio::io::copy_bidirectional(tunnel, local)
+        .await
+        // 传输层给出的原因比 io 错误更准确
+        .map_err(|e| tunnel.close_reason().unwrap_or_else(|| CloseReason::from(e)))
+}
+
+// 由后台任务产生隧道的监听器: 需要额外握手的传输 (QUIC / TLS) 在后台完成握手,
+// 一个慢速的对端不会阻塞其他连接的 accept
+pub struct ChannelListener {
+    local_addr: SocketAddr,
+    incoming: mpsc::Receiver<Tunnel>,
+}
+
+// 后台任务与监听器之间的队列长度
+const ACCEPT_BACKLOG: usize = 128;
+
+impl ChannelListener {
+    fn new(local_addr: SocketAddr) -> (mpsc::Sender<Tunnel>, Self) {
+        let (sender, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        (
+            sender,
+            ChannelListener {
+                local_addr,
+                incoming,
+            },
+        )
+    }
+}
+
+impl TunnelListener for ChannelListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Tunnel>> {
+        Box::pin(async move {
+            self.incoming.recv().await.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "listener has been closed")
+            })
+        })
+    }
+}
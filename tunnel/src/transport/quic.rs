@@ -0,0 +1,266 @@
+// QUIC 传输 (quinn + rustls)
+// 每个 Minecraft 连接使用一条双向流, 同一个 agent 的所有流共用一个 QUIC 连接
+use super::tls;
+use super::{ChannelListener, CloseReason, PeerInfo, Transport, Tunnel, TunnelStream};
+use futures::future::BoxFuture;
+use log::{debug, info};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, ConnectionError, Endpoint, IdleTimeout, RecvStream, SendStream, TransportConfig};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Mutex;
+
+const PROTOCOL: &str = "QUIC";
+
+// 连接参数, 两端各自配置
+#[derive(Debug, Clone, Copy)]
+pub struct QuicOptions {
+    pub idle_timeout: Duration, // 无数据往来多久后断开
+    pub keep_alive: Duration,   // 保活包间隔, 需要小于 idle_timeout 和 NAT 超时
+}
+
+impl Default for QuicOptions {
+    fn default() -> Self {
+        QuicOptions {
+            idle_timeout: Duration::from_secs(30),
+            keep_alive: Duration::from_secs(10),
+        }
+    }
+}
+
+impl QuicOptions {
+    fn transport_config(&self) -> io::Result<Arc<TransportConfig>> {
+        let idle_timeout = IdleTimeout::try_from(self.idle_timeout).map_err(io::Error::other)?;
+        let mut transport = TransportConfig::default();
+        transport
+            .max_idle_timeout(Some(idle_timeout))
+            .keep_alive_interval(Some(self.keep_alive));
+        Ok(Arc::new(transport))
+    }
+}
+
+pub struct QuicTransport {
+    server_addr: SocketAddr,
+    server_name: String,
+    endpoint: Endpoint,
+    connection: Mutex<Option<Connection>>, // 复用的 QUIC 连接, 断开后按需重连
+}
+
+impl QuicTransport {
+    // 需要在 tokio 运行时内调用; server_name 用于校验服务器证书
+    pub fn new(
+        server_addr: SocketAddr,
+        server_name: &str,
+        ca_file: Option<&Path>,
+        options: &QuicOptions,
+    ) -> io::Result<Self> {
+        tls::parse_server_name(server_name)?;
+        let crypto = QuicClientConfig::try_from(tls::client_config(ca_file)?).map_err(io::Error::other)?;
+        let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+        config.transport_config(options.transport_config()?);
+
+        let mut endpoint = Endpoint::client(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+        endpoint.set_default_client_config(config);
+        Ok(QuicTransport {
+            server_addr,
+            server_name: server_name.to_string(),
+            endpoint,
+            connection: Mutex::new(None),
+        })
+    }
+
+    fn tunnel(&self, connection: Connection, send: SendStream, recv: RecvStream) -> Tunnel {
+        Tunnel {
+            stream: Box::new(QuicStream {
+                connection,
+                send,
+                recv,
+            }),
+            peer: PeerInfo {
+                addr: self.server_addr,
+                protocol: PROTOCOL,
+                local_addr: self.endpoint.local_addr().ok(),
+            },
+        }
+    }
+
+    // 重连期间持有锁, 同时失败的多个游戏连接只建立一个新连接;
+    // failed 是调用方刚刚失败的连接, 其他调用方已经换上的新连接直接复用
+    async fn reconnect(&self, failed: Option<usize>) -> io::Result<Connection> {
+        let mut connection = self.connection.lock().await;
+        if let Some(existing) = connection.as_ref()
+            && Some(existing.stable_id()) != failed
+            && existing.close_reason().is_none()
+        {
+            return Ok(existing.clone());
+        }
+
+        let fresh = self
+            .endpoint
+            .connect(self.server_addr, &self.server_name)
+            .map_err(io::Error::other)?
+            .await
+            .map_err(io::Error::other)?;
+        info!("Established QUIC connection to {}", self.server_addr);
+        *connection = Some(fresh.clone());
+        Ok(fresh)
+    }
+}
+
+impl Transport for QuicTransport {
+    fn protocol(&self) -> &'static str {
+        PROTOCOL
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<Tunnel>> {
+        Box::pin(async move {
+            // 打开流时不持有锁: 对端的流数量达到上限时 open_bi 会等待, 不能挡住其他连接
+            let current = self.connection.lock().await.clone();
+            if let Some(existing) = &current
+                && existing.close_reason().is_none()
+                && let Ok((send, recv)) = existing.open_bi().await
+            {
+                return Ok(self.tunnel(existing.clone(), send, recv));
+            }
+
+            let connection = self.reconnect(current.map(|c| c.stable_id())).await?;
+            let (send, recv) = connection.open_bi().await.map_err(io::Error::other)?;
+            Ok(self.tunnel(connection, send, recv))
+        })
+    }
+}
+
+// 监听 QUIC 连接, 每条对端打开的双向流作为一条隧道
+pub fn listen(
+    listen_addr: SocketAddr,
+    cert_file: &Path,
+    key_file: &Path,
+    options: &QuicOptions,
+) -> io::Result<ChannelListener> {
+    let crypto = QuicServerConfig::try_from(tls::server_config(cert_file, key_file)?).map_err(io::Error::other)?;
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(options.transport_config()?);
+    let endpoint = Endpoint::server(config, listen_addr)?;
+    let listen_addr = endpoint.local_addr()?;
+    let (sender, channel) = ChannelListener::new(listen_addr);
+
+    tokio::spawn(async move {
+        loop {
+            let incoming = tokio::select! {
+                // 监听器已被丢弃
+                _ = sender.closed() => break,
+                incoming = endpoint.accept() => match incoming {
+                    Some(incoming) => incoming,
+                    None => break,
+                },
+            };
+
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let connection = match incoming.await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        debug!("QUIC handshake failed: {}", e);
+                        return;
+                    }
+                };
+                let addr = connection.remote_address();
+                // 监听通配地址时 agent 实际连接的地址, 平台不支持时为空
+                let local_addr = connection
+                    .local_ip()
+                    .map(|ip| SocketAddr::new(ip, listen_addr.port()));
+                info!("Accepted QUIC connection from {}", addr);
+
+                loop {
+                    let (send, recv) = match connection.accept_bi().await {
+                        Ok(streams) => streams,
+                        Err(e) => {
+                            info!("QUIC connection from {} closed: {}", addr, e);
+                            break;
+                        }
+                    };
+                    let tunnel = Tunnel {
+                        stream: Box::new(QuicStream {
+                            connection: connection.clone(),
+                            send,
+                            recv,
+                        }),
+                        peer: PeerInfo {
+                            addr,
+                            protocol: PROTOCOL,
+                            local_addr,
+                        },
+                    };
+                    if sender.send(tunnel).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        endpoint.close(0u32.into(), b"shutting down");
+    });
+
+    Ok(channel)
+}
+
+// 把一条 QUIC 双向流包装成一个可读写的流
+pub struct QuicStream {
+    connection: Connection, // 用于查询连接关闭的原因
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl TunnelStream for QuicStream {
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.connection.close_reason().map(|e| match e {
+            ConnectionError::TimedOut => CloseReason::TimedOut,
+            ConnectionError::Reset => CloseReason::Reset,
+            ConnectionError::LocallyClosed => CloseReason::ClosedLocally,
+            ConnectionError::ApplicationClosed(close) => {
+                CloseReason::ClosedByPeer(String::from_utf8_lossy(&close.reason).into_owned())
+            }
+            ConnectionError::ConnectionClosed(close) => {
+                CloseReason::ClosedByPeer(String::from_utf8_lossy(&close.reason).into_owned())
+            }
+            e => CloseReason::Error(e.to_string()),
+        })
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
+    }
+}
@@ -0,0 +1,78 @@
+// 明文 TCP 传输, 用于内网部署或前面已有 TLS 终结的场景
+use super::{PeerInfo, Transport, Tunnel, TunnelListener, TunnelStream};
+use futures::future::BoxFuture;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+
+const PROTOCOL: &str = "TCP";
+
+impl TunnelStream for TcpStream {}
+
+pub struct TcpTransport {
+    server_addr: SocketAddr,
+}
+
+impl TcpTransport {
+    pub fn new(server_addr: SocketAddr) -> Self {
+        TcpTransport { server_addr }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn protocol(&self) -> &'static str {
+        PROTOCOL
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<Tunnel>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(self.server_addr).await?;
+            stream.set_nodelay(true)?;
+            Ok(Tunnel {
+                peer: PeerInfo {
+                    addr: self.server_addr,
+                    protocol: PROTOCOL,
+                    local_addr: stream.local_addr().ok(),
+                },
+                stream: Box::new(stream),
+            })
+        })
+    }
+}
+
+pub struct TcpTunnelListener {
+    listener: TcpListener,
+}
+
+impl TcpTunnelListener {
+    pub async fn bind(listen_addr: SocketAddr) -> io::Result<Self> {
+        Ok(TcpTunnelListener {
+            listener: TcpListener::bind(listen_addr).await?,
+        })
+    }
+}
+
+impl TunnelListener for TcpTunnelListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Tunnel>> {
+        Box::pin(async move {
+            let (stream, addr) = self.listener.accept().await?;
+            stream.set_nodelay(true)?;
+            Ok(Tunnel {
+                peer: PeerInfo {
+                    addr,
+                    protocol: PROTOCOL,
+                    local_addr: stream.local_addr().ok(),
+                },
+                stream: Box::new(stream),
+            })
+        })
+    }
+}
@@ -0,0 +1,192 @@
+// TLS over TCP 传输, 以及 QUIC 共用的证书加载
+// 适用于 UDP 被完全封锁的网络, 代价是 TCP 的队头阻塞
+use super::{ChannelListener, PeerInfo, Transport, Tunnel, TunnelStream};
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector, client, server};
+
+const PROTOCOL: &str = "TLS";
+
+// TLS ALPN, 防止误连到其他服务
+pub const ALPN: &[u8] = b"tzca";
+
+// 完成 TLS 握手的最长时间
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl TunnelStream for client::TlsStream<TcpStream> {}
+
+impl TunnelStream for server::TlsStream<TcpStream> {}
+
+pub struct TlsTransport {
+    server_addr: SocketAddr,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+}
+
+impl TlsTransport {
+    // server_name 用于校验服务器证书; ca_file 为空时信任内置的 webpki 根证书
+    pub fn new(server_addr: SocketAddr, server_name: &str, ca_file: Option<&Path>) -> io::Result<Self> {
+        Ok(TlsTransport {
+            server_addr,
+            server_name: parse_server_name(server_name)?,
+            connector: TlsConnector::from(Arc::new(client_config(ca_file)?)),
+        })
+    }
+}
+
+impl Transport for TlsTransport {
+    fn protocol(&self) -> &'static str {
+        PROTOCOL
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    fn connect(&self) -> BoxFuture<'_, io::Result<Tunnel>> {
+        Box::pin(async move {
+            let tcp = TcpStream::connect(self.server_addr).await?;
+            tcp.set_nodelay(true)?;
+            let local_addr = tcp.local_addr().ok();
+            let stream = tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                self.connector.connect(self.server_name.clone(), tcp),
+            )
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+            Ok(Tunnel {
+                stream: Box::new(stream),
+                peer: PeerInfo {
+                    addr: self.server_addr,
+                    protocol: PROTOCOL,
+                    local_addr,
+                },
+            })
+        })
+    }
+}
+
+// 监听 TCP 并在后台完成 TLS 握手
+pub async fn listen(listen_addr: SocketAddr, cert_file: &Path, key_file: &Path) -> io::Result<ChannelListener> {
+    let acceptor = TlsAcceptor::from(Arc::new(server_config(cert_file, key_file)?));
+    let listener = TcpListener::bind(listen_addr).await?;
+    let (sender, channel) = ChannelListener::new(listener.local_addr()?);
+
+    tokio::spawn(async move {
+        loop {
+            let (tcp, addr) = tokio::select! {
+                // 监听器已被丢弃
+                _ = sender.closed() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // 通常是文件描述符耗尽, 稍后重试
+                        warn!("TLS listener failed to accept a connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+            };
+
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let _ = tcp.set_nodelay(true);
+                let local_addr = tcp.local_addr().ok();
+                let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {}", addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", addr);
+                        return;
+                    }
+                };
+                let _ = sender
+                    .send(Tunnel {
+                        stream: Box::new(stream),
+                        peer: PeerInfo {
+                            addr,
+                            protocol: PROTOCOL,
+                            local_addr,
+                        },
+                    })
+                    .await;
+            });
+        }
+    });
+
+    Ok(channel)
+}
+
+// 服务器 TLS 配置: 从 PEM 文件加载证书链和私钥, 只允许 TLS 1.3
+pub(crate) fn server_config(cert_file: &Path, key_file: &Path) -> io::Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(cert_file, e))?;
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|e| pem_error(key_file, e))?;
+
+    let mut config = ServerConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(config)
+}
+
+// 客户端 TLS 配置: 默认信任 webpki 根证书, 也可以指定自签 CA
+pub(crate) fn client_config(ca_file: Option<&Path>) -> io::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            for cert in CertificateDer::pem_file_iter(path).map_err(|e| pem_error(path, e))? {
+                roots
+                    .add(cert.map_err(|e| pem_error(path, e))?)
+                    .map_err(io::Error::other)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let mut config = ClientConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(config)
+}
+
+pub(crate) fn parse_server_name(server_name: &str) -> io::Result<ServerName<'static>> {
+    ServerName::try_from(server_name.to_string()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not a valid server name", server_name),
+        )
+    })
+}
+
+// 显式指定加密实现, 避免 rustls 同时启用 ring 和 aws-lc-rs 时无法选择默认实现
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::aws_lc_rs::default_provider())
+}
+
+fn pem_error(path: &Path, err: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("cannot load {}: {}", path.display(), err),
+    )
+}